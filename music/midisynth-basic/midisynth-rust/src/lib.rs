@@ -0,0 +1,3286 @@
+// =====================================================================
+// Midisynth library
+// =====================================================================
+// The MIDI parser, the conversion of events to notes and the synthesis
+// used by the midisynth binary, for use in other tools. Typical use:
+//
+//   let (events, division) = midisynth::parse_midi("song.mid")?;
+//   let (notes, total_duration) =
+//       midisynth::convert_events_to_notes(&events, division);
+//
+// To parse from memory, pass any `Read + Seek` (e.g. `io::Cursor`) to
+// `parse_midi_reader`. See main.rs for the command line options.
+//
+// =====================================================================
+
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(any(feature = "url", feature = "gzip-command"))]
+use std::process::Command;
+#[cfg(feature = "gzip-command")]
+use std::process::Stdio;
+
+// =====================================================================
+// CONSTANTS AND TYPES
+// =====================================================================
+
+pub const SAMPLE_RATE: u32 = 44100; // default, see `SynthSettings::sample_rate`
+const STREAM_SECONDS: usize = 10; // length of a block with `stream`
+const VIBRATO_DEPTH: f64 = 0.5;   // semitones of vibrato at full modulation
+const REVERB_WET: f32 = 0.3;      // level of the reverb added to the dry mix
+const CHORUS_SPREAD: f64 = 0.5;   // pan offset of each chorus voice from the note
+const PRESSURE_SWELL: f64 = 0.5;  // sustain level added by full aftertouch
+pub const MAX_HARMONICS: usize = 16; // partials in `Harmonics`
+pub const DEFAULT_RELEASE: f64 = 0.1; // seconds, as NOTE_RELEASE in mivi
+pub const DEFAULT_TAIL: f64 = 1.0; // seconds after the last event, as END_TAIL in mivi
+pub const DEFAULT_MAX_DURATION: f64 = 3600.0; // see `SynthSettings::max_duration`
+const DECLICK_SECONDS: f64 = 0.005; // fade at the very start and end
+const LIMIT_KNEE: f32 = 0.8; // part of full scale above which `limit` compresses
+const NOTE_CACHE_BYTES: usize = 64 << 20; // per rendered slice
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    NoteOn,
+    NoteOff,
+    SetTempo,
+    Modulation,    // Control Change 1 (mod wheel), value in `velocity`
+    Pan,           // Control Change 10, value in `velocity`
+    Sustain,       // Control Change 64, value in `velocity` (>= 64 is down)
+    ProgramChange, // program number in `note`
+    PitchBend,     // LSB in `note`, MSB in `velocity`, see `bend_value`
+    Pressure,      // Channel Pressure (aftertouch), value in `velocity`
+    PolyPressure,  // Polyphonic Key Pressure, key in `note`, value in `velocity`
+    Text,          // meta event type in `note`, the string in `text`
+    TimeSignature, // numerator in `note`, denominator as power of two in `velocity`
+}
+
+// Meta event types kept as `EventType::Text`
+pub const META_TEXT: u8 = 0x01;
+pub const META_TRACK_NAME: u8 = 0x03;
+pub const META_LYRIC: u8 = 0x05;
+pub const META_MARKER: u8 = 0x06;
+
+#[derive(Debug, Clone)]
+pub struct MidiEvent {
+    pub abs_tick: u32,
+    pub event_type: EventType,
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+    pub tempo_micros: u32,
+    pub text: String, // only for `EventType::Text`
+}
+
+impl MidiEvent {
+    // The 14-bit pitch bend value (0 to 16383, 8192 is no bend)
+    pub fn bend_value(&self) -> u16 {
+        ((self.velocity as u16) << 7) | self.note as u16
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub start_time: f64,
+    pub duration: f64,
+    pub midi_key: u8,
+    pub velocity: u8,
+    pub channel: u8,
+    pub pan: f64, // -1.0 (left) to 1.0 (right)
+    pub program: u8,
+    // Pitch bend curve as (seconds since start, bend from -1.0 to 1.0),
+    // beginning at 0.0 if the channel was bent; empty without bends.
+    pub bends: Vec<(f64, f64)>,
+    // Modulation wheel curve as (seconds since start, depth from 0.0 to
+    // 1.0) for the vibrato, like `bends`; empty without modulation.
+    pub vibrato: Vec<(f64, f64)>,
+    // Aftertouch curve as (seconds since start, pressure from 0.0 to 1.0),
+    // the higher of channel and key pressure; empty without aftertouch.
+    pub pressure: Vec<(f64, f64)>,
+    // Semitones from which the pitch slides to the note's own within
+    // `SynthSettings::glide` (see `set_glide`); 0.0 without glide.
+    pub glide_from: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wave {
+    Sine,
+    Square,
+    Sawtooth,
+    Triangle,
+    Noise, // Only used for drums, see `drum_params`
+}
+
+impl Wave {
+    pub fn from_name(name: &str) -> Option<Wave> {
+        match name {
+            "sine" => Some(Wave::Sine),
+            "square" => Some(Wave::Square),
+            "saw" | "sawtooth" => Some(Wave::Sawtooth),
+            "triangle" => Some(Wave::Triangle),
+            _ => None,
+        }
+    }
+}
+
+// Drum sounds as (frequency, duration in seconds, waveform) per key.
+// Keys that are set replace the General MIDI sound, see `parse_drum_map`.
+pub type DrumMap = [Option<(f64, f64, Wave)>; 128];
+
+// Sample format of the WAV file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    Pcm16,
+    Pcm24,
+    Float32, // written as rendered, without normalization or clipping
+}
+
+impl SampleFormat {
+    pub fn from_name(name: &str) -> Option<SampleFormat> {
+        match name {
+            "16" => Some(SampleFormat::Pcm16),
+            "24" => Some(SampleFormat::Pcm24),
+            "32f" => Some(SampleFormat::Float32),
+            _ => None,
+        }
+    }
+
+    fn bits_per_sample(self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 => 16,
+            SampleFormat::Pcm24 => 24,
+            SampleFormat::Float32 => 32,
+        }
+    }
+
+    // Format tag of the fmt chunk
+    fn audio_format(self) -> u16 {
+        match self {
+            SampleFormat::Pcm16 | SampleFormat::Pcm24 => 1, // PCM
+            SampleFormat::Float32 => 3,                     // IEEE float
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Envelope {
+    pub attack: f64,  // seconds
+    pub decay: f64,   // seconds
+    pub sustain: f64, // level 0..=1
+    pub release: f64, // seconds
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope { attack: 0.05, decay: 0.0, sustain: 1.0, release: DEFAULT_RELEASE }
+    }
+}
+
+impl Envelope {
+    pub fn clamped(self) -> Self {
+        Envelope {
+            attack: self.attack.max(0.0),
+            decay: self.decay.max(0.0),
+            sustain: self.sustain.clamp(0.0, 1.0),
+            release: self.release.max(0.0),
+        }
+    }
+
+    // Level after attack: decays from 1.0 to the sustain level
+    fn decay_level(&self, t: f64) -> f64 {
+        if t < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+
+    // Gain at time t (seconds since note start) for a note held for `duration`
+    fn gain(&self, t: f64, duration: f64) -> f64 {
+        if t > duration {
+            // A note shorter than the attack is released from the level
+            // it reached, i.e. its envelope is a triangle
+            let level = if duration < self.attack {
+                duration / self.attack
+            } else {
+                self.decay_level(duration)
+            };
+            (level * (1.0 - (t - duration) / self.release)).max(0.0)
+        } else if t < self.attack {
+            t / self.attack
+        } else {
+            self.decay_level(t)
+        }
+    }
+
+    // Like `gain`, with the sustain level raised by aftertouch `pressure`
+    // (0.0 to 1.0). The decay and the release follow the raised level, so
+    // the note swells without a jump at the end of the decay.
+    fn pressed_gain(&self, t: f64, duration: f64, pressure: f64) -> f64 {
+        if pressure == 0.0 {
+            return self.gain(t, duration);
+        }
+        let sustain = self.sustain * (1.0 + PRESSURE_SWELL * pressure);
+        Envelope { sustain, ..*self }.gain(t, duration)
+    }
+}
+
+// Amplitudes of the partials (fundamental first) that replace the
+// instrument family tables of `overtones_for_program` for every note.
+// A fixed-size array keeps `SynthSettings` Copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Harmonics {
+    amplitudes: [f64; MAX_HARMONICS],
+    len: usize,
+}
+
+impl Harmonics {
+    // None unless there are 1 to MAX_HARMONICS amplitudes, none of them
+    // negative and at least one positive
+    pub fn new(amplitudes: &[f64]) -> Option<Harmonics> {
+        let valid = (1..=MAX_HARMONICS).contains(&amplitudes.len())
+            && amplitudes.iter().all(|&a| a >= 0.0 && a.is_finite())
+            && amplitudes.iter().any(|&a| a > 0.0);
+        if !valid {
+            return None;
+        }
+        let mut harmonics = Harmonics { amplitudes: [0.0; MAX_HARMONICS], len: amplitudes.len() };
+        harmonics.amplitudes[..amplitudes.len()].copy_from_slice(amplitudes);
+        Some(harmonics)
+    }
+
+    pub fn as_slice(&self) -> &[f64] {
+        &self.amplitudes[..self.len]
+    }
+}
+
+// Synthesis settings that do not come from the MIDI file
+#[derive(Debug, Clone, Copy)]
+pub struct SynthSettings {
+    pub envelope: Envelope,
+    pub wave: Wave,
+    pub stereo: bool,
+    pub threads: usize,
+    pub bend_range: f64, // semitones for a full pitch bend
+    pub vibrato_rate: f64, // Hz of the vibrato from the modulation wheel
+    pub stream: bool,    // render block by block (low memory, twice the time)
+    pub lowpass: Option<f64>,  // cutoff in Hz of the low-pass over the mix
+    pub highpass: Option<f64>, // cutoff in Hz of the high-pass over the mix
+    pub reverb: Option<f64>,   // reverb time in seconds (decay by 60 dB)
+    pub format: SampleFormat,
+    pub sample_rate: u32,
+    pub normalize: bool,       // scale the loudest sample to the peak level
+    pub peak_db: Option<f64>,  // peak level in dBFS when normalizing
+    pub oversample: usize,     // render notes at this multiple of the rate
+    pub chorus: Option<f64>,   // detune in cents of the two chorus voices
+    pub max_duration: f64,     // seconds after which the output is cut off
+    pub drum_map: DrumMap,     // drum sounds replacing the General MIDI ones
+    pub harmonics: Option<Harmonics>, // partials of the sine wave for all programs
+    pub glide: Option<f64>,    // seconds of portamento for notes with `glide_from`
+    pub note_cache: bool,      // reuse the samples of repeated notes, see `render_notes`
+    pub limit: bool,           // soft-clip peaks instead of clipping them hard
+}
+
+impl Default for SynthSettings {
+    fn default() -> Self {
+        SynthSettings {
+            envelope: Envelope::default(),
+            wave: Wave::Sine,
+            stereo: false,
+            threads: 1,
+            bend_range: 2.0,
+            vibrato_rate: 5.0,
+            stream: false,
+            lowpass: None,
+            highpass: None,
+            reverb: None,
+            format: SampleFormat::Pcm16,
+            sample_rate: SAMPLE_RATE,
+            normalize: true,
+            peak_db: None,
+            oversample: 1,
+            chorus: None,
+            max_duration: DEFAULT_MAX_DURATION,
+            drum_map: [None; 128],
+            harmonics: None,
+            glide: None,
+            note_cache: true,
+            limit: false,
+        }
+    }
+}
+
+impl SynthSettings {
+    // Ring-out after the last event: `DEFAULT_TAIL`, or longer if the
+    // release plus the reverb need more time to fade out
+    pub fn tail(&self) -> f64 {
+        DEFAULT_TAIL.max(self.envelope.release + self.reverb.unwrap_or(0.0))
+    }
+}
+
+// =====================================================================
+// ERRORS
+// =====================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    Usage,
+    Input,
+    Parse,
+    Output,
+}
+
+#[derive(Debug)]
+pub struct AppError {
+    class: ErrorClass,
+    message: String,
+    offset: Option<u64>, // Byte offset in the input (parse errors)
+}
+
+impl AppError {
+    pub fn new(class: ErrorClass, message: impl Into<String>) -> Self {
+        AppError { class, message: message.into(), offset: None }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.class {
+            ErrorClass::Usage => 2,
+            ErrorClass::Input => 3,
+            ErrorClass::Parse => 4,
+            ErrorClass::Output => 5,
+        }
+    }
+
+    pub fn report(&self, json: bool) {
+        if json {
+            let offset = match self.offset {
+                Some(o) => o.to_string(),
+                None => "null".to_string(),
+            };
+            eprintln!("{{\"error\": \"{}\", \"offset\": {}}}", json_escape(&self.message), offset);
+        } else if let Some(o) = self.offset {
+            eprintln!("Error: {} (at byte {})", self.message, o);
+        } else {
+            eprintln!("Error: {}", self.message);
+        }
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// Progress messages go to stdout, unless the WAV file itself is written
+// there (output "-"); then `status_to_stderr` moves them to stderr.
+static STATUS_ON_STDERR: AtomicBool = AtomicBool::new(false);
+
+pub fn status_to_stderr() {
+    STATUS_ON_STDERR.store(true, Ordering::Relaxed);
+}
+
+pub fn status(args: fmt::Arguments) {
+    if STATUS_ON_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+// =====================================================================
+// HELPER: BINARY READING (Big Endian for MIDI)
+// =====================================================================
+
+fn read_u16_be<R: Read>(f: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    f.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32_be<R: Read>(f: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    f.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_varlen<R: Read>(f: &mut R) -> io::Result<u32> {
+    let mut value: u32 = 0;
+    let mut buf = [0u8; 1];
+    loop {
+        f.read_exact(&mut buf)?;
+        let c = buf[0];
+        value = (value << 7) | (c & 0x7F) as u32;
+        if (c & 0x80) == 0 {
+            break;
+        }
+    }
+    Ok(value)
+}
+
+fn write_varlen(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+// A chunk must fit into the rest of the file. Checking this up front
+// turns a truncated file into a clear error instead of an unexpected EOF
+// somewhere inside the track.
+fn check_chunk_len<R: Seek>(f: &mut R, len: u32, file_len: u64) -> io::Result<()> {
+    let left = file_len.saturating_sub(f.stream_position()?);
+    if len as u64 > left {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!(
+            "Track length exceeds file size ({} bytes declared, {} left)", len, left)));
+    }
+    Ok(())
+}
+
+// =====================================================================
+// MIDI PARSING LOGIC
+// =====================================================================
+
+fn is_url(filename: &str) -> bool {
+    filename.starts_with("http://") || filename.starts_with("https://")
+}
+
+#[cfg(feature = "url")]
+fn download(url: &str) -> io::Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", url])
+        .output()
+        .map_err(|e| io::Error::new(e.kind(),
+            format!("Could not run curl (is it installed?): {}", e)))?;
+
+    if !output.status.success() {
+        let msg = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(
+            format!("Download of {} failed: {}", url, msg.trim())));
+    }
+    if output.stdout.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+            format!("Download of {} returned no data", url)));
+    }
+    Ok(output.stdout)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[cfg(feature = "gzip-command")]
+fn gunzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("gzip")
+        .arg("-dc")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(),
+            format!("Could not run gzip (is it installed?): {}", e)))?;
+
+    // Feed stdin from a thread, so a full stdout pipe cannot block both
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let output = std::thread::scope(|scope| {
+        scope.spawn(move || stdin.write_all(data));
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        let msg = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Could not decompress gzip data: {}", msg.trim())));
+    }
+    Ok(output.stdout)
+}
+
+// Data that starts with the gzip magic bytes is decompressed by the `gzip` command
+// (feature "gzip-command"), anything else is returned as it is
+fn gunzip_if_compressed(data: Vec<u8>) -> Result<Vec<u8>, AppError> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data);
+    }
+
+    #[cfg(feature = "gzip-command")]
+    return gunzip(&data).map_err(|e| AppError::new(ErrorClass::Input, e.to_string()));
+
+    #[cfg(not(feature = "gzip-command"))]
+    Err(AppError::new(ErrorClass::Input,
+        "Gzipped input requires building with --features gzip-command"))
+}
+
+// Reads a file, a URL (feature "url") or, for "-", standard input. Any
+// of them may be gzipped (feature "gzip-command").
+pub fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), AppError> {
+    if filename == "-" {
+        // The parser seeks (running status), so buffer stdin first
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data).map_err(|e| {
+            AppError::new(ErrorClass::Input, format!("Could not read standard input: {}", e))
+        })?;
+        return parse_midi_reader(&mut Cursor::new(gunzip_if_compressed(data)?));
+    }
+
+    if is_url(filename) {
+        #[cfg(feature = "url")]
+        {
+            let data = download(filename)
+                .map_err(|e| AppError::new(ErrorClass::Input, e.to_string()))?;
+            return parse_midi_reader(&mut Cursor::new(gunzip_if_compressed(data)?));
+        }
+
+        #[cfg(not(feature = "url"))]
+        return Err(AppError::new(ErrorClass::Input,
+            "URL input requires building with --features url"));
+    }
+
+    let read_error = |e: io::Error| {
+        AppError::new(ErrorClass::Input, format!("Could not open file: {}", e))
+    };
+    let mut f = File::open(filename).map_err(read_error)?;
+
+    // Only a gzipped file is read into memory
+    let mut magic = [0u8; 2];
+    let compressed = f.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    f.seek(SeekFrom::Start(0)).map_err(read_error)?;
+    if compressed {
+        let mut data = Vec::new();
+        f.read_to_end(&mut data).map_err(read_error)?;
+        return parse_midi_reader(&mut Cursor::new(gunzip_if_compressed(data)?));
+    }
+    parse_midi_reader(&mut f)
+}
+
+// Parses from any reader, e.g. an in-memory buffer. On error, the byte
+// offset where parsing stopped is attached.
+pub fn parse_midi_reader<R: Read + Seek>(f: &mut R) -> Result<(Vec<MidiEvent>, u16), AppError> {
+    parse_midi_from(f).map_err(|e| AppError {
+        class: ErrorClass::Parse,
+        message: e.to_string(),
+        offset: f.stream_position().ok(),
+    })
+}
+
+// The MIDI standard leaves the encoding of text events open. Most files
+// use ASCII or Latin-1, newer ones UTF-8: take UTF-8 if it is valid and
+// Latin-1 otherwise, which maps every byte to a character.
+fn decode_text(bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|e| {
+        e.into_bytes().iter().map(|&b| b as char).collect()
+    })
+}
+
+fn parse_midi_from<R: Read + Seek>(f: &mut R) -> io::Result<(Vec<MidiEvent>, u16)> {
+    let start = f.stream_position()?;
+    let file_len = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(start))?;
+
+    // Header Chunk
+    let mut chunk_id = [0u8; 4];
+    f.read_exact(&mut chunk_id)?;
+    if &chunk_id != b"MThd" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            "Invalid MIDI file (Missing MThd header)"));
+    }
+
+    let header_len = read_u32_be(f)?;
+    check_chunk_len(f, header_len, file_len)?;
+    let format = read_u16_be(f)?;
+    let num_tracks = read_u16_be(f)?;
+    let division = read_u16_be(f)?;
+    // Zero ticks per beat (or an SMPTE frame rate of 0) would make every
+    // tick infinitely long
+    if division == 0 || division >> 8 == 0x80 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("Invalid MIDI file (time division {:#06x})", division)));
+    }
+    // Newer versions of the standard may extend the header
+    f.seek(SeekFrom::Current(header_len.saturating_sub(6) as i64))?;
+
+    if let Some(spt) = smpte_seconds_per_tick(division) {
+        status(format_args!("MIDI Info: {} tracks, SMPTE timing ({:.0} ticks per second)",
+            num_tracks, 1.0 / spt));
+    } else {
+        status(format_args!("MIDI Info: {} tracks, division {}", num_tracks, division));
+    }
+
+    let mut events = Vec::new();
+
+    // In format 2, each track is an independent pattern. They are played
+    // one after another, each starting where the previous one ended.
+    let mut track_offset = 0;
+
+    // Read tracks
+    for _ in 0..num_tracks {
+        f.read_exact(&mut chunk_id)?;
+        while &chunk_id != b"MTrk" {
+            // Skip unknown chunks
+            let skip = read_u32_be(f)?;
+            check_chunk_len(f, skip, file_len)?;
+            f.seek(SeekFrom::Current(skip as i64))?;
+            f.read_exact(&mut chunk_id)?;
+        }
+
+        let track_len = read_u32_be(f)?;
+        check_chunk_len(f, track_len, file_len)?;
+        let start_pos = f.stream_position()?;
+        let end_pos = start_pos + track_len as u64;
+
+        let mut abs_tick = if format == 2 { track_offset } else { 0 };
+        let mut running_status = 0u8;
+
+        while f.stream_position()? < end_pos {
+            let delta = read_varlen(f)?;
+            abs_tick += delta;
+
+            let mut buf = [0u8; 1];
+            f.read_exact(&mut buf)?;
+            let byte = buf[0];
+            let status;
+
+            if byte >= 0x80 {
+                status = byte;
+                // Only channel messages set running status. Many files keep
+                // using the previous Note On status after a meta event.
+                if status < 0xF0 {
+                    running_status = status;
+                }
+            } else {
+                status = running_status;
+                // Rewind 1 byte, as the read byte was data (note, etc.)
+                f.seek(SeekFrom::Current(-1))?;
+            }
+
+            if status == 0xFF {
+                // Meta Event
+                let mut type_buf = [0u8; 1];
+                f.read_exact(&mut type_buf)?;
+                let meta_type = type_buf[0];
+                let len = read_varlen(f)?;
+
+                if meta_type == 0x51 && len == 3 {
+                    // Set Tempo
+                    let mut tbytes = [0u8; 3];
+                    f.read_exact(&mut tbytes)?;
+                    let micros = ((tbytes[0] as u32) << 16)
+                        | ((tbytes[1] as u32) << 8)
+                        | (tbytes[2] as u32);
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::SetTempo,
+                        channel: 0,
+                        note: 0,
+                        velocity: 0,
+                        tempo_micros: micros,
+                        text: String::new(),
+                    });
+                } else if matches!(meta_type, META_TEXT | META_TRACK_NAME | META_LYRIC | META_MARKER) {
+                    let mut bytes = Vec::new();
+                    f.take(len as u64).read_to_end(&mut bytes)?;
+                    if bytes.len() < len as usize {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::Text,
+                        channel: 0,
+                        note: meta_type,
+                        velocity: 0,
+                        tempo_micros: 0,
+                        text: decode_text(bytes),
+                    });
+                } else if meta_type == 0x58 && len >= 2 {
+                    // Time Signature; clocks per click and 32nds per beat are not used
+                    let mut sig = [0u8; 2];
+                    f.read_exact(&mut sig)?;
+                    f.seek(SeekFrom::Current(len as i64 - 2))?;
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::TimeSignature,
+                        channel: 0,
+                        note: sig[0],
+                        velocity: sig[1],
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else if meta_type == 0x2F {
+                    // End of Track
+                    f.seek(SeekFrom::Start(end_pos))?;
+                    break;
+                } else {
+                    f.seek(SeekFrom::Current(len as i64))?;
+                }
+            } else if status == 0xF0 || status == 0xF7 {
+                // SysEx
+                let len = read_varlen(f)?;
+                f.seek(SeekFrom::Current(len as i64))?;
+            } else {
+                let cmd = status & 0xF0;
+
+                if cmd == 0x90 { // Note On
+                    let mut data = [0u8; 2];
+                    f.read_exact(&mut data)?;
+                    let note = data[0];
+                    let vel = data[1];
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: if vel > 0 { EventType::NoteOn } else { EventType::NoteOff },
+                        channel: status & 0x0F,
+                        note,
+                        velocity: vel,
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else if cmd == 0x80 { // Note Off
+                    let mut data = [0u8; 2];
+                    f.read_exact(&mut data)?;
+                    let note = data[0];
+                    let vel = data[1];
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::NoteOff,
+                        channel: status & 0x0F,
+                        note,
+                        velocity: vel,
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else if cmd == 0xB0 { // Control Change
+                    let mut data = [0u8; 2];
+                    f.read_exact(&mut data)?;
+                    let event_type = match data[0] {
+                        1 => Some(EventType::Modulation),
+                        10 => Some(EventType::Pan),
+                        64 => Some(EventType::Sustain),
+                        _ => None,
+                    };
+                    if let Some(event_type) = event_type {
+                        events.push(MidiEvent {
+                            abs_tick,
+                            event_type,
+                            channel: status & 0x0F,
+                            note: 0,
+                            velocity: data[1],
+                            tempo_micros: 0,
+                            text: String::new(),
+                        });
+                    }
+                } else if cmd == 0xC0 { // Program Change
+                    let mut data = [0u8; 1];
+                    f.read_exact(&mut data)?;
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::ProgramChange,
+                        channel: status & 0x0F,
+                        note: data[0],
+                        velocity: 0,
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else if cmd == 0xA0 { // Polyphonic Key Pressure
+                    let mut data = [0u8; 2];
+                    f.read_exact(&mut data)?;
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::PolyPressure,
+                        channel: status & 0x0F,
+                        note: data[0] & 0x7F,
+                        velocity: data[1] & 0x7F,
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else if cmd == 0xD0 { // Channel Pressure
+                    let mut data = [0u8; 1];
+                    f.read_exact(&mut data)?;
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::Pressure,
+                        channel: status & 0x0F,
+                        note: 0,
+                        velocity: data[0] & 0x7F,
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else if cmd == 0xE0 { // Pitch Bend
+                    let mut data = [0u8; 2];
+                    f.read_exact(&mut data)?;
+                    events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::PitchBend,
+                        channel: status & 0x0F,
+                        note: data[0] & 0x7F,
+                        velocity: data[1] & 0x7F,
+                        tempo_micros: 0,
+                        text: String::new(),
+                    });
+                } else {
+                    f.seek(SeekFrom::Current(2))?;
+                }
+            }
+        }
+        track_offset = abs_tick;
+    }
+
+    // Merge the tracks by tick. The sort is stable, and at the same tick
+    // a Note Off (including Note On with velocity 0) from one track comes
+    // before a Note On from another, so a note ended and restarted at
+    // the same tick is not cut to zero length.
+    events.sort_by_key(|e| (e.abs_tick, e.event_type != EventType::NoteOff));
+
+    Ok((events, division))
+}
+
+// =====================================================================
+// CONVERSION TO NOTES
+// =====================================================================
+
+// With SMPTE time division (bit 15 set), the upper byte is the negated
+// frames per second (24, 25, 29 = 29.97 drop frame, 30) and the lower
+// byte the ticks per frame. Ticks then have a fixed length in seconds,
+// independent of tempo events. Returns None for ticks per beat.
+fn smpte_seconds_per_tick(division: u16) -> Option<f64> {
+    if (division & 0x8000) == 0 {
+        return None;
+    }
+    let fps = match -((division >> 8) as u8 as i8) {
+        29 => 29.97,
+        fps => fps as f64,
+    };
+    let ticks_per_frame = (division & 0xFF).max(1) as f64;
+    Some(1.0 / (fps * ticks_per_frame))
+}
+
+// Tempo changes as (tick, time in seconds at that tick, microseconds per
+// beat from there on), starting with the default of 120 BPM at tick 0.
+// Several changes on the same tick leave only the last one. With SMPTE
+// division the tempo does not affect the timing, see `tick_to_seconds`.
+pub fn build_tempo_map(events: &[MidiEvent], division: u16) -> Vec<(u32, f64, f64)> {
+    let mut map = vec![(0, 0.0, 500000.0)]; // Default 120 BPM
+    for e in events.iter().filter(|e| e.event_type == EventType::SetTempo) {
+        let seconds = tick_to_seconds(e.abs_tick, &map, division);
+        if map.last().is_some_and(|&(tick, _, _)| tick == e.abs_tick) {
+            map.pop();
+        }
+        map.push((e.abs_tick, seconds, e.tempo_micros as f64));
+    }
+    map
+}
+
+// Converts an absolute tick into seconds using a map from `build_tempo_map`
+pub fn tick_to_seconds(tick: u32, map: &[(u32, f64, f64)], division: u16) -> f64 {
+    if let Some(spt) = smpte_seconds_per_tick(division) {
+        return tick as f64 * spt;
+    }
+    let index = map.partition_point(|&(t, _, _)| t <= tick).saturating_sub(1);
+    let Some(&(start, seconds, micros_per_beat)) = map.get(index) else {
+        return 0.0;
+    };
+    let seconds_per_tick = (micros_per_beat / 1_000_000.0) / (division as f64);
+    seconds + (tick - start) as f64 * seconds_per_tick
+}
+
+// Inverse of `tick_to_seconds`: the tick, with fraction, at `seconds`
+pub fn seconds_to_tick(seconds: f64, map: &[(u32, f64, f64)], division: u16) -> f64 {
+    if let Some(spt) = smpte_seconds_per_tick(division) {
+        return seconds / spt;
+    }
+    let index = map.partition_point(|&(_, s, _)| s <= seconds).saturating_sub(1);
+    let Some(&(start, start_seconds, micros_per_beat)) = map.get(index) else {
+        return 0.0;
+    };
+    let seconds_per_tick = (micros_per_beat / 1_000_000.0) / (division as f64);
+    start as f64 + (seconds - start_seconds) / seconds_per_tick
+}
+
+// Stereo position of a Pan controller (CC 10) value: 0 hard left, 64
+// center, 127 hard right
+fn pan_position(value: u8) -> f64 {
+    ((value as f64 - 64.0) / 63.0).clamp(-1.0, 1.0)
+}
+
+// Part of a channel's bend (or modulation) history with absolute times
+// that applies to a note from `start` to `end`, with times relative to
+// the note start.
+fn bend_curve(history: &[(f64, f64)], start: f64, end: f64) -> Vec<(f64, f64)> {
+    if history.is_empty() {
+        return Vec::new();
+    }
+    let first = history.partition_point(|&(t, _)| t <= start);
+    let initial = if first > 0 { history[first - 1].1 } else { 0.0 };
+    let mut curve = vec![(0.0, initial)];
+    curve.extend(history[first..].iter()
+        .take_while(|&&(t, _)| t < end)
+        .map(|&(t, value)| (t - start, value)));
+    curve
+}
+
+// Aftertouch curve of a note from its channel's pressure history and its
+// key's, like `bend_curve`. Where both apply, the higher one counts.
+fn pressure_curve(channel: &[(f64, f64)], key: &[(f64, f64)], start: f64, end: f64) -> Vec<(f64, f64)> {
+    let channel = bend_curve(channel, start, end);
+    let key = bend_curve(key, start, end);
+    if channel.is_empty() || key.is_empty() {
+        return if key.is_empty() { channel } else { key };
+    }
+    let value_at = |curve: &[(f64, f64)], t: f64| curve[curve.partition_point(|&(ct, _)| ct <= t) - 1].1;
+    let mut times: Vec<f64> = channel.iter().chain(&key).map(|&(t, _)| t).collect();
+    times.sort_by(f64::total_cmp);
+    times.dedup();
+    times.into_iter().map(|t| (t, value_at(&channel, t).max(value_at(&key, t)))).collect()
+}
+
+// Text events (text, track name, lyric, marker) as (time in seconds,
+// meta event type, text), e.g. to show the lyrics in sync with the notes
+pub fn text_events(events: &[MidiEvent], division: u16) -> Vec<(f64, u8, String)> {
+    let tempo_map = build_tempo_map(events, division);
+    events.iter()
+        .filter(|e| e.event_type == EventType::Text)
+        .map(|e| (tick_to_seconds(e.abs_tick, &tempo_map, division), e.note, e.text.clone()))
+        .collect()
+}
+
+// Converts the events into notes, with `DEFAULT_TAIL` seconds of ring-out
+// after the last event. Returns the notes and the total duration.
+pub fn convert_events_to_notes(
+    events: &[MidiEvent],
+    division: u16,
+) -> (Vec<Note>, f64) {
+    convert_events_to_notes_with_tail(events, division, DEFAULT_TAIL)
+}
+
+// Like `convert_events_to_notes`, with `tail` seconds after the last event
+pub fn convert_events_to_notes_with_tail(
+    events: &[MidiEvent],
+    division: u16,
+    tail: f64,
+) -> (Vec<Note>, f64) {
+    let mut notes = Vec::new();
+    let mut current_time = 0.0;
+    let tempo_map = build_tempo_map(events, division);
+
+    // active_notes[channel][pitch] = start_time
+    // We use f64::NEG_INFINITY as "not active" marker
+    let mut active_notes = [[f64::NEG_INFINITY; 128]; 16];
+    let mut active_velocities = [[0u8; 128]; 16];
+    // Number of Note Ons per key still waiting for their Note Off. A key
+    // struck twice needs two offs (vel 0 or 0x80) before it falls silent.
+    let mut active_counts = [[0u32; 128]; 16];
+    // Current Pan controller position per channel (center until the
+    // first CC 10) and the position each active note started with
+    let mut channel_pans = [0.0f64; 16];
+    let mut active_pans = [[0.0f64; 128]; 16];
+    let mut programs = [0u8; 16];
+    // Sustain pedal: keys released while it is down keep sounding
+    // (and stay in active_notes) until the pedal goes up.
+    let mut pedal_down = [false; 16];
+    let mut sustained = [[false; 128]; 16];
+    // Pitch bend changes per channel as (time, -1.0..1.0)
+    let mut bend_history: Vec<Vec<(f64, f64)>> = vec![Vec::new(); 16];
+    // Modulation wheel changes per channel as (time, 0.0..1.0)
+    let mut mod_history: Vec<Vec<(f64, f64)>> = vec![Vec::new(); 16];
+    // Aftertouch per channel and per channel * 128 + key as (time, 0.0..1.0)
+    let mut pressure_history: Vec<Vec<(f64, f64)>> = vec![Vec::new(); 16];
+    let mut key_pressure_history: Vec<Vec<(f64, f64)>> = vec![Vec::new(); 16 * 128];
+
+    for e in events {
+        current_time = tick_to_seconds(e.abs_tick, &tempo_map, division);
+
+        match e.event_type {
+            EventType::SetTempo | EventType::Text | EventType::TimeSignature => {}
+            EventType::Pan => {
+                channel_pans[e.channel as usize] = pan_position(e.velocity);
+            }
+            EventType::Modulation => {
+                mod_history[e.channel as usize].push((current_time, e.velocity as f64 / 127.0));
+            }
+            EventType::Pressure => {
+                pressure_history[e.channel as usize].push((current_time, e.velocity as f64 / 127.0));
+            }
+            EventType::PolyPressure => {
+                let key = e.channel as usize * 128 + e.note as usize;
+                key_pressure_history[key].push((current_time, e.velocity as f64 / 127.0));
+            }
+            EventType::ProgramChange => {
+                programs[e.channel as usize] = e.note;
+            }
+            EventType::PitchBend => {
+                let value = (e.bend_value() as f64 - 8192.0) / 8192.0;
+                bend_history[e.channel as usize].push((current_time, value));
+            }
+            EventType::Sustain => {
+                let ch = e.channel as usize;
+                pedal_down[ch] = e.velocity >= 64;
+                if pedal_down[ch] {
+                    continue;
+                }
+                // Pedal up: end all notes that were only held by the pedal
+                for n in 0..128 {
+                    if !sustained[ch][n] {
+                        continue;
+                    }
+                    let duration = current_time - active_notes[ch][n];
+                    if duration > 0.0 {
+                        notes.push(Note {
+                            start_time: active_notes[ch][n],
+                            duration,
+                            midi_key: n as u8,
+                            velocity: active_velocities[ch][n],
+                            channel: e.channel,
+                            pan: active_pans[ch][n],
+                            program: programs[ch],
+                            bends: bend_curve(&bend_history[ch], active_notes[ch][n], current_time),
+                            vibrato: bend_curve(&mod_history[ch], active_notes[ch][n], current_time),
+                            pressure: pressure_curve(&pressure_history[ch],
+                                &key_pressure_history[ch * 128 + n], active_notes[ch][n], current_time),
+                            glide_from: 0.0,
+                        });
+                    }
+                    active_notes[ch][n] = f64::NEG_INFINITY;
+                    sustained[ch][n] = false;
+                }
+            }
+            EventType::NoteOn => {
+                let ch = e.channel as usize;
+                let n = e.note as usize;
+
+                // Retrigger check
+                if active_notes[ch][n] != f64::NEG_INFINITY {
+                    let duration = current_time - active_notes[ch][n];
+                    if duration > 0.0 {
+                        notes.push(Note {
+                            start_time: active_notes[ch][n],
+                            duration,
+                            midi_key: e.note,
+                            velocity: active_velocities[ch][n],
+                            channel: e.channel,
+                            pan: active_pans[ch][n],
+                            program: programs[ch],
+                            bends: bend_curve(&bend_history[ch], active_notes[ch][n], current_time),
+                            vibrato: bend_curve(&mod_history[ch], active_notes[ch][n], current_time),
+                            pressure: pressure_curve(&pressure_history[ch],
+                                &key_pressure_history[ch * 128 + n], active_notes[ch][n], current_time),
+                            glide_from: 0.0,
+                        });
+                    }
+                }
+                active_notes[ch][n] = current_time;
+                active_velocities[ch][n] = e.velocity;
+                active_pans[ch][n] = channel_pans[ch];
+                active_counts[ch][n] += 1;
+                sustained[ch][n] = false;
+            }
+            EventType::NoteOff => {
+                let ch = e.channel as usize;
+                let n = e.note as usize;
+
+                if active_counts[ch][n] > 1 {
+                    active_counts[ch][n] -= 1;
+                } else if pedal_down[ch] && active_notes[ch][n] != f64::NEG_INFINITY {
+                    sustained[ch][n] = true;
+                    active_counts[ch][n] = 0;
+                } else if active_notes[ch][n] != f64::NEG_INFINITY {
+                    let duration = current_time - active_notes[ch][n];
+                    if duration > 0.0 {
+                        notes.push(Note {
+                            start_time: active_notes[ch][n],
+                            duration,
+                            midi_key: e.note,
+                            velocity: active_velocities[ch][n],
+                            channel: e.channel,
+                            pan: active_pans[ch][n],
+                            program: programs[ch],
+                            bends: bend_curve(&bend_history[ch], active_notes[ch][n], current_time),
+                            vibrato: bend_curve(&mod_history[ch], active_notes[ch][n], current_time),
+                            pressure: pressure_curve(&pressure_history[ch],
+                                &key_pressure_history[ch * 128 + n], active_notes[ch][n], current_time),
+                            glide_from: 0.0,
+                        });
+                    }
+                    active_notes[ch][n] = f64::NEG_INFINITY;
+                    active_counts[ch][n] = 0;
+                }
+            }
+        }
+    }
+
+    let total_duration = current_time + tail;
+
+    // Notes still sounding at the end, because the pedal is still down or
+    // the Note Off is missing (stuck notes): let them ring until the end
+    let mut stuck = 0;
+    for ch in 0..16 {
+        for n in 0..128 {
+            if active_notes[ch][n] != f64::NEG_INFINITY {
+                if !sustained[ch][n] {
+                    stuck += 1;
+                }
+                notes.push(Note {
+                    start_time: active_notes[ch][n],
+                    duration: total_duration - active_notes[ch][n],
+                    midi_key: n as u8,
+                    velocity: active_velocities[ch][n],
+                    channel: ch as u8,
+                    pan: active_pans[ch][n],
+                    program: programs[ch],
+                    bends: bend_curve(&bend_history[ch], active_notes[ch][n], total_duration),
+                    vibrato: bend_curve(&mod_history[ch], active_notes[ch][n], total_duration),
+                    pressure: pressure_curve(&pressure_history[ch],
+                        &key_pressure_history[ch * 128 + n], active_notes[ch][n], total_duration),
+                    glide_from: 0.0,
+                });
+            }
+        }
+    }
+    if stuck > 0 {
+        eprintln!("Warning: {} notes without Note Off, held until the end", stuck);
+    }
+
+    (notes, total_duration)
+}
+
+// Shifts every note except drums by `semitones`. Notes that would leave
+// the MIDI range 0 to 127 are removed; returns how many.
+pub fn transpose_notes(notes: &mut Vec<Note>, semitones: i32) -> usize {
+    let before = notes.len();
+    notes.retain_mut(|n| {
+        if n.channel == 9 {
+            return true;
+        }
+        match u8::try_from(n.midi_key as i32 + semitones) {
+            Ok(key) if key < 128 => {
+                n.midi_key = key;
+                true
+            }
+            _ => false,
+        }
+    });
+    before - notes.len()
+}
+
+// Snaps the start of every note to the nearest 1/`grid` of a whole note
+// (16 for sixteenths), following the tempo map. Notes keep their length,
+// or with `ends` also end on the grid, at least one step after the start.
+// Rounding keeps the order of the notes. A SMPTE division has no beats,
+// so the grid then assumes the default 120 BPM.
+pub fn quantize_notes(
+    notes: &mut [Note],
+    map: &[(u32, f64, f64)],
+    division: u16,
+    grid: u32,
+    ends: bool,
+) {
+    let default_map = [(0, 0.0, 500000.0)];
+    let (map, division) = if smpte_seconds_per_tick(division).is_some() {
+        (&default_map[..], 480)
+    } else {
+        (map, division)
+    };
+    let step = division as f64 * 4.0 / grid.max(1) as f64;
+    let snap = |seconds: f64| {
+        (seconds_to_tick(seconds, map, division) / step).round().max(0.0) * step
+    };
+    let to_seconds = |tick: f64| tick_to_seconds(tick.round() as u32, map, division);
+
+    for n in notes {
+        let start = snap(n.start_time);
+        let end_seconds = n.start_time + n.duration;
+        n.start_time = to_seconds(start);
+        n.duration = if ends {
+            to_seconds(snap(end_seconds).max(start + step)) - n.start_time
+        } else {
+            n.duration
+        };
+    }
+}
+
+// Portamento for monophonic lines: a note that starts while the previous
+// note of its channel still sounds, or at most `glide` seconds after it
+// ended, slides in from that note's pitch (see `Note::glide_from`).
+// Notes struck together (chords) and drums never glide.
+pub fn set_glide(notes: &mut [Note], glide: f64) {
+    let mut order: Vec<usize> = (0..notes.len()).collect();
+    order.sort_by(|&a, &b| notes[a].start_time.total_cmp(&notes[b].start_time));
+
+    let mut previous: [Option<(f64, f64, u8)>; 16] = [None; 16]; // start, end and key
+    for i in order {
+        let n = &mut notes[i];
+        if n.channel == 9 {
+            continue;
+        }
+        let ch = n.channel as usize & 15;
+        n.glide_from = match previous[ch] {
+            Some((start, end, key)) if start < n.start_time && n.start_time - end <= glide => {
+                key as f64 - n.midi_key as f64
+            }
+            _ => 0.0,
+        };
+        previous[ch] = Some((n.start_time, n.start_time + n.duration, n.midi_key));
+    }
+}
+
+// Voice stealing like a hardware synth: when a note starts while
+// `max_voices` notes of its channel are already sounding, the oldest of
+// them (the quietest among equally old ones) is cut off at that moment
+// and goes into its release. Notes cut off before they sounded at all
+// are removed. Returns the number of notes that were cut.
+pub fn limit_voices(notes: &mut Vec<Note>, max_voices: usize) -> usize {
+    let max_voices = max_voices.max(1);
+    let mut order: Vec<usize> = (0..notes.len()).collect();
+    order.sort_by(|&a, &b| notes[a].start_time.total_cmp(&notes[b].start_time));
+
+    let mut active: Vec<Vec<usize>> = vec![Vec::new(); 16];
+    let mut stolen = 0;
+    for i in order {
+        let start = notes[i].start_time;
+        let voices = &mut active[notes[i].channel as usize & 15];
+        voices.retain(|&v| notes[v].start_time + notes[v].duration > start);
+
+        if voices.len() >= max_voices {
+            let pos = (0..voices.len()).min_by(|&a, &b| {
+                let (na, nb) = (&notes[voices[a]], &notes[voices[b]]);
+                na.start_time.total_cmp(&nb.start_time).then(na.velocity.cmp(&nb.velocity))
+            }).unwrap();
+            let victim = voices.swap_remove(pos);
+            notes[victim].duration = start - notes[victim].start_time;
+            stolen += 1;
+        }
+        voices.push(i);
+    }
+    notes.retain(|n| n.duration > 0.0);
+    stolen
+}
+
+// Drum notes for a click on every beat until `end` (seconds), following
+// tempo and time signature changes. Beat 1 of each measure gets the
+// accented Metronome Bell (GM drum key 34), the other beats the
+// Metronome Click (33). A beat is one unit of the denominator, e.g. an
+// eighth in 6/8. Without time signature events 4/4 is assumed.
+pub fn metronome_notes(events: &[MidiEvent], division: u16, end: f64) -> Vec<Note> {
+    // SMPTE division has no beats to follow
+    if smpte_seconds_per_tick(division).is_some() || division == 0 {
+        return Vec::new();
+    }
+    let tempo_map = build_tempo_map(events, division);
+    let mut signatures: Vec<(u32, u32, u32)> = vec![(0, 4, 2)];
+    for e in events.iter().filter(|e| e.event_type == EventType::TimeSignature) {
+        if signatures.last().is_some_and(|&(tick, _, _)| tick == e.abs_tick) {
+            signatures.pop();
+        }
+        signatures.push((e.abs_tick, (e.note as u32).max(1), (e.velocity as u32).min(6)));
+    }
+
+    let mut notes = Vec::new();
+    for (i, &(start, numerator, denominator)) in signatures.iter().enumerate() {
+        let next = signatures.get(i + 1).map_or(u32::MAX, |s| s.0);
+        let beat_ticks = ((division as u32 * 4) >> denominator).max(1);
+        let mut tick = start;
+        let mut beat = 0;
+        while tick < next {
+            let time = tick_to_seconds(tick, &tempo_map, division);
+            if time >= end {
+                return notes;
+            }
+            let accent = beat % numerator == 0;
+            notes.push(Note {
+                start_time: time,
+                duration: 0.05,
+                midi_key: if accent { 34 } else { 33 },
+                velocity: if accent { 110 } else { 80 },
+                channel: 9,
+                pan: 0.0,
+                program: 0,
+                bends: Vec::new(),
+                vibrato: Vec::new(),
+                pressure: Vec::new(),
+                glide_from: 0.0,
+            });
+            tick = tick.saturating_add(beat_ticks);
+            beat += 1;
+        }
+    }
+    notes
+}
+
+// =====================================================================
+// MIDI WRITING
+// =====================================================================
+
+// Serializes notes into a format-0 MIDI file at a fixed 120 BPM with
+// `division` ticks per beat, the inverse of `convert_events_to_notes`.
+// Each note becomes a Note On/Off pair, preceded by a Program Change and
+// a Pan controller when its channel's program or pan differs from the
+// previous note. Pitch bends and vibrato are not written. Times are
+// rounded to whole ticks.
+pub fn midi_bytes(notes: &[Note], division: u16) -> Vec<u8> {
+    let ticks_per_second = division as f64 * 2.0; // 120 BPM
+    let to_tick = |seconds: f64| (seconds * ticks_per_second).round().max(0.0) as u32;
+
+    // (tick, order, bytes): at the same tick, offs come before the
+    // controllers and program changes of the notes that start there
+    let mut events: Vec<(u32, u8, Vec<u8>)> = Vec::new();
+    let mut programs = [0u8; 16];
+    let mut pans = [64u8; 16];
+    let mut sorted: Vec<&Note> = notes.iter().collect();
+    sorted.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+    for n in sorted {
+        let ch = (n.channel & 0x0F) as usize;
+        let start = to_tick(n.start_time);
+        let end = to_tick(n.start_time + n.duration).max(start + 1);
+        if programs[ch] != n.program {
+            programs[ch] = n.program;
+            events.push((start, 1, vec![0xC0 | ch as u8, n.program & 0x7F]));
+        }
+        let pan = (64.0 + n.pan * 63.0).round().clamp(0.0, 127.0) as u8;
+        if pans[ch] != pan {
+            pans[ch] = pan;
+            events.push((start, 1, vec![0xB0 | ch as u8, 10, pan]));
+        }
+        events.push((start, 2, vec![0x90 | ch as u8, n.midi_key & 0x7F, n.velocity.clamp(1, 127)]));
+        events.push((end, 0, vec![0x80 | ch as u8, n.midi_key & 0x7F, 0]));
+    }
+    events.sort_by_key(|&(tick, order, _)| (tick, order));
+
+    let mut track = vec![0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]; // 500000 us per beat
+    let mut last_tick = 0;
+    for (tick, _, bytes) in events {
+        write_varlen(&mut track, tick - last_tick);
+        track.extend_from_slice(&bytes);
+        last_tick = tick;
+    }
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    let mut data = Vec::with_capacity(track.len() + 22);
+    data.extend_from_slice(b"MThd");
+    data.extend_from_slice(&6u32.to_be_bytes());
+    data.extend_from_slice(&[0, 0, 0, 1]); // format 0, one track
+    data.extend_from_slice(&division.to_be_bytes());
+    data.extend_from_slice(b"MTrk");
+    data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    data.extend_from_slice(&track);
+    data
+}
+
+// Writes `midi_bytes(notes, division)` to `path`
+pub fn write_midi(notes: &[Note], division: u16, path: &str) -> Result<(), AppError> {
+    File::create(path)
+        .and_then(|mut f| f.write_all(&midi_bytes(notes, division)))
+        .map_err(|e| AppError::new(ErrorClass::Output, format!("Could not write MIDI file: {}", e)))
+}
+
+// =====================================================================
+// NOTE TEXT INPUT
+// =====================================================================
+
+// MIDI key of a note name like "C4", "F#3" or "Bb-1" (C4 is 60, as in
+// `midi_to_freq` A4 is 69)
+pub fn note_name_to_midi(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    let base = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (accidental, octave) = if let Some(rest) = rest.strip_prefix('#') {
+        (1, rest)
+    } else if let Some(rest) = rest.strip_prefix('b') {
+        (-1, rest)
+    } else {
+        (0, rest)
+    };
+    let key = (octave.parse::<i32>().ok()? + 1) * 12 + base + accidental;
+    u8::try_from(key).ok().filter(|&key| key < 128)
+}
+
+// Notes from a simple text format with one note per line:
+//   pitch start duration velocity
+// e.g. "C4 0.0 0.5 100", with the pitch as note name or MIDI key and the
+// times in seconds. Empty lines and lines starting with '#' are skipped.
+// Returns the notes and the total duration with `tail` seconds added.
+pub fn parse_note_text(text: &str, tail: f64) -> Result<(Vec<Note>, f64), AppError> {
+    let mut notes = Vec::new();
+    let mut end: f64 = 0.0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |what: &str| AppError::new(ErrorClass::Parse,
+            format!("Line {}: {}: {}", i + 1, what, line));
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [pitch, start, duration, velocity] = fields[..] else {
+            return Err(error("Expected pitch, start, duration and velocity"));
+        };
+        let midi_key = note_name_to_midi(pitch)
+            .or_else(|| pitch.parse().ok().filter(|&key: &u8| key < 128))
+            .ok_or_else(|| error("Invalid pitch"))?;
+        let start_time: f64 = start.parse().ok()
+            .filter(|&t: &f64| t >= 0.0)
+            .ok_or_else(|| error("Invalid start time"))?;
+        let duration: f64 = duration.parse().ok()
+            .filter(|&d: &f64| d > 0.0)
+            .ok_or_else(|| error("Invalid duration"))?;
+        let velocity: u8 = velocity.parse().ok()
+            .filter(|&v| (1..128).contains(&v))
+            .ok_or_else(|| error("Invalid velocity"))?;
+
+        end = end.max(start_time + duration);
+        notes.push(Note {
+            start_time,
+            duration,
+            midi_key,
+            velocity,
+            channel: 0,
+            pan: 0.0,
+            program: 0,
+            bends: Vec::new(),
+            vibrato: Vec::new(),
+            pressure: Vec::new(),
+            glide_from: 0.0,
+        });
+    }
+    notes.sort_by(|a, b| a.start_time.total_cmp(&b.start_time));
+    Ok((notes, end + tail))
+}
+
+// Reads a note text file (see `parse_note_text`) or, for "-", standard input
+pub fn read_note_text(filename: &str, tail: f64) -> Result<(Vec<Note>, f64), AppError> {
+    let mut text = String::new();
+    let result = if filename == "-" {
+        io::stdin().lock().read_to_string(&mut text)
+    } else {
+        File::open(filename).and_then(|mut f| f.read_to_string(&mut text))
+    };
+    result.map_err(|e| AppError::new(ErrorClass::Input, format!("Could not read file: {}", e)))?;
+    parse_note_text(&text, tail)
+}
+
+// =====================================================================
+// DRUM MAP
+// =====================================================================
+
+// Drum sounds for kits that do not follow General MIDI, one per line:
+//   key frequency duration waveform
+// e.g. "60 55 0.2 sine" to play key 60 as a bass drum. The key is a MIDI
+// key number or note name, the duration in seconds, the waveform sine,
+// square, sawtooth, triangle or noise (for noise, the frequency sets how
+// often a new random value is drawn). Empty lines and lines starting
+// with '#' are skipped. Keys not in the map keep their GM sound.
+pub fn parse_drum_map(text: &str) -> Result<DrumMap, AppError> {
+    let mut map = [None; 128];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |what: &str| AppError::new(ErrorClass::Parse,
+            format!("Line {}: {}: {}", i + 1, what, line));
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [key, freq, duration, wave] = fields[..] else {
+            return Err(error("Expected key, frequency, duration and waveform"));
+        };
+        let key = note_name_to_midi(key)
+            .or_else(|| key.parse().ok().filter(|&key: &u8| key < 128))
+            .ok_or_else(|| error("Invalid key"))?;
+        let freq: f64 = freq.parse().ok()
+            .filter(|&f: &f64| f > 0.0)
+            .ok_or_else(|| error("Invalid frequency"))?;
+        let duration: f64 = duration.parse().ok()
+            .filter(|&d: &f64| d > 0.0)
+            .ok_or_else(|| error("Invalid duration"))?;
+        let wave = match wave {
+            "noise" => Wave::Noise,
+            name => Wave::from_name(name).ok_or_else(|| error("Invalid waveform"))?,
+        };
+        map[key as usize] = Some((freq, duration, wave));
+    }
+    Ok(map)
+}
+
+// Reads a drum map file, see `parse_drum_map`
+pub fn read_drum_map(filename: &str) -> Result<DrumMap, AppError> {
+    let text = std::fs::read_to_string(filename).map_err(|e| AppError::new(ErrorClass::Input,
+        format!("Could not read drum map {}: {}", filename, e)))?;
+    parse_drum_map(&text)
+}
+
+// =====================================================================
+// STATISTICS
+// =====================================================================
+
+// Maximum number of simultaneously sounding notes. Works on the
+// converted notes, so Note On with velocity 0 is already an off.
+pub fn peak_polyphony(notes: &[Note]) -> usize {
+    let mut edges: Vec<(f64, i32)> = Vec::with_capacity(notes.len() * 2);
+    for n in notes {
+        edges.push((n.start_time, 1));
+        edges.push((n.start_time + n.duration, -1));
+    }
+    // At equal times, note ends come before note starts
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut current = 0i32;
+    let mut peak = 0i32;
+    for (_, delta) in edges {
+        current += delta;
+        peak = peak.max(current);
+    }
+    peak as usize
+}
+
+// =====================================================================
+// SYNTHESIS AND WAV WRITING
+// =====================================================================
+
+// `total_frames` counts sample frames, i.e. one sample per channel.
+fn write_wav_header(
+    f: &mut impl Write,
+    total_frames: u32,
+    channels: u16,
+    bits_per_sample: u16,
+    audio_format: u16,
+    sample_rate: u32,
+) -> io::Result<()> {
+    let block_align = bits_per_sample / 8 * channels;
+    let byte_rate = sample_rate * block_align as u32;
+    let data_chunk_size = total_frames * block_align as u32;
+    let file_size = 36 + data_chunk_size;
+
+    // RIFF Header
+    f.write_all(b"RIFF")?;
+    f.write_all(&file_size.to_le_bytes())?;
+    f.write_all(b"WAVE")?;
+    f.write_all(b"fmt ")?;
+
+    let subchunk1_size = 16u32;
+    let num_channels = channels;
+
+    // fmt chunk
+    f.write_all(&subchunk1_size.to_le_bytes())?;
+    f.write_all(&audio_format.to_le_bytes())?;
+    f.write_all(&num_channels.to_le_bytes())?;
+    f.write_all(&sample_rate.to_le_bytes())?;
+    f.write_all(&byte_rate.to_le_bytes())?;
+    f.write_all(&block_align.to_le_bytes())?;
+    f.write_all(&bits_per_sample.to_le_bytes())?;
+
+    // data chunk
+    f.write_all(b"data")?;
+    f.write_all(&data_chunk_size.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn midi_to_freq(key: u8) -> f64 {
+    440.0 * 2.0_f64.powf((key as f64 - 69.0) / 12.0)
+}
+
+// Relative amplitudes of the fundamental and its harmonics for each
+// General MIDI instrument family (groups of 8 programs). Only a rough
+// approximation, but enough to tell the instruments apart.
+fn overtones_for_program(program: u8) -> &'static [f64] {
+    match program / 8 {
+        1 => &[1.0, 0.0, 0.4, 0.0, 0.2],                   // Chromatic percussion
+        2 => &[1.0, 0.8, 0.6, 0.0, 0.4, 0.0, 0.0, 0.3],    // Organ
+        3 => &[1.0, 0.6, 0.4, 0.3, 0.2],                   // Guitar
+        4 => &[1.0, 0.3, 0.1],                             // Bass
+        5 | 6 => &[1.0, 0.7, 0.5, 0.4, 0.3, 0.2, 0.15],    // Strings, ensemble
+        7 => &[1.0, 0.9, 0.8, 0.6, 0.5, 0.4, 0.3],         // Brass
+        8 => &[1.0, 0.1, 0.6, 0.1, 0.4, 0.1, 0.2],         // Reed
+        9 => &[1.0, 0.2, 0.05],                            // Pipe
+        _ => &[1.0, 0.5, 0.3, 0.1],                        // Piano and others
+    }
+}
+
+// Weight of an overtone by how hard the note is played: the fundamental
+// and the first harmonic always sound, the higher ones fade in with the
+// velocity, so louder notes are brighter.
+fn overtone_weight(index: usize, velocity: u8) -> f64 {
+    if index < 2 { 1.0 } else { velocity as f64 / 127.0 }
+}
+
+// One period of the waveform for a normalized phase in [0, 1).
+// All waveforms start at zero crossing or edge at phase 0.
+fn oscillator(kind: Wave, phase: f64) -> f64 {
+    match kind {
+        Wave::Sine => (2.0 * PI * phase).sin(),
+        Wave::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+        Wave::Sawtooth => 2.0 * (phase + 0.5).fract() - 1.0,
+        Wave::Triangle => 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0,
+        Wave::Noise => noise((phase * u32::MAX as f64) as u32),
+    }
+}
+
+// Pseudo-random value in [-1, 1] for an integer seed (integer hash, so
+// the same sample always gets the same value, regardless of how the
+// rendering is split into slices or blocks).
+fn noise(seed: u32) -> f64 {
+    let mut x = seed.wrapping_mul(0x9E37_79B9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xC2B2_AE35);
+    x ^= x >> 16;
+    x as f64 / u32::MAX as f64 * 2.0 - 1.0
+}
+
+// Frequency, length in seconds and waveform for a General MIDI percussion
+// key on channel 10. For noise, the frequency sets how often a new random
+// value is drawn, i.e. how bright the noise sounds. Unknown keys keep the
+// short 100 Hz tick.
+fn drum_params(note: u8) -> (f64, f64, Wave) {
+    match note {
+        33 => (1500.0, 0.03, Wave::Sine),        // Metronome click
+        34 => (2000.0, 0.06, Wave::Sine),        // Metronome bell
+        35 => (55.0, 0.15, Wave::Sine),          // Acoustic bass drum
+        36 => (60.0, 0.15, Wave::Sine),          // Bass drum
+        37 => (400.0, 0.03, Wave::Triangle),     // Side stick
+        38 => (3000.0, 0.12, Wave::Noise),       // Acoustic snare
+        39 => (1200.0, 0.08, Wave::Noise),       // Hand clap
+        40 => (4000.0, 0.1, Wave::Noise),        // Electric snare
+        41 => (90.0, 0.2, Wave::Sine),           // Low floor tom
+        43 => (110.0, 0.2, Wave::Sine),          // High floor tom
+        45 => (130.0, 0.18, Wave::Sine),         // Low tom
+        47 => (160.0, 0.18, Wave::Sine),         // Low-mid tom
+        48 => (190.0, 0.15, Wave::Sine),         // Hi-mid tom
+        50 => (220.0, 0.15, Wave::Sine),         // High tom
+        42 => (9000.0, 0.04, Wave::Noise),       // Closed hi-hat
+        44 => (9000.0, 0.06, Wave::Noise),       // Pedal hi-hat
+        46 => (9000.0, 0.25, Wave::Noise),       // Open hi-hat
+        49 | 57 => (7000.0, 0.6, Wave::Noise),   // Crash cymbals
+        51 | 59 => (6000.0, 0.35, Wave::Noise),  // Ride cymbals
+        53 => (2400.0, 0.3, Wave::Triangle),     // Ride bell
+        54 => (8000.0, 0.12, Wave::Noise),       // Tambourine
+        56 => (800.0, 0.15, Wave::Square),       // Cowbell
+        _ => (100.0, 0.05, Wave::Sine),
+    }
+}
+
+// `drum_params`, unless the drum map has its own sound for the key
+fn drum_sound(note: u8, drum_map: &DrumMap) -> (f64, f64, Wave) {
+    drum_map.get(note as usize).copied().flatten().unwrap_or_else(|| drum_params(note))
+}
+
+// Percussive envelope for drums: a very short attack, then a linear
+// decay to silence at the end of the drum sound.
+fn drum_gain(t: f64, duration: f64) -> f64 {
+    let attack = 0.002;
+    if t < attack {
+        t / attack
+    } else {
+        (1.0 - (t - attack) / (duration - attack)).max(0.0)
+    }
+}
+
+// Follows a note's pitch bend curve and returns the phase increment for
+// each sample. Without bends, this is always `base_inc`. With a vibrato
+// curve, the pitch additionally swings by up to `VIBRATO_DEPTH` semitones
+// (times the modulation depth) at `lfo_rate` Hz. With a glide, it starts
+// `glide` semitones away and slides linearly to the note within
+// `glide_time` seconds.
+struct BendCursor<'a> {
+    bends: &'a [(f64, f64)],
+    next: usize,
+    base_inc: f64,
+    range: f64,
+    inc: f64,
+    vibrato: &'a [(f64, f64)],
+    vibrato_next: usize,
+    depth: f64,
+    lfo_rate: f64,
+    glide: f64,
+    glide_time: f64,
+}
+
+impl<'a> BendCursor<'a> {
+    fn new(bends: &'a [(f64, f64)], base_inc: f64, range: f64) -> Self {
+        BendCursor {
+            bends, next: 0, base_inc, range, inc: base_inc,
+            vibrato: &[], vibrato_next: 0, depth: 0.0, lfo_rate: 0.0,
+            glide: 0.0, glide_time: 0.0,
+        }
+    }
+
+    fn with_vibrato(self, vibrato: &'a [(f64, f64)], lfo_rate: f64) -> Self {
+        BendCursor { vibrato, lfo_rate, ..self }
+    }
+
+    fn with_glide(self, glide: f64, glide_time: f64) -> Self {
+        BendCursor { glide, glide_time, ..self }
+    }
+
+    // `time` in seconds since note start; must not decrease between calls
+    fn phase_inc(&mut self, time: f64) -> f64 {
+        while self.next < self.bends.len() && self.bends[self.next].0 <= time {
+            let semitones = self.bends[self.next].1 * self.range;
+            self.inc = self.base_inc * 2.0_f64.powf(semitones / 12.0);
+            self.next += 1;
+        }
+        while self.vibrato_next < self.vibrato.len() && self.vibrato[self.vibrato_next].0 <= time {
+            self.depth = self.vibrato[self.vibrato_next].1;
+            self.vibrato_next += 1;
+        }
+        let gliding = self.glide != 0.0 && time < self.glide_time;
+        if self.depth == 0.0 && !gliding {
+            return self.inc;
+        }
+        let mut semitones = self.depth * VIBRATO_DEPTH * (2.0 * PI * self.lfo_rate * time).sin();
+        if gliding {
+            semitones += self.glide * (1.0 - time / self.glide_time);
+        }
+        self.inc * 2.0_f64.powf(semitones / 12.0)
+    }
+}
+
+// Identifies the samples of one voice of a note without bends, vibrato,
+// aftertouch or glide: key, drum, velocity, program, the bits of the
+// duration, of the chorus detune and of the pan, and the number of voices
+type VoiceKey = (u8, bool, u8, u8, u64, u64, u64, usize);
+
+// Adds all notes to `left`/`right`, which hold the output starting at
+// sample `offset` (`right` is empty in mono). Every slice processes the
+// notes in the same order, so rendering the buffer in slices gives the
+// same result as rendering it in one piece. With `note_cache`, once a
+// voice of a note without bends, vibrato, aftertouch or glide repeats
+// with exactly the same duration and velocity, its samples are kept (up
+// to `NOTE_CACHE_BYTES`) and copied for further repetitions (drums,
+// arpeggios). A copy is bit-identical to rendering the voice again, so
+// the output depends neither on the cache nor on the slicing.
+fn render_notes(
+    notes: &[Note],
+    settings: &SynthSettings,
+    offset: usize,
+    left: &mut [f32],
+    right: &mut [f32],
+) {
+    let envelope = &settings.envelope;
+    let wave = settings.wave;
+    let release = envelope.release;
+    let stereo = !right.is_empty();
+    let slice_end = offset + left.len();
+    let rate = settings.sample_rate as f64;
+    let mut cache: HashMap<VoiceKey, (Vec<f32>, Vec<f32>)> = HashMap::new();
+    let mut seen: HashSet<VoiceKey> = HashSet::new();
+    let mut cache_bytes = 0;
+
+    for n in notes {
+        let is_drum = n.channel == 9; // Channel 10 in MIDI is index 9
+        let (freq, duration, drum_wave) = if is_drum {
+            drum_sound(n.midi_key, &settings.drum_map)
+        } else {
+            (midi_to_freq(n.midi_key), n.duration, wave)
+        };
+        let cacheable = settings.note_cache
+            && (is_drum || (n.bends.is_empty() && n.vibrato.is_empty()
+                && n.pressure.is_empty() && n.glide_from == 0.0));
+        let velocity = n.velocity;
+        let amp = (velocity as f64 / 127.0) * 0.3;
+        let overtones = match &settings.harmonics {
+            Some(harmonics) => harmonics.as_slice(),
+            None => overtones_for_program(n.program),
+        };
+        let overtone_sum: f64 = overtones.iter().enumerate()
+            .map(|(i, &ov)| ov * overtone_weight(i, velocity))
+            .sum();
+
+        let start_s = (n.start_time * rate) as usize;
+        let tail = if is_drum { 0.0 } else { release };
+        let len_s = ((duration + tail) * rate) as usize;
+
+        // Part of the note that falls into this slice
+        let first = start_s.max(offset);
+        let last = (start_s + len_s).min(slice_end);
+        if first >= last { continue; }
+
+        // With --chorus, two voices detuned by the depth in opposite
+        // directions and spread apart in the stereo field, each at half
+        // the level. Drums stay a single voice.
+        let voices = match settings.chorus {
+            Some(cents) if !is_drum => {
+                let ratio = 2.0f64.powf(cents / 1200.0);
+                vec![(1.0 / ratio, (n.pan - CHORUS_SPREAD).max(-1.0)),
+                    (ratio, (n.pan + CHORUS_SPREAD).min(1.0))]
+            }
+            _ => vec![(1.0, n.pan)],
+        };
+        let voice_gain = 1.0 / voices.len() as f64;
+
+        for &(detune, pan) in &voices {
+            // Constant power panning
+            let angle = (pan + 1.0) * PI / 4.0;
+            let (gain_l, gain_r) = if stereo { (angle.cos(), angle.sin()) } else { (1.0, 0.0) };
+
+            let key = cacheable.then(|| (n.midi_key, is_drum, velocity, n.program,
+                duration.to_bits(), detune.to_bits(), pan.to_bits(), voices.len()));
+            if let Some((samples_l, samples_r)) = key.and_then(|key| cache.get(&key)) {
+                let range = first - start_s..last - start_s;
+                for (i, &out) in (first - offset..).zip(&samples_l[range.clone()]) {
+                    left[i] += out;
+                }
+                if stereo {
+                    for (i, &out) in (first - offset..).zip(&samples_r[range]) {
+                        right[i] += out;
+                    }
+                }
+                continue;
+            }
+            // Only voices that repeat are kept, and only as far as the budget allows
+            let bytes = len_s * if stereo { 8 } else { 4 };
+            let repeated = key.is_some_and(|key| !seen.insert(key));
+            let record = repeated && first == start_s && last == start_s + len_s
+                && cache_bytes + bytes <= NOTE_CACHE_BYTES;
+            let capacity = if record { len_s } else { 0 };
+            let mut recorded_l = Vec::with_capacity(capacity);
+            let mut recorded_r = Vec::with_capacity(if stereo { capacity } else { 0 });
+
+            // Phase accumulator (normalized to one period). For a note that
+            // began in an earlier slice, advance it the same way sample by
+            // sample, so the phase matches exactly.
+            let bends: &[(f64, f64)] = if is_drum { &[] } else { &n.bends };
+            let vibrato: &[(f64, f64)] = if is_drum { &[] } else { &n.vibrato };
+            let glide = if is_drum { 0.0 } else { n.glide_from };
+            let mut bend = BendCursor::new(bends, freq * detune / rate, settings.bend_range)
+                .with_vibrato(vibrato, settings.vibrato_rate)
+                .with_glide(glide, settings.glide.unwrap_or(0.0));
+            let mut phase = 0.0;
+            for t in 0..(first - start_s) {
+                phase = (phase + bend.phase_inc(t as f64 / rate)).fract();
+            }
+            let pressure: &[(f64, f64)] = if is_drum { &[] } else { &n.pressure };
+            let mut pressure_next = 0;
+            let mut pressed = 0.0;
+
+            for t in (first - start_s)..(last - start_s) {
+                let time_in_note = t as f64 / rate;
+                let phase_inc = bend.phase_inc(time_in_note);
+                let freq = phase_inc * rate;
+
+                let sample_val = if drum_wave == Wave::Noise {
+                    // Sample and hold at twice the frequency
+                    noise((time_in_note * 2.0 * freq) as u32)
+                } else if is_drum {
+                    oscillator(drum_wave, phase)
+                } else if wave == Wave::Sine {
+                    let mut sum = 0.0;
+                    for (ov_idx, &ov_amp) in overtones.iter().enumerate() {
+                        let harmonic = ov_idx as f64 + 1.0;
+                        if freq * harmonic < (rate / 2.0) {
+                            let weight = ov_amp * overtone_weight(ov_idx, velocity);
+                            sum += weight * oscillator(Wave::Sine, (phase * harmonic).fract());
+                        }
+                    }
+                    sum / overtone_sum // Normalize overtones
+                } else {
+                    oscillator(wave, phase)
+                };
+
+                while pressure_next < pressure.len() && pressure[pressure_next].0 <= time_in_note {
+                    pressed = pressure[pressure_next].1;
+                    pressure_next += 1;
+                }
+                let env = if is_drum {
+                    drum_gain(time_in_note, duration)
+                } else {
+                    envelope.pressed_gain(time_in_note, duration, pressed)
+                };
+
+                let out = sample_val * amp * voice_gain * env;
+                let i = start_s + t - offset;
+                let out_l = (out * gain_l) as f32;
+                left[i] += out_l;
+                if stereo {
+                    let out_r = (out * gain_r) as f32;
+                    right[i] += out_r;
+                    if record {
+                        recorded_r.push(out_r);
+                    }
+                }
+                if record {
+                    recorded_l.push(out_l);
+                }
+                phase = (phase + phase_inc).fract();
+            }
+            if let (true, Some(key)) = (record, key) {
+                cache_bytes += bytes;
+                cache.insert(key, (recorded_l, recorded_r));
+            }
+        }
+    }
+}
+
+// Renders the samples from `offset` on into `left`/`right` (`right` is
+// empty in mono). With more than one thread, the block is split into
+// contiguous slices that are rendered in parallel; the result is
+// bit-identical to a single thread.
+fn render_block(
+    notes: &[Note],
+    settings: &SynthSettings,
+    offset: usize,
+    left: &mut [f32],
+    right: &mut [f32],
+) {
+    if settings.oversample > 1 {
+        render_oversampled(notes, settings, offset, left, right);
+        return;
+    }
+
+    let stereo = !right.is_empty();
+    let threads = settings.threads;
+
+    if threads <= 1 {
+        render_notes(notes, settings, offset, left, right);
+        return;
+    }
+
+    let slice_len = left.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let mut right_rest: &mut [f32] = right;
+        for (i, left_slice) in left.chunks_mut(slice_len).enumerate() {
+            let right_len = if stereo { left_slice.len() } else { 0 };
+            let (right_slice, rest) = std::mem::take(&mut right_rest).split_at_mut(right_len);
+            right_rest = rest;
+            scope.spawn(move || {
+                render_notes(notes, settings, offset + i * slice_len, left_slice, right_slice);
+            });
+        }
+    });
+}
+
+// Low-pass for decimating by `factor`: a Blackman-windowed sinc with its
+// cutoff a little below the Nyquist frequency of the target rate, with
+// 16 taps per output sample. The taps sum to 1.
+fn decimation_taps(factor: usize) -> Vec<f32> {
+    let half = 8 * factor;
+    let cutoff = 0.45 / factor as f64; // in cycles per oversampled sample
+    let len = 2 * half + 1;
+    let taps: Vec<f64> = (0..len).map(|i| {
+        let x = i as f64 - half as f64;
+        let sinc = if x == 0.0 { 1.0 } else { (2.0 * PI * cutoff * x).sin() / (PI * x) / (2.0 * cutoff) };
+        let w = 2.0 * PI * i as f64 / (len - 1) as f64;
+        sinc * (0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos())
+    }).collect();
+    let sum: f64 = taps.iter().sum();
+    taps.iter().map(|&t| (t / sum) as f32).collect()
+}
+
+// Renders at `oversample` times the rate and decimates to the output
+// rate, which removes most of the aliasing of square and sawtooth waves
+// on high notes. The oversampled block reaches half a filter length
+// beyond both ends, so each output sample sees the same input however
+// the song is split into blocks, and streaming stays bit-identical.
+fn render_oversampled(
+    notes: &[Note],
+    settings: &SynthSettings,
+    offset: usize,
+    left: &mut [f32],
+    right: &mut [f32],
+) {
+    let factor = settings.oversample;
+    let fine = SynthSettings {
+        sample_rate: settings.sample_rate * factor as u32,
+        oversample: 1,
+        ..*settings
+    };
+    let taps = decimation_taps(factor);
+    let half = taps.len() / 2;
+
+    // Before the start of the song there is only silence
+    let start = offset * factor;
+    let before = half.min(start);
+    let fine_len = left.len() * factor + before + half;
+    let mut fine_left = vec![0.0f32; fine_len];
+    let mut fine_right = vec![0.0f32; if right.is_empty() { 0 } else { fine_len }];
+    render_block(notes, &fine, start - before, &mut fine_left, &mut fine_right);
+
+    for (out, input) in [(left, &fine_left), (right, &fine_right)] {
+        for (i, sample) in out.iter_mut().enumerate() {
+            // Index of the first tap in `input`; taps before it are zero
+            let first = (i * factor + before) as isize - half as isize;
+            let skip = (-first).max(0) as usize;
+            *sample = taps[skip..].iter()
+                .zip(&input[(first + skip as isize) as usize..])
+                .map(|(&t, &x)| t * x)
+                .sum();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterKind {
+    LowPass,
+    HighPass,
+}
+
+// One-pole IIR filter, y[n] = y[n-1] + a * (x[n] - y[n-1]); the high-pass
+// is the input minus the low-passed signal. `y` is the filter state,
+// kept by the caller so that blocks can be filtered one after another.
+fn onepole(buf: &mut [f32], cutoff: f64, rate: u32, kind: FilterKind, y: &mut f64) {
+    let a = 1.0 - (-2.0 * PI * cutoff / rate as f64).exp();
+    for sample in buf.iter_mut() {
+        let x = *sample as f64;
+        *y += a * (x - *y);
+        *sample = match kind {
+            FilterKind::LowPass => *y,
+            FilterKind::HighPass => x - *y,
+        } as f32;
+    }
+}
+
+// Delay line with feedback, the building block of the reverb. As an
+// all-pass, it passes all frequencies at equal level and only smears
+// the phase.
+struct Delay {
+    buf: Vec<f32>,
+    pos: usize,
+    gain: f32,
+}
+
+impl Delay {
+    fn new(samples: usize, gain: f32) -> Self {
+        Delay { buf: vec![0.0; samples.max(1)], pos: 0, gain }
+    }
+
+    fn comb(&mut self, x: f32) -> f32 {
+        let y = self.buf[self.pos];
+        self.buf[self.pos] = x + y * self.gain;
+        self.pos = (self.pos + 1) % self.buf.len();
+        y
+    }
+
+    fn allpass(&mut self, x: f32) -> f32 {
+        let delayed = self.buf[self.pos];
+        let y = delayed - self.gain * x;
+        self.buf[self.pos] = x + self.gain * y;
+        self.pos = (self.pos + 1) % self.buf.len();
+        y
+    }
+}
+
+// Schroeder reverb: four parallel feedback combs followed by two all-
+// passes. The comb feedback is chosen so that the echoes decay by 60 dB
+// within `time` seconds. `spread` lengthens the delays slightly, so that
+// the two channels do not reverberate identically.
+struct Reverb {
+    combs: Vec<Delay>,
+    allpasses: Vec<Delay>,
+}
+
+impl Reverb {
+    fn new(time: f64, rate: u32, spread: f64) -> Self {
+        let samples = |ms: f64| ((ms + spread) * rate as f64 / 1000.0) as usize;
+        let combs = [29.7, 37.1, 41.1, 43.7].iter()
+            .map(|&ms| {
+                let feedback = 10.0_f64.powf(-3.0 * ms / 1000.0 / time.max(0.01));
+                Delay::new(samples(ms), feedback as f32)
+            })
+            .collect();
+        let allpasses = [5.0, 1.7].iter().map(|&ms| Delay::new(samples(ms), 0.7)).collect();
+        Reverb { combs, allpasses }
+    }
+
+    fn process(&mut self, buf: &mut [f32]) {
+        for sample in buf.iter_mut() {
+            let x = *sample;
+            let mut wet = self.combs.iter_mut().map(|c| c.comb(x)).sum::<f32>() / 4.0;
+            for allpass in &mut self.allpasses {
+                wet = allpass.allpass(wet);
+            }
+            *sample = x + REVERB_WET * wet;
+        }
+    }
+}
+
+// State of the effects over the final mix for the left and right channel,
+// carried from block to block when streaming
+struct MixState {
+    filters: [[f64; 2]; 2], // low-pass and high-pass
+    reverbs: Vec<Reverb>,
+}
+
+impl MixState {
+    fn new(settings: &SynthSettings) -> Self {
+        let reverbs = match settings.reverb {
+            Some(time) => vec![
+                Reverb::new(time, settings.sample_rate, 0.0),
+                Reverb::new(time, settings.sample_rate, 0.5),
+            ],
+            None => Vec::new(),
+        };
+        MixState { filters: [[0.0; 2]; 2], reverbs }
+    }
+}
+
+// Runs the reverb and filters chosen in `settings` over a block of the
+// final mix
+fn process_mix(
+    left: &mut [f32],
+    right: &mut [f32],
+    settings: &SynthSettings,
+    state: &mut MixState,
+) {
+    for (i, buf) in [left, right].into_iter().enumerate() {
+        if let Some(reverb) = state.reverbs.get_mut(i) {
+            reverb.process(buf);
+        }
+        let filter = &mut state.filters[i];
+        if let Some(cutoff) = settings.lowpass {
+            onepole(buf, cutoff, settings.sample_rate, FilterKind::LowPass, &mut filter[0]);
+        }
+        if let Some(cutoff) = settings.highpass {
+            onepole(buf, cutoff, settings.sample_rate, FilterKind::HighPass, &mut filter[1]);
+        }
+    }
+}
+
+// Fades the first and last few milliseconds of the output in and out, so
+// it starts and ends at zero and does not click, e.g. when looped. The
+// block starts at sample `offset` of `total_samples`.
+fn declick(
+    left: &mut [f32],
+    right: &mut [f32],
+    offset: usize,
+    total_samples: usize,
+    rate: u32,
+) {
+    let fade = (DECLICK_SECONDS * rate as f64) as usize;
+    for buf in [left, right] {
+        for (i, sample) in buf.iter_mut().enumerate() {
+            let pos = offset + i;
+            let edge = pos.min(total_samples - 1 - pos);
+            if edge < fade {
+                *sample *= edge as f32 / fade as f32;
+            }
+        }
+    }
+}
+
+// Renders `total_samples` samples (per channel) in one buffer
+fn render(
+    notes: &[Note],
+    total_samples: usize,
+    settings: &SynthSettings,
+) -> (Vec<f32>, Vec<f32>) {
+    // Buffers initialized with 0.0; the right one stays empty in mono
+    let mut left: Vec<f32> = vec![0.0; total_samples];
+    let mut right: Vec<f32> = vec![0.0; if settings.stereo { total_samples } else { 0 }];
+    render_block(notes, settings, 0, &mut left, &mut right);
+    process_mix(&mut left, &mut right, settings, &mut MixState::new(settings));
+    declick(&mut left, &mut right, 0, total_samples, settings.sample_rate);
+    (left, right)
+}
+
+// Loudest sample over both channels and its index
+fn find_peak(left: &[f32], right: &[f32]) -> (f32, usize) {
+    let mut max_val = 0.0f32;
+    let mut peak_index = 0;
+    for (i, &sample) in left.iter().enumerate() {
+        let abs_val = sample.abs().max(right.get(i).map_or(0.0, |s| s.abs()));
+        if abs_val > max_val {
+            max_val = abs_val;
+            peak_index = i;
+        }
+    }
+    (max_val, peak_index)
+}
+
+// Scale from the mix to 16 bit. By default, the loudest sample becomes
+// 32000, but quiet mixes are not amplified beyond 1.0 = 32000. With a
+// peak level, the loudest sample is brought exactly to that level.
+// Without normalization, 1.0 is full scale and louder samples clip.
+fn norm_factor(max_val: f32, settings: &SynthSettings) -> f32 {
+    if !settings.normalize {
+        return 32768.0;
+    }
+    if let Some(db) = settings.peak_db {
+        let target = 32768.0 * 10f32.powf(db as f32 / 20.0);
+        return if max_val > 0.0 { target / max_val } else { target };
+    }
+    let mut norm_factor = 32000.0;
+    if max_val > 0.0 {
+        norm_factor = 32000.0 / max_val;
+    }
+    if norm_factor > 32000.0 {
+        norm_factor = 32000.0;
+    }
+    norm_factor
+}
+
+// Soft clipping for `limit`, with 1.0 as full scale: unchanged up to
+// the knee, above it a tanh curve that continues with the same slope
+// and approaches full scale without reaching it.
+fn soft_clip(x: f32) -> f32 {
+    let level = x.abs();
+    if level <= LIMIT_KNEE {
+        return x;
+    }
+    let room = 1.0 - LIMIT_KNEE;
+    (LIMIT_KNEE + room * ((level - LIMIT_KNEE) / room).tanh()).copysign(x)
+}
+
+// `value` soft-clipped against `full_scale` if `limit` is set
+fn limited(value: f32, full_scale: f32, limit: bool) -> f32 {
+    if limit { soft_clip(value / full_scale) * full_scale } else { value }
+}
+
+// Appends the samples in the given format, interleaved L/R frames in
+// stereo. `norm_factor` scales to 16 bit; 24 bit uses 256 times that,
+// float ignores it. With `limit`, peaks are soft-clipped before the
+// conversion instead of being clipped hard.
+fn encode_frames(
+    left: &[f32],
+    right: &[f32],
+    norm_factor: f32,
+    format: SampleFormat,
+    limit: bool,
+    out: &mut Vec<u8>,
+) {
+    let stereo = !right.is_empty();
+    for i in 0..left.len() {
+        let frame = if stereo { &[left[i], right[i]][..] } else { &[left[i]][..] };
+        for &sample in frame {
+            match format {
+                SampleFormat::Pcm16 => {
+                    let val = limited(sample * norm_factor, 32768.0, limit) as i32;
+                    let clamped = val.clamp(-32768, 32767) as i16;
+                    out.extend_from_slice(&clamped.to_le_bytes());
+                }
+                SampleFormat::Pcm24 => {
+                    let val = limited(sample * norm_factor * 256.0, 8_388_608.0, limit) as i32;
+                    let clamped = val.clamp(-8_388_608, 8_388_607);
+                    out.extend_from_slice(&clamped.to_le_bytes()[..3]);
+                }
+                SampleFormat::Float32 => {
+                    out.extend_from_slice(&limited(sample, 1.0, limit).to_le_bytes());
+                }
+            }
+        }
+    }
+}
+
+// Renders block by block so that memory use does not grow with the
+// length of the file. As normalization needs the overall peak first,
+// everything is rendered twice: once to find the peak, once to write
+// (only once without normalization). The output is identical to
+// rendering in one buffer. The length is known in advance, so the
+// header is written first and the output need not be seekable.
+fn stream_to_wav(
+    f: &mut impl Write,
+    notes: &[Note],
+    total_samples: usize,
+    settings: &SynthSettings,
+) -> io::Result<usize> {
+    let stereo = settings.stereo;
+    let channels = if stereo { 2 } else { 1 };
+    let block = settings.sample_rate as usize * STREAM_SECONDS;
+    let mut left = vec![0.0f32; block.min(total_samples)];
+    let mut right = vec![0.0f32; if stereo { left.len() } else { 0 }];
+
+    let mut max_val = 0.0f32;
+    let mut peak_index = 0;
+    let mut state = MixState::new(settings);
+    let first_pass = if settings.normalize { total_samples } else { 0 };
+    for offset in (0..first_pass).step_by(block) {
+        let len = block.min(total_samples - offset);
+        let (l, r) = (&mut left[..len], &mut right[..if stereo { len } else { 0 }]);
+        l.fill(0.0);
+        r.fill(0.0);
+        render_block(notes, settings, offset, l, r);
+        process_mix(l, r, settings, &mut state);
+        declick(l, r, offset, total_samples, settings.sample_rate);
+        let (block_max, block_index) = find_peak(l, r);
+        if block_max > max_val {
+            max_val = block_max;
+            peak_index = offset + block_index;
+        }
+    }
+    let norm_factor = norm_factor(max_val, settings);
+
+    let format = settings.format;
+    let bits = format.bits_per_sample();
+    write_wav_header(f, total_samples as u32, channels, bits, format.audio_format(), settings.sample_rate)?;
+    let block_align = bits / 8 * channels;
+    let mut out_buffer = Vec::with_capacity(left.len() * block_align as usize);
+    let mut state = MixState::new(settings);
+    for offset in (0..total_samples).step_by(block) {
+        let len = block.min(total_samples - offset);
+        let (l, r) = (&mut left[..len], &mut right[..if stereo { len } else { 0 }]);
+        l.fill(0.0);
+        r.fill(0.0);
+        render_block(notes, settings, offset, l, r);
+        process_mix(l, r, settings, &mut state);
+        declick(l, r, offset, total_samples, settings.sample_rate);
+        if !settings.normalize {
+            let (block_max, block_index) = find_peak(l, r);
+            if block_max > max_val {
+                max_val = block_max;
+                peak_index = offset + block_index;
+            }
+        }
+        out_buffer.clear();
+        encode_frames(l, r, norm_factor, format, settings.limit, &mut out_buffer);
+        f.write_all(&out_buffer)?;
+    }
+
+    Ok(peak_index)
+}
+
+// Writes to standard output if `filename` is "-". Returns the time of
+// the loudest sample in seconds.
+pub fn synthesize_and_write(
+    filename: &str,
+    notes: &[Note],
+    total_duration: f64,
+    settings: &SynthSettings,
+) -> io::Result<f64> {
+    let release = settings.envelope.release;
+    let stereo = settings.stereo;
+
+    // Extend the buffer so that no note's release tail is cut off
+    let total_duration = notes.iter()
+        .map(|n| {
+            if n.channel == 9 {
+                return n.start_time + drum_sound(n.midi_key, &settings.drum_map).1;
+            }
+            n.start_time + n.duration + release
+        })
+        .fold(total_duration, f64::max);
+
+    // A corrupt file can place events days into the song. Cut it off
+    // instead of trying to allocate a buffer for all of that.
+    let kept: Vec<Note>;
+    let (notes, total_duration) = if total_duration.is_finite() && total_duration <= settings.max_duration {
+        (notes, total_duration)
+    } else {
+        eprintln!("Warning: duration of {:.0} s cut to {:.0} s (see --max-duration)",
+            total_duration, settings.max_duration);
+        kept = notes.iter().filter(|n| n.start_time < settings.max_duration).cloned().collect();
+        (&kept[..], settings.max_duration)
+    };
+
+    let rate = settings.sample_rate;
+    let total_samples = (total_duration * rate as f64).ceil() as usize;
+
+    // The RIFF sizes are 32 bit, whatever the limit above allows
+    let channels: u64 = if stereo { 2 } else { 1 };
+    let data_bytes = total_samples as u64 * channels * (settings.format.bits_per_sample() / 8) as u64;
+    if data_bytes > (u32::MAX - 36) as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+            "{:.0} s of audio exceed the maximum size of a WAV file", total_duration)));
+    }
+
+    status(format_args!("Synthesizing {} notes in {} samples...", notes.len(), total_samples));
+
+    let mut f: BufWriter<Box<dyn Write>> = BufWriter::new(if filename == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        Box::new(File::create(filename)?)
+    });
+    let peak_index = if settings.stream {
+        stream_to_wav(&mut f, notes, total_samples, settings)?
+    } else {
+        let (left, right) = render(notes, total_samples, settings);
+
+        // Normalization and writing
+        let format = settings.format;
+        let channels = channels as u16;
+        let bits = format.bits_per_sample();
+        write_wav_header(&mut f, total_samples as u32, channels, bits, format.audio_format(), rate)?;
+        let (max_val, peak_index) = find_peak(&left, &right);
+
+        // Buffer for block-wise writing (efficiency)
+        let mut out_buffer = Vec::with_capacity(total_samples * (bits / 8 * channels) as usize);
+        encode_frames(&left, &right, norm_factor(max_val, settings), format, settings.limit,
+            &mut out_buffer);
+        f.write_all(&out_buffer)?;
+        peak_index
+    };
+    f.flush()?;
+
+    if filename != "-" {
+        status(format_args!("WAV written to: {}", filename));
+    }
+    Ok(peak_index as f64 / rate as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn midi_file(track: &[u8]) -> Vec<u8> {
+        midi_file_with_tracks(0, &[track])
+    }
+
+    // Centered piano note at velocity 100, without any controller curves
+    fn test_note(start_time: f64, duration: f64, midi_key: u8, channel: u8) -> Note {
+        Note {
+            start_time, duration, midi_key, velocity: 100, channel,
+            pan: 0.0, program: 0, bends: Vec::new(), vibrato: Vec::new(),
+            pressure: Vec::new(), glide_from: 0.0,
+        }
+    }
+
+    fn midi_file_with_tracks(format: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"MThd");
+        data.extend_from_slice(&6u32.to_be_bytes());
+        data.extend_from_slice(&format.to_be_bytes());
+        data.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        data.extend_from_slice(&480u16.to_be_bytes()); // division
+        for track in tracks {
+            data.extend_from_slice(b"MTrk");
+            data.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            data.extend_from_slice(track);
+        }
+        data
+    }
+
+    #[test]
+    fn polyphony_counts_velocity_zero_as_note_off() {
+        let track = [
+            0x00, 0x90, 60, 100, // C4 on
+            0x00, 64, 100,       // E4 on (running status)
+            0x83, 0x60, 60, 0,   // C4 off as vel 0, delta 480
+            0x00, 67, 100,       // G4 on
+            0x83, 0x60, 64, 0,   // E4 off as vel 0
+            0x00, 67, 0,         // G4 off as vel 0
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        assert_eq!(notes.len(), 3);
+        assert_eq!(peak_polyphony(&notes), 2);
+    }
+
+    #[test]
+    fn format_0_file_parses_into_timed_notes() {
+        // 480 ticks per beat at 60 BPM (1 s per beat), through the
+        // public reader as a caller with an in-memory file would use it
+        let track = [
+            0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM
+            0x00, 0x90, 60, 100,                      // C4 on
+            0x83, 0x60, 0x80, 60, 64,                 // C4 off after a beat
+            0x81, 0x70, 0x91, 72, 80,                 // C5 on, channel 1, half a beat later
+            0x87, 0x40, 0x81, 72, 0,                  // C5 off after two beats
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_reader(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        assert_eq!(spans(&notes), vec![(60, 0.0, 1.0), (72, 1.5, 2.0)]);
+        let by_key = |key| notes.iter().find(|n| n.midi_key == key).unwrap();
+        assert_eq!((by_key(60).channel, by_key(60).velocity), (0, 100));
+        assert_eq!((by_key(72).channel, by_key(72).velocity), (1, 80));
+    }
+
+    fn spans(notes: &[Note]) -> Vec<(u8, f64, f64)> {
+        let mut spans: Vec<(u8, f64, f64)> = notes.iter()
+            .map(|n| (n.midi_key, n.start_time, n.duration))
+            .collect();
+        spans.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        spans
+    }
+
+    #[test]
+    fn zero_division_is_rejected() {
+        let track: &[u8] = &[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        for division in [0x0000u16, 0x8018] {
+            let mut data = midi_file(track);
+            data[12..14].copy_from_slice(&division.to_be_bytes());
+            let err = parse_midi_from(&mut Cursor::new(data)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn extended_header_is_skipped() {
+        let track: &[u8] = &[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        let mut data = midi_file(track);
+        data[7] = 8; // header length
+        data.splice(14..14, [0xAB, 0xCD]);
+        let (events, division) = parse_midi_from(&mut Cursor::new(data)).unwrap();
+        assert_eq!(division, 480);
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn gzipped_input_is_decompressed() {
+        let data = midi_file(&[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00]);
+        assert_eq!(gunzip_if_compressed(data.clone()).unwrap(), data);
+
+        // The same file after gzip -9n
+        let gzipped = vec![
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xf3, 0x0d, 0xc9,
+            0x48, 0x61, 0x60, 0x60, 0x60, 0x03, 0x62, 0x46, 0xc6, 0x07, 0xbe, 0x21, 0x45,
+            0xd9, 0x40, 0x16, 0x0f, 0xc3, 0x04, 0x9b, 0x94, 0x84, 0x06, 0x1b, 0x06, 0x86,
+            0xff, 0xfa, 0x0c, 0x00, 0x1b, 0x9a, 0xaa, 0x3a, 0x22, 0x00, 0x00, 0x00,
+        ];
+        #[cfg(feature = "gzip-command")]
+        {
+            assert_eq!(gunzip_if_compressed(gzipped.clone()).unwrap(), data);
+            assert!(gunzip_if_compressed(gzipped[..20].to_vec()).is_err());
+        }
+        #[cfg(not(feature = "gzip-command"))]
+        assert_eq!(gunzip_if_compressed(gzipped).unwrap_err().exit_code(), 3);
+    }
+
+    #[test]
+    fn truncated_track_reports_its_length() {
+        let track: &[u8] = &[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        let mut data = midi_file_with_tracks(1, &[track]);
+        data.truncate(data.len() - 4);
+        let err = parse_midi_reader(&mut Cursor::new(data)).unwrap_err();
+        assert_eq!(err.class, ErrorClass::Parse);
+        assert_eq!(err.offset, Some(22));
+        assert!(err.message.contains("12 bytes declared, 8 left"), "{}", err.message);
+    }
+
+    #[test]
+    fn format_2_plays_patterns_in_sequence() {
+        // Each pattern: one beat of silence, then a one-beat note
+        let pattern_a: &[u8] = &[
+            0x83, 0x60, 0x90, 60, 100,
+            0x83, 0x60, 0x80, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let pattern_b: &[u8] = &[
+            0x83, 0x60, 0x90, 67, 100,
+            0x83, 0x60, 0x80, 67, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+
+        let data = midi_file_with_tracks(2, &[pattern_a, pattern_b]);
+        let (events, division) = parse_midi_from(&mut Cursor::new(data)).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+        assert_eq!(spans(&notes), vec![(60, 0.5, 0.5), (67, 1.5, 0.5)]);
+
+        // The same tracks in format 1 play simultaneously
+        let data = midi_file_with_tracks(1, &[pattern_a, pattern_b]);
+        let (events, division) = parse_midi_from(&mut Cursor::new(data)).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+        assert_eq!(spans(&notes), vec![(60, 0.5, 0.5), (67, 0.5, 0.5)]);
+    }
+
+    #[test]
+    fn note_off_at_the_tick_of_a_retrigger_in_another_track() {
+        // The restart of C4 comes in the first track, its end (Note On
+        // with velocity 0) in the second one, both at one beat
+        let restart: &[u8] = &[
+            0x83, 0x60, 0x90, 60, 80,
+            0x83, 0x60, 0x80, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let first: &[u8] = &[
+            0x00, 0x90, 60, 100,
+            0x83, 0x60, 0x90, 60, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+
+        let data = midi_file_with_tracks(1, &[restart, first]);
+        let (events, division) = parse_midi_from(&mut Cursor::new(data)).unwrap();
+        let at_beat: Vec<EventType> =
+            events.iter().filter(|e| e.abs_tick == 480).map(|e| e.event_type).collect();
+        assert_eq!(at_beat, vec![EventType::NoteOff, EventType::NoteOn]);
+
+        let (notes, _) = convert_events_to_notes(&events, division);
+        assert_eq!(spans(&notes), vec![(60, 0.0, 0.5), (60, 0.5, 0.5)]);
+        assert_eq!(notes.iter().map(|n| n.velocity).collect::<Vec<_>>(), vec![100, 80]);
+    }
+
+    #[test]
+    fn sustain_pedal_extends_released_notes() {
+        // One beat (480 ticks) is 0.5 s
+        let track = [
+            0x00, 0x90, 60, 100,       // C4 on
+            0x00, 0xB0, 64, 127,       // pedal down
+            0x83, 0x60, 0x80, 60, 0,   // C4 off at 0.5 s, held by pedal
+            0x00, 0x90, 64, 100,       // E4 on
+            0x83, 0x60, 0xB0, 64, 0,   // pedal up at 1.0 s ends C4
+            0x00, 64, 127,             // re-pedal right away (running status)
+            0x83, 0x60, 0x80, 64, 0,   // E4 off at 1.5 s, held by pedal
+            0x83, 0x60, 0x90, 67, 100, // G4 on at 2.0 s
+            0x83, 0x60, 0x80, 67, 0,   // G4 off at 2.5 s, pedal still down
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, total_duration) = convert_events_to_notes(&events, division);
+
+        assert_eq!(total_duration, 3.5);
+        assert_eq!(spans(&notes), vec![
+            (60, 0.0, 1.0),
+            (64, 0.5, 3.0),
+            (67, 2.0, 1.5),
+        ]);
+    }
+
+    #[test]
+    fn voice_limit_cuts_the_oldest_note() {
+        let track = [
+            0x00, 0x90, 60, 100,       // C4 on
+            0x00, 0x90, 64, 50,        // E4 on, quieter
+            0x83, 0x60, 0x90, 67, 100, // G4 on at 0.5 s
+            0x00, 0x91, 48, 100,       // other channel: not limited
+            0x83, 0x60, 0x80, 60, 0,   // all off at 1.0 s
+            0x00, 0x80, 64, 0,
+            0x00, 0x80, 67, 0,
+            0x00, 0x81, 48, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (mut notes, _) = convert_events_to_notes(&events, division);
+
+        assert_eq!(limit_voices(&mut notes, 2), 1);
+        assert_eq!(spans(&notes), vec![
+            (48, 0.5, 0.5),
+            (60, 0.0, 1.0),
+            (64, 0.0, 0.5),
+            (67, 0.5, 0.5),
+        ]);
+
+        // With a single voice, a note replaced right at its start is dropped
+        let (mut notes, _) = convert_events_to_notes(&events, division);
+        assert_eq!(limit_voices(&mut notes, 1), 2);
+        assert_eq!(spans(&notes), vec![(48, 0.5, 0.5), (64, 0.0, 0.5), (67, 0.5, 0.5)]);
+    }
+
+    #[test]
+    fn metronome_follows_tempo_and_time_signature() {
+        let track = [
+            0x00, 0xFF, 0x58, 0x04, 3, 2, 24, 8,  // 3/4
+            0x8B, 0x20, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM after 3 beats
+            0x00, 0xFF, 0x58, 0x04, 6, 3, 24, 8,  // 6/8
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let clicks: Vec<(f64, u8)> = metronome_notes(&events, division, 3.0).iter()
+            .map(|n| (n.start_time, n.midi_key))
+            .collect();
+        assert_eq!(clicks, vec![
+            (0.0, 34), (0.5, 33), (1.0, 33),
+            (1.5, 34), (2.0, 33), (2.5, 33),
+        ]);
+    }
+
+    #[test]
+    fn pitch_bend_curve_follows_note_lifetime() {
+        let track = [
+            0x00, 0xE0, 0x00, 0x20,    // bend -0.5 before the note
+            0x00, 0x90, 60, 100,       // C4 on
+            0x83, 0x60, 0xE0, 0x00, 0x40, // center at 0.5 s
+            0x83, 0x60, 0xE0, 0x7F, 0x7F, // full up at 1.0 s
+            0x00, 0x80, 60, 0,         // C4 off at 1.0 s
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].bends, vec![(0.0, -0.5), (0.5, 0.0)]);
+
+        let mut bend = BendCursor::new(&notes[0].bends, 0.01, 2.0);
+        assert!((bend.phase_inc(0.0) - 0.01 * 2.0_f64.powf(-1.0 / 12.0)).abs() < 1e-12);
+        assert_eq!(bend.phase_inc(0.6), 0.01);
+    }
+
+    #[test]
+    fn notes_without_note_off_ring_until_the_end() {
+        // 480 ticks per beat at 120 BPM, so one beat is 0.5 s
+        let track = [
+            0x00, 0x90, 60, 100,       // C4 on
+            0x83, 0x60, 0x80, 60, 0,   // C4 off at 0.5 s
+            0x00, 0x90, 64, 100,       // final chord without Note Offs
+            0x00, 67, 100,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, total_duration) = convert_events_to_notes(&events, division);
+
+        assert_eq!(total_duration, 0.5 + DEFAULT_TAIL);
+        assert_eq!(spans(&notes), vec![
+            (60, 0.0, 0.5),
+            (64, 0.5, DEFAULT_TAIL),
+            (67, 0.5, DEFAULT_TAIL),
+        ]);
+    }
+
+    #[test]
+    fn mod_wheel_adds_vibrato() {
+        let track = [
+            0x00, 0x90, 60, 100,       // C4 on
+            0x83, 0x60, 0xB0, 1, 127,  // full modulation at 0.5 s
+            0x83, 0x60, 0x80, 60, 0,   // C4 off at 1.0 s
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].vibrato, vec![(0.0, 0.0), (0.5, 1.0)]);
+
+        // No vibrato before 0.5 s; at 0.65 s (3.25 LFO periods) the pitch is at its highest
+        let mut bend = BendCursor::new(&[], 0.01, 2.0).with_vibrato(&notes[0].vibrato, 5.0);
+        assert_eq!(bend.phase_inc(0.25), 0.01);
+        let top = bend.phase_inc(0.65);
+        assert!((top - 0.01 * 2.0_f64.powf(VIBRATO_DEPTH / 12.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn transpose_leaves_drums_and_drops_notes_out_of_range() {
+        let note = |midi_key, channel| test_note(0.0, 0.5, midi_key, channel);
+        let mut notes = vec![note(60, 0), note(2, 1), note(36, 9), note(126, 0)];
+        assert_eq!(transpose_notes(&mut notes, -3), 1);
+        let keys: Vec<(u8, u8)> = notes.iter().map(|n| (n.midi_key, n.channel)).collect();
+        assert_eq!(keys, vec![(57, 0), (36, 9), (123, 0)]);
+        assert_eq!(transpose_notes(&mut notes, 5), 1);
+        assert_eq!(notes.len(), 2);
+    }
+
+    #[test]
+    fn quantize_snaps_to_the_grid_of_the_tempo_map() {
+        let note = |start_time, duration| test_note(start_time, duration, 60, 0);
+        // 120 BPM, then 60 BPM from 1 s on: sixteenths of 0.125 s, later 0.25 s
+        let tempo = MidiEvent {
+            abs_tick: 960, event_type: EventType::SetTempo, channel: 0, note: 0, velocity: 0,
+            tempo_micros: 1_000_000, text: String::new(),
+        };
+        let map = build_tempo_map(&[tempo], 480);
+        assert_eq!(seconds_to_tick(1.5, &map, 480), 1200.0);
+
+        let mut notes = vec![note(0.14, 0.3), note(0.2, 0.01), note(1.13, 0.5)];
+        quantize_notes(&mut notes, &map, 480, 16, false);
+        let spans = |notes: &[Note]| {
+            notes.iter().map(|n| (n.start_time, n.duration)).collect::<Vec<_>>()
+        };
+        assert_eq!(spans(&notes), vec![(0.125, 0.3), (0.25, 0.01), (1.25, 0.5)]);
+
+        // Ends on the grid too, never shorter than one step
+        quantize_notes(&mut notes, &map, 480, 16, true);
+        assert_eq!(spans(&notes), vec![(0.125, 0.25), (0.25, 0.125), (1.25, 0.5)]);
+    }
+
+    #[test]
+    fn glide_slides_from_the_previous_note_of_the_channel() {
+        let note = |start_time, midi_key, channel| test_note(start_time, 0.5, midi_key, channel);
+        // Overlapping, close after, too far after, chord, another channel, drums
+        let mut notes = vec![note(0.0, 60, 0), note(0.4, 67, 0), note(0.95, 65, 0),
+            note(2.0, 72, 0), note(2.0, 76, 0), note(2.1, 48, 1), note(2.2, 36, 9)];
+        set_glide(&mut notes, 0.1);
+        let glides: Vec<f64> = notes.iter().map(|n| n.glide_from).collect();
+        assert_eq!(glides, vec![0.0, -7.0, 2.0, 0.0, 0.0, 0.0, 0.0]);
+
+        // Starts 7 semitones low, halfway there after half the glide time
+        let mut bend = BendCursor::new(&[], 0.01, 2.0).with_glide(-7.0, 0.1);
+        assert!((bend.phase_inc(0.0) - 0.01 * 2.0_f64.powf(-7.0 / 12.0)).abs() < 1e-12);
+        assert!((bend.phase_inc(0.05) - 0.01 * 2.0_f64.powf(-3.5 / 12.0)).abs() < 1e-12);
+        assert_eq!(bend.phase_inc(0.1), 0.01);
+    }
+
+    #[test]
+    fn aftertouch_swells_the_sustain() {
+        let track = [
+            0x00, 0x90, 60, 100,       // C4 on
+            0x00, 0x90, 64, 100,       // E4 on
+            0x83, 0x60, 0xD0, 63,      // channel pressure at 0.5 s
+            0x00, 0xA0, 64, 127,       // full key pressure on E4
+            0x83, 0x60, 0x80, 60, 0,   // both off at 1.0 s
+            0x00, 0x80, 64, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+        let curve = |key| notes.iter().find(|n| n.midi_key == key).unwrap().pressure.clone();
+        assert_eq!(curve(60), vec![(0.0, 0.0), (0.5, 63.0 / 127.0)]);
+        assert_eq!(curve(64), vec![(0.0, 0.0), (0.5, 1.0)]);
+
+        let envelope = Envelope { attack: 0.1, decay: 0.1, sustain: 0.6, release: 0.2 };
+        assert_eq!(envelope.pressed_gain(0.5, 1.0, 0.0), 0.6);
+        assert!((envelope.pressed_gain(0.5, 1.0, 1.0) - 0.6 * (1.0 + PRESSURE_SWELL)).abs() < 1e-12);
+        // The release starts from the raised level
+        assert!((envelope.pressed_gain(1.1, 1.0, 1.0) - 0.45).abs() < 1e-12);
+    }
+
+    #[test]
+    fn threaded_rendering_is_bit_identical() {
+        let track = [
+            0x00, 0x90, 60, 100, // C4 on
+            0x00, 64, 80,        // E4 on
+            0x00, 0x99, 36, 100, // bass drum
+            0x83, 0x60, 0x80, 60, 0,
+            0x00, 0x90, 67, 90,  // G4 on
+            0x81, 0x70, 0xE0, 0x00, 0x60, // bend up while E4 and G4 sound
+            0x81, 0x70, 0x90, 64, 0,
+            0x00, 67, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, total_duration) = convert_events_to_notes(&events, division);
+        let total_samples = (total_duration * SAMPLE_RATE as f64).ceil() as usize;
+
+        for (stereo, oversample, chorus) in [(false, 1, None), (true, 1, None), (true, 2, None),
+            (true, 1, Some(8.0))]
+        {
+            let settings = SynthSettings { stereo, oversample, chorus, ..SynthSettings::default() };
+            let (left, right) = render(&notes, total_samples, &settings);
+            for threads in [2, 3, 8] {
+                let (l, r) = render(&notes, total_samples, &SynthSettings { threads, ..settings });
+                let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+                assert_eq!(bits(&l), bits(&left));
+                assert_eq!(bits(&r), bits(&right));
+            }
+        }
+    }
+
+    #[test]
+    fn note_cache_does_not_change_the_output() {
+        let note = |start_time, midi_key, channel| Note {
+            velocity: 101, pan: if channel == 1 { 0.5 } else { 0.0 },
+            ..test_note(start_time, 0.2537, midi_key, channel)
+        };
+        // Repeated arpeggio and drums, the same notes on a panned channel,
+        // and notes played by hand that nearly but not quite repeat
+        let mut notes = Vec::new();
+        for i in 0..8 {
+            let start_time = i as f64 * 0.25;
+            notes.push(note(start_time, [60, 64, 67, 72][i % 4], 0));
+            notes.push(note(start_time, 36, 9));
+            notes.push(note(start_time + 0.125, 64, 1));
+            notes.push(Note {
+                duration: 0.25 + i as f64 * 0.0013, velocity: 97 + i as u8,
+                ..note(start_time, 55, 0)
+            });
+        }
+        let total_samples = SAMPLE_RATE as usize * 3;
+        let bits = |v: &[f32]| v.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+
+        for (stereo, threads, chorus) in [(false, 1, None), (true, 3, None), (true, 1, Some(8.0))] {
+            let settings = SynthSettings { stereo, threads, chorus, ..SynthSettings::default() };
+            let (left, right) = render(&notes, total_samples, &settings);
+            let uncached = SynthSettings { note_cache: false, ..settings };
+            let (l, r) = render(&notes, total_samples, &uncached);
+            assert_eq!(bits(&l), bits(&left));
+            assert_eq!(bits(&r), bits(&right));
+        }
+    }
+
+    #[test]
+    fn oversampling_keeps_low_notes_and_tames_aliasing() {
+        let taps = decimation_taps(4);
+        assert!((taps.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // A high sawtooth aliases below its fundamental without
+        // oversampling; measure that part with a low-pass
+        let note = |key| test_note(0.0, 0.5, key, 0);
+        let settings = SynthSettings { wave: Wave::Sawtooth, ..SynthSettings::default() };
+        let total_samples = SAMPLE_RATE as usize / 2;
+        let low_energy = |oversample| {
+            let (mut left, _) = render(&[note(120)], total_samples,
+                &SynthSettings { oversample, ..settings });
+            for _ in 0..4 {
+                onepole(&mut left, 700.0, SAMPLE_RATE, FilterKind::LowPass, &mut 0.0);
+            }
+            // Only the sustained part, without the attack
+            left[total_samples / 4..].iter().map(|x| x * x).sum::<f32>()
+        };
+        assert!(low_energy(4) < low_energy(1) * 0.6);
+
+        // A low sine note is nearly unchanged
+        let sine = SynthSettings::default();
+        let (plain, _) = render(&[note(48)], total_samples, &sine);
+        let (over, _) = render(&[note(48)], total_samples, &SynthSettings { oversample: 2, ..sine });
+        let max_diff = plain.iter().zip(&over).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+        assert!(max_diff < 0.01, "{}", max_diff);
+    }
+
+    #[test]
+    fn output_is_cut_at_the_maximum_duration() {
+        let note = |start_time| test_note(start_time, 0.5, 60, 0);
+        let path = std::env::temp_dir().join(format!("midisynth-max-{}.wav", std::process::id()));
+        let path = path.to_str().unwrap();
+        let settings = SynthSettings { max_duration: 2.0, sample_rate: 8000, ..SynthSettings::default() };
+        synthesize_and_write(path, &[note(0.0), note(1e9)], 1e9, &settings).unwrap();
+        let len = std::fs::metadata(path).unwrap().len();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(len, 44 + 2 * 8000 * 2);
+
+        // Beyond the 4 GB of a WAV file even with a higher limit
+        let settings = SynthSettings { max_duration: 1e6, ..settings };
+        let err = synthesize_and_write(path, &[note(0.0)], 1e6, &settings).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn streamed_wav_header_is_complete_without_seeking() {
+        // A Vec cannot seek, like a pipe on stdout
+        let note = test_note(0.0, 0.5, 60, 0);
+        let settings = SynthSettings { stream: true, stereo: true, sample_rate: 8000, ..SynthSettings::default() };
+        let mut out = Vec::new();
+        stream_to_wav(&mut out, &[note], 8000, &settings).unwrap();
+        let size_at = |i: usize| u32::from_le_bytes(out[i..i + 4].try_into().unwrap()) as usize;
+        assert_eq!(out.len(), 44 + 8000 * 4);
+        assert_eq!(size_at(4), out.len() - 8);
+        assert_eq!(size_at(40), out.len() - 44);
+    }
+
+    #[test]
+    fn wav_header_and_samples_match_the_format() {
+        let notes = [test_note(0.0, 0.1, 60, 0)];
+        let u16_at = |out: &[u8], i: usize| u16::from_le_bytes([out[i], out[i + 1]]);
+        for (format, tag, bits) in [(SampleFormat::Pcm24, 1, 24), (SampleFormat::Float32, 3, 32)] {
+            let settings = SynthSettings {
+                stream: true, stereo: true, sample_rate: 8000, format, ..SynthSettings::default()
+            };
+            let mut out = Vec::new();
+            stream_to_wav(&mut out, &notes, 800, &settings).unwrap();
+            assert_eq!(u16_at(&out, 20), tag);
+            assert_eq!(u16_at(&out, 22), 2);
+            assert_eq!(u16_at(&out, 32), bits / 8 * 2);
+            assert_eq!(u16_at(&out, 34), bits);
+            assert_eq!(out.len(), 44 + 800 * (bits / 8 * 2) as usize);
+        }
+
+        // 24 bit: half scale and a quarter below zero, three bytes each
+        let mut out = Vec::new();
+        encode_frames(&[0.5, -0.25], &[], 32768.0, SampleFormat::Pcm24, false, &mut out);
+        assert_eq!(out, [0x00, 0x00, 0x40, 0x00, 0x00, 0xE0]);
+        // Float: the sample itself, whatever the normalization factor
+        out.clear();
+        encode_frames(&[0.25], &[], 32768.0, SampleFormat::Float32, false, &mut out);
+        assert_eq!(out, 0.25f32.to_le_bytes());
+    }
+
+    #[test]
+    fn limit_soft_clips_only_the_peaks() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert_eq!(soft_clip(-LIMIT_KNEE), -LIMIT_KNEE);
+        assert!(soft_clip(1.5) > soft_clip(1.0) && soft_clip(1.5) < 1.0);
+        assert_eq!(soft_clip(-3.0), -soft_clip(3.0));
+
+        let samples = [0.1, 1.1, -1.1];
+        let decode = |limit| {
+            let mut out = Vec::new();
+            encode_frames(&samples, &[], 32767.0, SampleFormat::Pcm16, limit, &mut out);
+            out.chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect::<Vec<_>>()
+        };
+        let (hard, soft) = (decode(false), decode(true));
+        assert_eq!(hard[0], soft[0]);
+        assert_eq!((hard[1], hard[2]), (32767, -32768));
+        assert!(soft[1] < 32767 && soft[2] > -32768, "{:?}", soft);
+    }
+
+    #[test]
+    fn drum_map_overrides_general_midi_sounds() {
+        let map = parse_drum_map("# tracker kit\n60 55 0.2 sine\nC#4 9000 0.05 noise\n").unwrap();
+        assert_eq!(drum_sound(60, &map), (55.0, 0.2, Wave::Sine));
+        assert_eq!(drum_sound(61, &map), (9000.0, 0.05, Wave::Noise));
+        assert_eq!(drum_sound(36, &map), drum_params(36));
+
+        let err = parse_drum_map("60 55 0.2 bell\n").unwrap_err();
+        assert_eq!(err.exit_code(), 4);
+        assert!(parse_drum_map("60 55 0.2\n").is_err());
+    }
+
+    #[test]
+    fn harmonics_replace_the_instrument_partials() {
+        assert!(Harmonics::new(&[]).is_none());
+        assert!(Harmonics::new(&[0.0, 0.0]).is_none());
+        assert!(Harmonics::new(&[1.0, -0.5]).is_none());
+        assert!(Harmonics::new(&[0.1; MAX_HARMONICS + 1]).is_none());
+
+        let note = |program| Note { velocity: 127, program, ..test_note(0.0, 0.2, 60, 0) };
+        let total_samples = SAMPLE_RATE as usize / 4;
+        let settings = SynthSettings { normalize: false, ..SynthSettings::default() };
+        let pure = SynthSettings { harmonics: Harmonics::new(&[2.0]), ..settings };
+
+        // Only the fundamental, normalized by the sum of amplitudes: the
+        // same as a plain sine at the note's level, for any program
+        let (piano, _) = render(&[note(0)], total_samples, &pure);
+        let (organ, _) = render(&[note(16)], total_samples, &pure);
+        assert_eq!(piano, organ);
+        let peak = piano.iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        assert!((peak - 0.3).abs() < 0.01, "{}", peak);
+        assert_ne!(render(&[note(0)], total_samples, &settings).0, piano);
+    }
+
+    #[test]
+    fn chorus_spreads_detuned_voices_across_the_stereo_field() {
+        let note = |channel| test_note(0.0, 0.5, 69, channel);
+        let total_samples = SAMPLE_RATE as usize / 2;
+        let dry = SynthSettings { stereo: true, ..SynthSettings::default() };
+        let wet = SynthSettings { chorus: Some(10.0), ..dry };
+
+        let (left, right) = render(&[note(0)], total_samples, &dry);
+        assert_eq!(left, right);
+        let (left, right) = render(&[note(0)], total_samples, &wet);
+        let diff = left.iter().zip(&right).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+        assert!(diff > 0.01, "{}", diff);
+
+        // Drums are not doubled
+        let drum = [Note { midi_key: 36, ..note(9) }];
+        assert_eq!(render(&drum, total_samples, &dry), render(&drum, total_samples, &wet));
+    }
+
+    #[test]
+    fn notes_keep_the_pan_of_their_start() {
+        let track = [
+            0x00, 0xB0, 10, 0,         // channel 0 hard left
+            0x00, 0x90, 60, 100,
+            0x00, 0x91, 64, 100,       // channel 1 has no Pan controller
+            0x60, 0xB0, 10, 127,       // hard right while C4 still sounds
+            0x00, 0x90, 67, 100,
+            0x60, 0x80, 60, 0,
+            0x00, 0x81, 64, 0,
+            0x00, 0x80, 67, 0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        let pan = |key| notes.iter().find(|n| n.midi_key == key).unwrap().pan;
+        assert_eq!(pan(60), -1.0);
+        assert_eq!(pan(64), 0.0);
+        assert_eq!(pan(67), 1.0);
+    }
+
+    #[test]
+    fn text_events_are_decoded_and_timed() {
+        let track = [
+            0x00, 0xFF, 0x03, 4, b'S', b'o', b'n', b'g',
+            0x83, 0x60, 0xFF, 0x05, 3, b'H', 0xC3, 0xA4, // UTF-8 "Hä" at 0.5 s
+            0x00, 0xFF, 0x05, 2, b'H', 0xE4,             // Latin-1 "Hä"
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        assert_eq!(text_events(&events, division), vec![
+            (0.0, META_TRACK_NAME, "Song".to_string()),
+            (0.5, META_LYRIC, "Hä".to_string()),
+            (0.5, META_LYRIC, "Hä".to_string()),
+        ]);
+
+        // A length beyond the end of the file is an error, not a panic
+        let track = [0x00, 0xFF, 0x01, 0x7F, b'x'];
+        assert!(parse_midi_from(&mut Cursor::new(midi_file(&track))).is_err());
+    }
+
+    #[test]
+    fn note_text_is_parsed_into_notes() {
+        assert_eq!(note_name_to_midi("C4"), Some(60));
+        assert_eq!(note_name_to_midi("a4"), Some(69));
+        assert_eq!(note_name_to_midi("F#3"), Some(54));
+        assert_eq!(note_name_to_midi("Bb-1"), Some(10));
+        assert_eq!(note_name_to_midi("G9"), Some(127));
+        assert_eq!(note_name_to_midi("G#9"), None);
+        assert_eq!(note_name_to_midi("H4"), None);
+
+        let text = "# melody\nE4 0.5 0.5 90\n\n60 0 0.5 100\n";
+        let (notes, total_duration) = parse_note_text(text, 1.0).unwrap();
+        assert_eq!(total_duration, 2.0);
+        assert_eq!(spans(&notes), vec![(60, 0.0, 0.5), (64, 0.5, 0.5)]);
+
+        let err = parse_note_text("C4 0 0.5\n", 1.0).unwrap_err();
+        assert_eq!(err.exit_code(), 4);
+    }
+
+    #[test]
+    fn tempo_map_converts_ticks_across_changes() {
+        let tempo = |abs_tick, tempo_micros| MidiEvent {
+            abs_tick, event_type: EventType::SetTempo, channel: 0, note: 0, velocity: 0, tempo_micros,
+            text: String::new(),
+        };
+        // 480 ticks per beat: 120 BPM, then 60 BPM from beat 2 (set twice)
+        let events = [tempo(960, 500000), tempo(960, 1_000_000)];
+        let map = build_tempo_map(&events, 480);
+        assert_eq!(map, vec![(0, 0.0, 500000.0), (960, 1.0, 1_000_000.0)]);
+        assert_eq!(tick_to_seconds(480, &map, 480), 0.5);
+        assert_eq!(tick_to_seconds(1440, &map, 480), 2.0);
+
+        // SMPTE: 25 fps with 40 ticks per frame are 1000 ticks per second
+        let smpte = ((-25i8 as u8 as u16) << 8) | 40;
+        assert_eq!(tick_to_seconds(1500, &build_tempo_map(&events, smpte), smpte), 1.5);
+    }
+
+    #[test]
+    fn tempo_change_during_note_splits_its_duration() {
+        // One beat at 120 BPM (0.5 s), then 60 BPM while the note is held
+        // for one more beat (1 s). mivi has the same test for its converter.
+        let track = [
+            0x00, 0x90, 60, 100,                            // C4 on
+            0x83, 0x60, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM
+            0x83, 0x60, 0x80, 60, 0,                        // C4 off
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+        assert_eq!(spans(&notes), vec![(60, 0.0, 1.5)]);
+    }
+
+    #[test]
+    fn written_midi_parses_back_into_the_same_notes() {
+        let track = [
+            0x00, 0xC1, 40,                   // channel 1: violin
+            0x00, 0xB1, 10, 127,              // hard right
+            0x00, 0x90, 60, 100,              // C4 on
+            0x00, 0x91, 67, 80,               // G4 on
+            0x83, 0x60, 0x80, 60, 0,          // C4 off
+            0x00, 0x90, 60, 90,               // C4 again
+            0x81, 0x70, 0x81, 67, 0,          // G4 off
+            0x81, 0x70, 0x80, 60, 0,          // C4 off
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        let data = midi_bytes(&notes, division);
+        let (events, division) = parse_midi_from(&mut Cursor::new(data)).unwrap();
+        let (round_trip, _) = convert_events_to_notes(&events, division);
+
+        let describe = |notes: &[Note]| {
+            let mut list: Vec<(u8, u8, u8, u8, f64)> = notes.iter()
+                .map(|n| (n.channel, n.midi_key, n.velocity, n.program, n.pan))
+                .collect();
+            list.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            list
+        };
+        assert_eq!(spans(&round_trip), spans(&notes));
+        assert_eq!(describe(&round_trip), describe(&notes));
+    }
+
+    #[test]
+    fn staccato_note_is_released_from_its_peak() {
+        let env = Envelope::default();
+        let duration = 0.01;
+        assert!((env.gain(duration, duration) - 0.2).abs() < 1e-9);
+        assert!(env.gain(duration + 0.001, duration) < env.gain(duration, duration));
+        assert!(env.gain(duration + env.release - 1e-6, duration) < 1e-4);
+
+        // Notes longer than the attack are unchanged
+        assert!((env.gain(0.04, 0.5) - 0.8).abs() < 1e-9);
+        assert!((env.gain(0.55, 0.5) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn declick_fades_both_ends() {
+        let rate = 1000; // five samples of fade
+        let mut whole = vec![1.0f32; 20];
+        declick(&mut whole, &mut [], 0, 20, rate);
+        assert_eq!(&whole[..6], &[0.0, 0.2, 0.4, 0.6, 0.8, 1.0]);
+        assert_eq!(whole[19], 0.0);
+
+        // In blocks, as when streaming, the result is the same
+        let mut split = vec![1.0f32; 20];
+        let (a, b) = split.split_at_mut(3);
+        declick(a, &mut [], 0, 20, rate);
+        declick(b, &mut [], 3, 20, rate);
+        assert_eq!(whole, split);
+    }
+
+    #[test]
+    fn onepole_filters_split_the_signal() {
+        // A constant offset passes the low-pass and is removed by the high-pass
+        let mut low = vec![1.0f32; SAMPLE_RATE as usize];
+        let mut high = low.clone();
+        onepole(&mut low, 100.0, SAMPLE_RATE, FilterKind::LowPass, &mut 0.0);
+        onepole(&mut high, 100.0, SAMPLE_RATE, FilterKind::HighPass, &mut 0.0);
+        assert!((low[low.len() - 1] - 1.0).abs() < 1e-6);
+        assert!(high[high.len() - 1].abs() < 1e-6);
+
+        // Filtering in two blocks with the carried state gives the same result
+        let mut whole: Vec<f32> = (0..1000).map(|i| if i % 7 < 3 { 1.0 } else { -1.0 }).collect();
+        let mut split = whole.clone();
+        onepole(&mut whole, 2000.0, SAMPLE_RATE, FilterKind::LowPass, &mut 0.0);
+        let mut y = 0.0;
+        let (a, b) = split.split_at_mut(400);
+        onepole(a, 2000.0, SAMPLE_RATE, FilterKind::LowPass, &mut y);
+        onepole(b, 2000.0, SAMPLE_RATE, FilterKind::LowPass, &mut y);
+        assert_eq!(whole, split);
+    }
+
+    #[test]
+    fn tail_leaves_room_for_release_and_reverb() {
+        let settings = SynthSettings::default();
+        assert_eq!(settings.tail(), DEFAULT_TAIL);
+        let reverb = SynthSettings { reverb: Some(2.0), ..settings };
+        assert_eq!(reverb.tail(), DEFAULT_RELEASE + 2.0);
+    }
+
+    #[test]
+    fn peak_level_sets_the_normalization_factor() {
+        let settings = SynthSettings::default();
+        // Loud songs peak just below full scale, quiet ones are not boosted
+        assert_eq!(norm_factor(2.0, &settings), 16000.0);
+        assert_eq!(norm_factor(0.5, &settings), 32000.0);
+
+        // -6 dBFS is about half of full scale, whatever the loudest sample
+        let peak = SynthSettings { peak_db: Some(-6.0), ..settings };
+        let scaled = 0.25 * norm_factor(0.25, &peak);
+        assert!((scaled - 16422.9).abs() < 0.1, "{}", scaled);
+        let full = SynthSettings { peak_db: Some(0.0), ..settings };
+        assert_eq!(2.0 * norm_factor(2.0, &full), 32768.0);
+
+        let raw = SynthSettings { normalize: false, ..peak };
+        assert_eq!(norm_factor(0.25, &raw), 32768.0);
+    }
+
+    #[test]
+    fn reverb_rings_on_after_the_input() {
+        let mut buf = vec![0.0f32; SAMPLE_RATE as usize];
+        buf[0] = 1.0;
+        let mut whole = buf.clone();
+        Reverb::new(1.0, SAMPLE_RATE, 0.0).process(&mut whole);
+        assert!(whole[SAMPLE_RATE as usize / 2..].iter().any(|&x| x.abs() > 1e-4));
+
+        // Processing in blocks with the same reverb gives the same result
+        let mut reverb = Reverb::new(1.0, SAMPLE_RATE, 0.0);
+        for block in buf.chunks_mut(1000) {
+            reverb.process(block);
+        }
+        assert_eq!(buf, whole);
+    }
+
+    #[test]
+    fn running_status_velocity_zero_keeps_restruck_chord() {
+        // 480 ticks per beat at 120 BPM, so one beat is 0.5 s
+        let track = [
+            0x00, 0x90, 60, 100, // C4 on
+            0x00, 64, 100,       // E4 on (running status)
+            0x00, 67, 100,       // G4 on
+            0x00, 0xFF, 0x06, 0x01, b'A', // marker between running-status events
+            0x83, 0x60, 60, 90,  // C4 struck again while held, delta 480
+            0x83, 0x60, 60, 0,   // first C4 off as vel 0
+            0x83, 0x60, 0x80, 60, 0, // matching 0x80 for the second strike
+            0x00, 0x90, 64, 0,   // E4 off as vel 0
+            0x00, 67, 0,         // G4 off as vel 0
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (events, division) = parse_midi_from(&mut Cursor::new(midi_file(&track))).unwrap();
+        let (notes, _) = convert_events_to_notes(&events, division);
+
+        assert_eq!(spans(&notes), vec![
+            (60, 0.0, 0.5),
+            (60, 0.5, 1.0),
+            (64, 0.0, 1.5),
+            (67, 0.0, 1.5),
+        ]);
+    }
+}
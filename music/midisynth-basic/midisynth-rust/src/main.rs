@@ -12,456 +12,526 @@
 // able, or the specifications may not be followed in detail.
 //
 // Usage:
-//   ./midisynth input.mid output.wav
+//   ./midisynth [options] input.mid [more.mid ...] output.wav
+//
+// Use - as input to read the MIDI file from standard input, e.g.
+//   cat song.mid | ./midisynth - output.wav
+//
+// Use - as output to write the WAV file to standard output (messages
+// then go to stderr), e.g.
+//   ./midisynth song.mid - | ffmpeg -i - song.mp3
+//
+// Several input files are played one after another into one WAV file,
+// e.g. for a medley. Each file starts when the previous one has ended
+// (including its --tail), plus the --gap.
+//
+// Options:
+//   --info         Only print a summary of each input file (tracks,
+//                  division, notes, duration, tempo range and channels)
+//                  and exit. No output file is needed.
+//   --dump-events  Only print every event the parser read from each
+//                  MIDI file, one per line with its tick, time in
+//                  seconds, type, channel, note and velocity (tempo in
+//                  BPM, texts and time signatures in the last column),
+//                  and exit. For diagnosing files that sound wrong.
+//   --text         The input files are not MIDI files but plain text
+//                  with one note per line: pitch, start and duration in
+//                  seconds, and velocity (1 to 127), e.g.
+//                    C4  0.0 0.5 100
+//                    F#4 0.5 0.5 90
+//                  The pitch is a note name (C4 is middle C, sharps #,
+//                  flats b) or a MIDI key number. Lines starting with #
+//                  are comments.
+//   --peak-time    Print the time (in seconds) of the loudest sample,
+//                  e.g. to seek a preview to the most intense moment.
+//   --json-errors  Report errors as a single JSON object on stderr,
+//                  e.g. {"error": "...", "offset": 1234}.
+//   --attack S     Envelope attack time in seconds (default 0.05)
+//   --decay S      Envelope decay time in seconds (default 0)
+//   --sustain L    Envelope sustain level, 0 to 1 (default 1)
+//   --release S    Envelope release time in seconds (default 0.1)
+//   --wave W       Oscillator waveform: sine (default, with harmonics),
+//                  square, sawtooth or triangle. Drums are unaffected.
+//                  With sine, the harmonic mix follows the General MIDI
+//                  instrument family chosen by Program Change.
+//   --harmonics LIST
+//                  Amplitudes of the partials of the sine wave, fundamen-
+//                  tal first, separated by commas (at most 16), e.g.
+//                  1,0.5,0.3,0.1. Replaces the instrument family mix for
+//                  all notes. The level does not depend on the amplitudes,
+//                  only their ratio matters.
+//   --stereo       Write a stereo WAV file. Each note is panned by the
+//                  Pan controller (CC 10) of its channel at the time
+//                  the note starts, or centered if the channel has no
+//                  Pan controller yet. Without this option the output
+//                  is mono.
+//   --chorus CENTS Play every note except drums as two voices, detuned
+//                  by CENTS up and down (e.g. 8), for a fuller sound.
+//                  With --stereo the two voices are spread to the left
+//                  and right of the note's pan position.
+//   --threads N    Number of threads for the synthesis (default 1).
+//                  The output is identical for any number of threads.
+//   --transpose N  Shift all notes except drums by N semitones, e.g. -3
+//                  to fit a singer's range. Notes pushed beyond the MIDI
+//                  range are left out with a warning.
+//   --quantize DIV Snap the start of every note to the nearest 1/DIV
+//                  note (e.g. 16 for sixteenths) of the file's tempo
+//                  map, to tidy up a recorded performance. The notes
+//                  keep their length.
+//   --quantize-ends
+//                  With --quantize, let the notes end on the grid too,
+//                  at least one grid step after their start.
+//   --glide MS     Portamento: a note that starts while the previous
+//                  note of its channel still sounds, or up to MS milli-
+//                  seconds after it, slides in from that note's pitch
+//                  within MS milliseconds. For monophonic lead lines.
+//   --bend-range N Pitch bend range in semitones (default 2), i.e. how
+//                  far a full pitch bend detunes the notes of a channel.
+//   --vibrato-rate HZ
+//                  Speed of the vibrato added by the modulation wheel
+//                  (CC 1) in Hz (default 5). Full modulation swings the
+//                  pitch by half a semitone.
+//   --stream       Render and write in blocks of 10 seconds instead of
+//                  holding the whole song in memory. Takes about twice
+//                  as long; the output is identical.
+//   --tail S       Seconds of ring-out after the last MIDI event
+//                  (default 1, or the release plus the reverb time if
+//                  that is longer).
+//   --max-duration S
+//                  Cut the output off after S seconds (default 3600),
+//                  with a warning. Protects against corrupt files with
+//                  events far in the future, which would otherwise need
+//                  a huge amount of memory.
+//   --gap S        Seconds of silence between several input files
+//                  (default 0).
+//   --reverb S     Add a simple reverb to the mix that decays within S
+//                  seconds. The default tail leaves room for it.
+//   --rate HZ      Sample rate of the WAV file (default 44100), e.g.
+//                  48000 for video or 22050 for quick previews.
+//   --oversample N Render the notes at N times the sample rate (1, 2 or
+//                  4; default 1) and filter down to the rate. Reduces
+//                  the aliasing of high square and sawtooth notes, at
+//                  N times the rendering time.
+//   --peak DB      Normalize the loudest sample to DB dBFS (0 or below),
+//                  e.g. -6.
+//                  Without it, loud songs peak at about -0.2 dBFS and
+//                  quiet ones are not amplified.
+//   --no-normalize Write the mix as it is, without adjusting the gain,
+//                  so that the level is comparable between files.
+//                  Samples beyond full scale are clipped, unless --limit
+//                  is given.
+//   --limit        Soft-clip peaks above 80% of full scale instead of
+//                  clipping them hard, which avoids harsh distortion in
+//                  loud passages. Quieter samples are not changed.
+//   --max-voices N Let at most N notes sound at once per channel. A new
+//                  note beyond that cuts off the oldest one, like voice
+//                  stealing in a hardware synth. This changes the out-
+//                  put, but keeps very dense files fast to render and
+//                  less prone to clipping. Unlimited by default.
+//   --only-channels LIST
+//                  Render only the notes of these MIDI channels, given
+//                  as numbers from 0 to 15 separated by commas, e.g.
+//                  0,1,9 (9 is the drum channel). Useful to bounce the
+//                  stems of single instruments with several runs.
+//   --mute-channels LIST
+//                  Leave out the notes of these channels.
+//   --metronome    Mix a click on every beat into the output, with an
+//                  accented click on beat 1 of each measure. Follows
+//                  the tempo and time signature of the MIDI file; --text
+//                  input clicks at 120 BPM in 4/4.
+//   --drum-map FILE
+//                  Replace the drum sounds of some keys, for files whose
+//                  drum channel does not follow General MIDI. One key
+//                  per line: key (number or note name), frequency in Hz,
+//                  duration in seconds and waveform (sine, square,
+//                  sawtooth, triangle or noise), e.g.
+//                    60 55 0.2 sine
+//                    C#4 9000 0.05 noise
+//                  Lines starting with # are comments.
+//   --bits B       Sample format of the WAV file: 16 (default), 24 or
+//                  32f. 32f writes 32 bit float samples as rendered,
+//                  without normalization or clipping, e.g. for a DAW.
+//   --lowpass HZ   Run a one-pole low-pass filter with this cutoff over
+//                  the mix, e.g. to soften square and sawtooth waves.
+//   --highpass HZ  Run a one-pole high-pass filter with this cutoff over
+//                  the mix, e.g. 20 to remove DC offset and rumble.
+//
+// Exit codes:
+//   0  success
+//   2  invalid command line
+//   3  input file could not be opened or downloaded
+//   4  input is not a valid/supported MIDI file
+//   5  output file could not be written
+//
+// With the feature "url" enabled (cargo build --features url), the
+// input may also be an http:// or https:// URL. The file is then down-
+// loaded into memory via `curl`, which must be installed.
+//
+// With the feature "gzip-command" enabled (cargo build --features
+// gzip-command), gzipped MIDI files (e.g. song.mid.gz) are read as well,
+// from a file, standard input or a URL. They are recognized by their
+// first bytes, whatever their name, and decompressed by running
+// `gzip -dc`, which must be installed and in PATH. Without it, reading
+// a gzipped file fails at run time with exit code 3.
 //
 // =====================================================================
 
+use midisynth::{
+    build_tempo_map, convert_events_to_notes_with_tail, limit_voices, metronome_notes, parse_midi,
+    peak_polyphony, quantize_notes, read_drum_map, read_note_text, set_glide, status,
+    status_to_stderr, synthesize_and_write, tick_to_seconds, transpose_notes,
+    AppError, ErrorClass, EventType, Harmonics, MidiEvent, Note, SampleFormat, SynthSettings, Wave,
+};
 use std::env;
-use std::f64::consts::PI;
-use std::fs::File;
-use std::io::{self, Read, Seek, SeekFrom, Write};
 
 // =====================================================================
-// CONSTANTS AND TYPES
+// MAIN
 // =====================================================================
 
-const SAMPLE_RATE: u32 = 44100;
-
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum EventType {
-    NoteOn,
-    NoteOff,
-    SetTempo,
-}
-
-#[derive(Debug, Clone)]
-struct MidiEvent {
-    abs_tick: u32,
-    event_type: EventType,
-    channel: u8,
-    note: u8,
-    velocity: u8,
-    tempo_micros: u32,
+struct Options {
+    inputs: Vec<String>,
+    output: String, // empty with --info and --dump-events
+    info: bool,
+    dump_events: bool,
+    text: bool,
+    metronome: bool,
+    peak_time: bool,
+    json_errors: bool,
+    tail: f64,
+    gap: f64,
+    transpose: i32,
+    max_voices: Option<usize>,
+    quantize: Option<u32>, // grid as a fraction of a whole note
+    quantize_ends: bool,
+    channels: [bool; 16], // channels to render
+    settings: SynthSettings,
 }
 
-#[derive(Debug, Clone)]
-struct Note {
-    start_time: f64,
-    duration: f64,
-    midi_key: u8,
-    velocity: u8,
-    channel: u8,
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, AppError> {
+    let value = value.ok_or_else(|| AppError::new(ErrorClass::Usage,
+        format!("Missing value for {}", flag)))?;
+    value.parse().map_err(|_| AppError::new(ErrorClass::Usage,
+        format!("Invalid value for {}: {}", flag, value)))
 }
 
-// =====================================================================
-// HELPER: BINARY READING (Big Endian for MIDI)
-// =====================================================================
-
-fn read_u16_be(f: &mut File) -> io::Result<u16> {
-    let mut buf = [0u8; 2];
-    f.read_exact(&mut buf)?;
-    Ok(u16::from_be_bytes(buf))
+// Comma-separated list of MIDI channels (0 to 15)
+fn parse_channels(flag: &str, value: Option<&String>) -> Result<Vec<usize>, AppError> {
+    let list: String = parse_value(flag, value)?;
+    list.split(',').map(|c| {
+        c.trim().parse::<usize>().ok().filter(|&c| c < 16).ok_or_else(|| AppError::new(
+            ErrorClass::Usage, format!("Invalid channel for {}: {}", flag, c)))
+    }).collect()
 }
 
-fn read_u32_be(f: &mut File) -> io::Result<u32> {
-    let mut buf = [0u8; 4];
-    f.read_exact(&mut buf)?;
-    Ok(u32::from_be_bytes(buf))
+// Comma-separated partial amplitudes for --harmonics
+fn parse_harmonics(flag: &str, value: Option<&String>) -> Result<Harmonics, AppError> {
+    let list: String = parse_value(flag, value)?;
+    let amplitudes: Option<Vec<f64>> = list.split(',').map(|a| a.trim().parse().ok()).collect();
+    amplitudes.as_deref().and_then(Harmonics::new).ok_or_else(|| AppError::new(
+        ErrorClass::Usage, format!("Invalid value for {}: {}", flag, list)))
 }
 
-fn read_varlen(f: &mut File) -> io::Result<u32> {
-    let mut value: u32 = 0;
-    let mut buf = [0u8; 1];
-    loop {
-        f.read_exact(&mut buf)?;
-        let c = buf[0];
-        value = (value << 7) | (c & 0x7F) as u32;
-        if (c & 0x80) == 0 {
-            break;
-        }
+// Value that must be positive, e.g. a filter cutoff in Hz
+fn parse_positive(flag: &str, value: Option<&String>) -> Result<f64, AppError> {
+    let number: f64 = parse_value(flag, value)?;
+    if number > 0.0 {
+        Ok(number)
+    } else {
+        Err(AppError::new(ErrorClass::Usage,
+            format!("Invalid value for {}: {}", flag, number)))
     }
-    Ok(value)
 }
 
-// =====================================================================
-// MIDI PARSING LOGIC
-// =====================================================================
-
-fn parse_midi(filename: &str) -> io::Result<(Vec<MidiEvent>, u16)> {
-    let mut f = File::open(filename).map_err(|_| {
-        io::Error::new(io::ErrorKind::NotFound, "Could not open file")
-    })?;
-
-    // Header Chunk
-    let mut chunk_id = [0u8; 4];
-    f.read_exact(&mut chunk_id)?;
-    if &chunk_id != b"MThd" {
-        panic!("Invalid MIDI file (Missing MThd header).");
-    }
-
-    let _header_len = read_u32_be(&mut f)?;
-    let _format = read_u16_be(&mut f)?;
-    let num_tracks = read_u16_be(&mut f)?;
-    let division = read_u16_be(&mut f)?;
-
-    if (division & 0x8000) != 0 {
-        panic!("Error: SMPTE timecode not supported.");
-    }
-
-    println!("MIDI Info: {} tracks, division {}", num_tracks, division);
-
-    let mut events = Vec::new();
-
-    // Read tracks
-    for _ in 0..num_tracks {
-        f.read_exact(&mut chunk_id)?;
-        while &chunk_id != b"MTrk" {
-            // Skip unknown chunks
-            let skip = read_u32_be(&mut f)?;
-            f.seek(SeekFrom::Current(skip as i64))?;
-            f.read_exact(&mut chunk_id)?;
-        }
-
-        let track_len = read_u32_be(&mut f)?;
-        let start_pos = f.stream_position()?;
-        let end_pos = start_pos + track_len as u64;
-
-        let mut abs_tick = 0;
-        let mut running_status = 0u8;
-
-        while f.stream_position()? < end_pos {
-            let delta = read_varlen(&mut f)?;
-            abs_tick += delta;
-
-            let mut buf = [0u8; 1];
-            f.read_exact(&mut buf)?;
-            let byte = buf[0];
-            let status;
-
-            if byte >= 0x80 {
-                status = byte;
-                running_status = status;
-            } else {
-                status = running_status;
-                // Rewind 1 byte, as the read byte was data (note, etc.)
-                f.seek(SeekFrom::Current(-1))?;
-            }
-
-            if status == 0xFF {
-                // Meta Event
-                let mut type_buf = [0u8; 1];
-                f.read_exact(&mut type_buf)?;
-                let meta_type = type_buf[0];
-                let len = read_varlen(&mut f)?;
-
-                if meta_type == 0x51 && len == 3 {
-                    // Set Tempo
-                    let mut tbytes = [0u8; 3];
-                    f.read_exact(&mut tbytes)?;
-                    let micros = ((tbytes[0] as u32) << 16)
-                        | ((tbytes[1] as u32) << 8)
-                        | (tbytes[2] as u32);
-                    events.push(MidiEvent {
-                        abs_tick,
-                        event_type: EventType::SetTempo,
-                        channel: 0,
-                        note: 0,
-                        velocity: 0,
-                        tempo_micros: micros,
-                    });
-                } else if meta_type == 0x2F {
-                    // End of Track
-                    f.seek(SeekFrom::Start(end_pos))?;
-                    break;
-                } else {
-                    f.seek(SeekFrom::Current(len as i64))?;
-                }
-            } else if status == 0xF0 || status == 0xF7 {
-                // SysEx
-                let len = read_varlen(&mut f)?;
-                f.seek(SeekFrom::Current(len as i64))?;
-            } else {
-                let cmd = status & 0xF0;
-
-                if cmd == 0x90 { // Note On
-                    let mut data = [0u8; 2];
-                    f.read_exact(&mut data)?;
-                    let note = data[0];
-                    let vel = data[1];
-                    events.push(MidiEvent {
-                        abs_tick,
-                        event_type: if vel > 0 { EventType::NoteOn } else { EventType::NoteOff },
-                        channel: status & 0x0F,
-                        note,
-                        velocity: vel,
-                        tempo_micros: 0,
-                    });
-                } else if cmd == 0x80 { // Note Off
-                    let mut data = [0u8; 2];
-                    f.read_exact(&mut data)?;
-                    let note = data[0];
-                    let vel = data[1];
-                    events.push(MidiEvent {
-                        abs_tick,
-                        event_type: EventType::NoteOff,
-                        channel: status & 0x0F,
-                        note,
-                        velocity: vel,
-                        tempo_micros: 0,
-                    });
-                } else if cmd == 0xC0 || cmd == 0xD0 {
-                    f.seek(SeekFrom::Current(1))?;
-                } else {
-                    f.seek(SeekFrom::Current(2))?;
+fn parse_args(args: &[String]) -> Result<Options, AppError> {
+    let mut positional = Vec::new();
+    let mut info = false;
+    let mut dump_events = false;
+    let mut text = false;
+    let mut metronome = false;
+    let mut peak_time = false;
+    let mut json_errors = false;
+    let mut tail = None;
+    let mut gap = 0.0;
+    let mut transpose = 0;
+    let mut max_voices = None;
+    let mut quantize = None;
+    let mut quantize_ends = false;
+    let mut channels = [true; 16];
+    let mut settings = SynthSettings::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--info" => info = true,
+            "--dump-events" => dump_events = true,
+            "--text" => text = true,
+            "--metronome" => metronome = true,
+            "--peak-time" => peak_time = true,
+            "--json-errors" => json_errors = true,
+            "--stereo" => settings.stereo = true,
+            "--stream" => settings.stream = true,
+            "--no-normalize" => settings.normalize = false,
+            "--limit" => settings.limit = true,
+            "--peak" => {
+                let db: f64 = parse_value(arg, iter.next())?;
+                if !db.is_finite() || db > 0.0 {
+                    return Err(AppError::new(ErrorClass::Usage,
+                        format!("Invalid value for --peak: {} (must be 0 or below)", db)));
                 }
+                settings.peak_db = Some(db);
             }
-        }
-    }
-
-    // Sort (stable sort is often safer for MIDI)
-    events.sort_by_key(|e| e.abs_tick);
-
-    Ok((events, division))
-}
-
-// =====================================================================
-// CONVERSION TO NOTES
-// =====================================================================
-
-fn convert_events_to_notes(
-    events: &[MidiEvent],
-    division: u16,
-) -> (Vec<Note>, f64) {
-    let mut notes = Vec::new();
-    let mut current_time = 0.0;
-    let mut current_tick = 0;
-    let mut micros_per_beat = 500000.0; // Default 120 BPM
-
-    // active_notes[channel][pitch] = start_time
-    // We use f64::NEG_INFINITY as "not active" marker
-    let mut active_notes = [[f64::NEG_INFINITY; 128]; 16];
-    let mut active_velocities = [[0u8; 128]; 16];
-
-    for e in events {
-        let delta_ticks = e.abs_tick - current_tick;
-        if delta_ticks > 0 {
-            let seconds_per_tick = (micros_per_beat / 1_000_000.0) / (division as f64);
-            current_time += (delta_ticks as f64) * seconds_per_tick;
-            current_tick = e.abs_tick;
-        }
-
-        match e.event_type {
-            EventType::SetTempo => {
-                micros_per_beat = e.tempo_micros as f64;
+            "--threads" => settings.threads = parse_value(arg, iter.next())?,
+            "--bend-range" => settings.bend_range = parse_value(arg, iter.next())?,
+            "--vibrato-rate" => settings.vibrato_rate = parse_value(arg, iter.next())?,
+            "--rate" => settings.sample_rate = parse_value(arg, iter.next())?,
+            "--oversample" => settings.oversample = parse_value(arg, iter.next())?,
+            "--attack" => settings.envelope.attack = parse_value(arg, iter.next())?,
+            "--decay" => settings.envelope.decay = parse_value(arg, iter.next())?,
+            "--sustain" => settings.envelope.sustain = parse_value(arg, iter.next())?,
+            "--release" => settings.envelope.release = parse_value(arg, iter.next())?,
+            "--lowpass" => settings.lowpass = Some(parse_positive(arg, iter.next())?),
+            "--highpass" => settings.highpass = Some(parse_positive(arg, iter.next())?),
+            "--tail" => tail = Some(parse_value::<f64>(arg, iter.next())?.max(0.0)),
+            "--max-duration" => settings.max_duration = parse_positive(arg, iter.next())?,
+            "--gap" => gap = parse_value::<f64>(arg, iter.next())?.max(0.0),
+            "--transpose" => transpose = parse_value(arg, iter.next())?,
+            "--glide" => settings.glide = Some(parse_positive(arg, iter.next())? / 1000.0),
+            "--only-channels" => {
+                let only = parse_channels(arg, iter.next())?;
+                channels = std::array::from_fn(|c| only.contains(&c));
             }
-            EventType::NoteOn => {
-                let ch = e.channel as usize;
-                let n = e.note as usize;
-
-                // Retrigger check
-                if active_notes[ch][n] != f64::NEG_INFINITY {
-                    let duration = current_time - active_notes[ch][n];
-                    if duration > 0.0 {
-                        notes.push(Note {
-                            start_time: active_notes[ch][n],
-                            duration,
-                            midi_key: e.note,
-                            velocity: active_velocities[ch][n],
-                            channel: e.channel,
-                        });
-                    }
+            "--mute-channels" => {
+                for c in parse_channels(arg, iter.next())? {
+                    channels[c] = false;
                 }
-                active_notes[ch][n] = current_time;
-                active_velocities[ch][n] = e.velocity;
             }
-            EventType::NoteOff => {
-                let ch = e.channel as usize;
-                let n = e.note as usize;
-
-                if active_notes[ch][n] != f64::NEG_INFINITY {
-                    let duration = current_time - active_notes[ch][n];
-                    if duration > 0.0 {
-                        notes.push(Note {
-                            start_time: active_notes[ch][n],
-                            duration,
-                            midi_key: e.note,
-                            velocity: active_velocities[ch][n],
-                            channel: e.channel,
-                        });
-                    }
-                    active_notes[ch][n] = f64::NEG_INFINITY;
+            "--quantize" => {
+                let grid: u32 = parse_value(arg, iter.next())?;
+                if grid == 0 {
+                    return Err(AppError::new(ErrorClass::Usage, "Invalid value for --quantize: 0"));
                 }
+                quantize = Some(grid);
+            }
+            "--quantize-ends" => quantize_ends = true,
+            "--max-voices" => max_voices = Some(parse_value::<usize>(arg, iter.next())?.max(1)),
+            "--harmonics" => settings.harmonics = Some(parse_harmonics(arg, iter.next())?),
+            "--chorus" => settings.chorus = Some(parse_value::<f64>(arg, iter.next())?.abs()),
+            "--drum-map" => {
+                settings.drum_map = read_drum_map(&parse_value::<String>(arg, iter.next())?)?;
             }
+            "--reverb" => settings.reverb = Some(parse_value::<f64>(arg, iter.next())?.max(0.0)),
+            "--bits" => {
+                let name: String = parse_value(arg, iter.next())?;
+                settings.format = SampleFormat::from_name(&name).ok_or_else(|| AppError::new(
+                    ErrorClass::Usage, format!("Unsupported sample format: {}", name)))?;
+            }
+            "--wave" => {
+                let name: String = parse_value(arg, iter.next())?;
+                settings.wave = Wave::from_name(&name).ok_or_else(|| AppError::new(
+                    ErrorClass::Usage, format!("Unknown waveform: {}", name)))?;
+            }
+            flag if flag.starts_with("--") => {
+                return Err(AppError::new(ErrorClass::Usage,
+                    format!("Unknown option: {}", flag)));
+            }
+            _ => positional.push(arg.clone()),
         }
     }
 
-    let total_duration = current_time + 1.0; // +1 second reverb tail
-    (notes, total_duration)
-}
-
-// =====================================================================
-// SYNTHESIS AND WAV WRITING
-// =====================================================================
-
-fn write_wav_header(f: &mut File, total_samples: u32) -> io::Result<()> {
-    let byte_rate = SAMPLE_RATE * 2; // 16 bit mono
-    let data_chunk_size = total_samples * 2;
-    let file_size = 36 + data_chunk_size;
-
-    // RIFF Header
-    f.write_all(b"RIFF")?;
-    f.write_all(&file_size.to_le_bytes())?;
-    f.write_all(b"WAVE")?;
-    f.write_all(b"fmt ")?;
-
-    let subchunk1_size = 16u32;
-    let audio_format = 1u16; // PCM
-    let num_channels = 1u16; // Mono
-    let sample_rate = SAMPLE_RATE;
-    let block_align = 2u16;
-    let bits_per_sample = 16u16;
+    if dump_events && text {
+        return Err(AppError::new(ErrorClass::Usage, "--dump-events needs MIDI input, not --text"));
+    }
+    let no_output = info || dump_events;
+    if no_output && positional.is_empty() {
+        return Err(AppError::new(ErrorClass::Usage, "Expected an input file"));
+    }
+    if !no_output && positional.len() < 2 {
+        return Err(AppError::new(ErrorClass::Usage,
+            "Expected an input and an output file"));
+    }
+    let output = if no_output { String::new() } else { positional.pop().unwrap() };
+    let inputs = positional;
+
+    settings.envelope = settings.envelope.clamped();
+    let tail = tail.unwrap_or_else(|| settings.tail());
+    settings.threads = settings.threads.max(1);
+    if !(1000..=384_000).contains(&settings.sample_rate) {
+        return Err(AppError::new(ErrorClass::Usage,
+            format!("Invalid value for --rate: {}", settings.sample_rate)));
+    }
+    if ![1, 2, 4].contains(&settings.oversample) {
+        return Err(AppError::new(ErrorClass::Usage,
+            format!("Invalid value for --oversample: {}", settings.oversample)));
+    }
 
-    // fmt chunk
-    f.write_all(&subchunk1_size.to_le_bytes())?;
-    f.write_all(&audio_format.to_le_bytes())?;
-    f.write_all(&num_channels.to_le_bytes())?;
-    f.write_all(&sample_rate.to_le_bytes())?;
-    f.write_all(&byte_rate.to_le_bytes())?;
-    f.write_all(&block_align.to_le_bytes())?;
-    f.write_all(&bits_per_sample.to_le_bytes())?;
+    Ok(Options {
+        inputs, output, info, dump_events, text, metronome, peak_time, json_errors, tail, gap,
+        transpose, max_voices, quantize, quantize_ends, channels, settings,
+    })
+}
 
-    // data chunk
-    f.write_all(b"data")?;
-    f.write_all(&data_chunk_size.to_le_bytes())?;
+// Summary for --info, without the tail
+fn print_note_info(notes: &[Note], duration: f64) {
+    println!("Notes: {}, peak polyphony: {}", notes.len(), peak_polyphony(notes));
+    println!("Duration: {:.3} s", duration);
 
-    Ok(())
+    let mut channels: Vec<u8> = notes.iter().map(|n| n.channel).collect();
+    channels.sort_unstable();
+    channels.dedup();
+    let channels: Vec<String> = channels.iter().map(|c| c.to_string()).collect();
+    println!("Channels: {}", channels.join(", "));
 }
 
-fn midi_to_freq(key: u8) -> f64 {
-    440.0 * 2.0_f64.powf((key as f64 - 69.0) / 12.0)
+// Summary for --info of a MIDI file. Track count and division are printed
+// by the parser.
+fn print_info(events: &[MidiEvent], division: u16) {
+    let (notes, duration) = convert_events_to_notes_with_tail(events, division, 0.0);
+    print_note_info(&notes, duration);
+
+    // With SMPTE division (bit 15), tempo events do not affect the timing
+    if division & 0x8000 == 0 {
+        let bpm: Vec<f64> = build_tempo_map(events, division).iter()
+            .map(|&(_, _, micros_per_beat)| 60_000_000.0 / micros_per_beat)
+            .collect();
+        let min = bpm.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = bpm.iter().cloned().fold(0.0, f64::max);
+        println!("Tempo: {:.1} to {:.1} BPM ({} changes)", min, max, bpm.len() - 1);
+    }
 }
 
-fn synthesize_and_write(
-    filename: &str,
-    notes: &[Note],
-    total_duration: f64,
-) -> io::Result<()> {
-    let total_samples = (total_duration * SAMPLE_RATE as f64) as usize;
-
-    println!("Synthesizing {} notes in {} samples...", notes.len(), total_samples);
-
-    // Buffer initialized with 0.0
-    let mut buffer: Vec<f32> = vec![0.0; total_samples];
-
-    let overtones = [1.0, 0.5, 0.3, 0.1];
-    let attack = 0.05;
-    let release = 0.1;
-
-    for n in notes {
-        let is_drum = n.channel == 9; // Channel 10 in MIDI is index 9
-        let freq = if is_drum { 100.0 } else { midi_to_freq(n.midi_key) };
-        let duration = if is_drum { 0.05 } else { n.duration };
-        let amp = (n.velocity as f64 / 127.0) * 0.3;
-
-        let start_s = (n.start_time * SAMPLE_RATE as f64) as usize;
-        let len_s = ((duration + release) * SAMPLE_RATE as f64) as usize;
-
-        let end_loop = (start_s + len_s).min(total_samples);
-
-        // To minimize slice checking in the loop
-        if start_s >= total_samples { continue; }
-
-        for t in 0..(end_loop - start_s) {
-            let time_in_note = t as f64 / SAMPLE_RATE as f64;
-            let mut sample_val = 0.0;
-
-            if is_drum {
-                sample_val = (2.0 * PI * freq * time_in_note).sin();
-            } else {
-                for (ov_idx, &ov_amp) in overtones.iter().enumerate() {
-                    let h_freq = freq * (ov_idx as f64 + 1.0);
-                    if h_freq < (SAMPLE_RATE as f64 / 2.0) {
-                        sample_val += ov_amp * (2.0 * PI * h_freq * time_in_note).sin();
-                    }
-                }
-                sample_val /= 1.9; // Normalize overtones
-            }
-
-            // Envelope
-            let mut env = 1.0;
-            if time_in_note < attack {
-                env = time_in_note / attack;
-            } else if time_in_note > duration {
-                let rel_phase = time_in_note - duration;
-                env = 1.0 - (rel_phase / release);
-                if env < 0.0 { env = 0.0; }
-            }
-
-            buffer[start_s + t] += (sample_val * amp * env) as f32;
-        }
+// Event list for --dump-events, in the order the notes are built from it
+fn print_events(events: &[MidiEvent], division: u16) {
+    let tempo_map = build_tempo_map(events, division);
+    println!("{:>8} {:>10}  {:<14} {:>2} {:>4} {:>4}", "tick", "seconds", "type", "ch", "note", "vel");
+    for e in events {
+        let seconds = tick_to_seconds(e.abs_tick, &tempo_map, division);
+        let kind = format!("{:?}", e.event_type);
+        let columns = match e.event_type {
+            EventType::SetTempo => format!("{:>2} {:>4} {:>4}  {:.2} BPM", "-", "-", "-",
+                60_000_000.0 / e.tempo_micros as f64),
+            EventType::Text => format!("{:>2} {:>4} {:>4}  0x{:02X} {:?}", "-", "-", "-",
+                e.note, e.text),
+            EventType::TimeSignature => format!("{:>2} {:>4} {:>4}  {}/{}", "-", "-", "-",
+                e.note, 1u32 << e.velocity.min(31)),
+            EventType::PitchBend => format!("{:>2} {:>4} {:>4}  {}", e.channel, e.note, e.velocity,
+                e.bend_value() as i32 - 8192),
+            _ => format!("{:>2} {:>4} {:>4}", e.channel, e.note, e.velocity),
+        };
+        println!("{:>8} {:>10.4}  {:<14} {}", e.abs_tick, seconds, kind, columns);
     }
+}
 
-    // Normalization and writing
-    let mut f = File::create(filename)?;
-    write_wav_header(&mut f, total_samples as u32)?;
-
-    // Peak Finding
-    let mut max_val = 0.0f32;
-    for &sample in &buffer {
-        let abs_val = sample.abs();
-        if abs_val > max_val {
-            max_val = abs_val;
+// Notes and duration (with tail) of an input file, MIDI or with --text
+// the note text format
+fn load_input(input: &str, opts: &Options) -> Result<(Vec<Note>, f64), AppError> {
+    if opts.text {
+        let (mut notes, duration) = read_note_text(input, opts.tail)?;
+        if opts.info {
+            print_note_info(&notes, duration - opts.tail);
         }
+        notes.retain(|n| opts.channels[n.channel as usize & 15]);
+        if let Some(grid) = opts.quantize {
+            quantize_notes(&mut notes, &build_tempo_map(&[], 480), 480, grid, opts.quantize_ends);
+        }
+        if opts.metronome {
+            notes.extend(metronome_notes(&[], 480, duration - opts.tail));
+        }
+        return Ok((notes, duration));
     }
-
-    let mut norm_factor = 32000.0;
-    if max_val > 0.0 {
-        norm_factor = 32000.0 / max_val;
+    let (events, division) = parse_midi(input)?;
+    if opts.dump_events {
+        print_events(&events, division);
     }
-    if norm_factor > 32000.0 {
-        norm_factor = 32000.0;
+    if opts.info {
+        print_info(&events, division);
     }
-
-    // Buffer for block-wise writing (efficiency)
-    let mut out_buffer = Vec::with_capacity(total_samples);
-
-    for sample in buffer {
-        let val = (sample * norm_factor) as i32;
-        let clamped = val.clamp(-32768, 32767) as i16;
-        out_buffer.extend_from_slice(&clamped.to_le_bytes());
+    let (mut notes, duration) = convert_events_to_notes_with_tail(&events, division, opts.tail);
+    notes.retain(|n| opts.channels[n.channel as usize & 15]);
+    if let Some(grid) = opts.quantize {
+        let map = build_tempo_map(&events, division);
+        quantize_notes(&mut notes, &map, division, grid, opts.quantize_ends);
     }
-
-    f.write_all(&out_buffer)?;
-
-    println!("WAV written to: {}", filename);
-    Ok(())
+    if opts.metronome {
+        notes.extend(metronome_notes(&events, division, duration - opts.tail));
+    }
+    Ok((notes, duration))
 }
 
-// =====================================================================
-// MAIN
-// =====================================================================
-
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        println!("Usage: {} <input.mid> <output.wav>", args[0]);
+    let opts = match parse_args(&args[1..]) {
+        Ok(opts) => opts,
+        Err(e) => {
+            if args.len() <= 1 {
+                println!("Usage: {} [options] <input.mid>... <output.wav>", args[0]);
+                return;
+            }
+            e.report(args.iter().any(|a| a == "--json-errors"));
+            std::process::exit(e.exit_code());
+        }
+    };
+
+    if opts.output == "-" {
+        status_to_stderr();
+    }
+
+    // Each file is converted with its own tempo map and then moved to
+    // start where the previous one ended
+    let mut notes = Vec::new();
+    let mut total_duration = 0.0;
+    for (i, input) in opts.inputs.iter().enumerate() {
+        let (file_notes, duration) = match load_input(input, &opts) {
+            Ok(res) => res,
+            Err(e) => {
+                e.report(opts.json_errors);
+                std::process::exit(e.exit_code());
+            }
+        };
+
+        let offset = if i > 0 { total_duration + opts.gap } else { 0.0 };
+        notes.extend(file_notes.into_iter().map(|mut n| {
+            n.start_time += offset;
+            n
+        }));
+        total_duration = offset + duration;
+    }
+    if opts.info || opts.dump_events {
         return;
     }
 
-    let (events, division) = match parse_midi(&args[1]) {
-        Ok(res) => res,
-        Err(e) => {
-            eprintln!("Error parsing MIDI file: {}", e);
-            std::process::exit(1);
+    if opts.transpose != 0 {
+        let dropped = transpose_notes(&mut notes, opts.transpose);
+        if dropped > 0 {
+            eprintln!("Warning: {} notes out of range after --transpose, left out", dropped);
         }
-    };
+    }
 
-    let (notes, total_duration) = convert_events_to_notes(&events, division);
+    status(format_args!("Notes: {}, peak polyphony: {}", notes.len(), peak_polyphony(&notes)));
+    if let Some(max_voices) = opts.max_voices {
+        let stolen = limit_voices(&mut notes, max_voices);
+        if stolen > 0 {
+            status(format_args!("Voice limit: {} notes cut off", stolen));
+        }
+    }
 
     if notes.is_empty() {
-        println!("No notes found!");
-    } else if let Err(e) = synthesize_and_write(&args[2], &notes, total_duration) {
-        eprintln!("Error writing WAV file: {}", e);
-        std::process::exit(1);
+        status(format_args!("No notes found!"));
+        return;
+    }
+    if let Some(glide) = opts.settings.glide {
+        set_glide(&mut notes, glide);
+    }
+    match synthesize_and_write(&opts.output, &notes, total_duration, &opts.settings) {
+        Ok(peak_time) => {
+            if opts.peak_time {
+                status(format_args!("Peak time: {:.3} s", peak_time));
+            }
+        }
+        Err(e) => {
+            let e = AppError::new(ErrorClass::Output,
+                format!("Could not write WAV file: {}", e));
+            e.report(opts.json_errors);
+            std::process::exit(e.exit_code());
+        }
     }
 }
@@ -5,6 +5,8 @@
 // ABHÄNGIGKEITEN
 //   Hängt von SDL2 ab. Installieren unter Ubuntu/Debian:
 //   sudo apt install libsdl2-dev libsdl2-image-dev timidity
+//   Für --midi-out mit "cargo build --features midi-out" bauen; unter
+//   Linux braucht die Crate midir dann zusätzlich libasound2-dev.
 
 const HELP: &str = r#"
 Mivi -- Version 2026-02-12
@@ -15,10 +17,29 @@ VERWENDUNG
 STEUERUNG (Tastatur)
   SPACE / K      : Pausieren
   J/L            : Spulen (um 10 Sekunden)
-  Links / Rechts : Spulen (um 4 Sekunden)
+  Links / Rechts : Spulen (um 4 Sekunden, mit Shift um 0,25 Sekunden)
   Komma / Punkt  : Spulen (um eine Sekunde)
+  < / >          : Tempo verringern / erhöhen (um 5 Prozent)
   F              : Vollbildmodus
   S              : Ansicht wechseln (Piano zu Staff zu Split)
+  D              : Debug-Ausgabe der klingenden Noten ein/aus
+  M              : Visuelles Metronom (Aufblinken auf der Eins) ein/aus
+  R              : Wellen über angeschlagenen Tasten ein/aus
+  [ / ]          : Schleifenanfang A / -ende B auf die aktuelle Zeit setzen
+                   (auch mit A / B); zwischen A und B wird wiederholt
+  Rücktaste      : Schleife A-B aufheben
+  P              : Fortschrittsbalken, Zeit- und Tempoanzeige ein/aus
+  W              : Wellenform der zuletzt gespielten Sekunde am unteren
+                   Rand ein/aus
+  + / -          : Zeitachse strecken / stauchen (auch = und Ziffernblock)
+  1 - 9, 0       : Kanal 1 - 10 stumm schalten / wieder einschalten
+  Shift + Ziffer : Kanal solo (erneut: alle Kanäle wieder an). Mit -tm
+                   betrifft das nur die Anzeige.
+  TAB            : Spielmodus ein/aus. Die Tastatur wird zum Klavier:
+                   untere Buchstabenreihe (Y/Z X C V ...) weiße und die
+                   Reihe darüber (S D G H J) schwarze Tasten ab C4, die
+                   Q-Reihe und Ziffernreihe eine Oktave höher. Belegte
+                   Tasten steuern dann nicht mehr.
   ESC            : Beenden
 
 OPTIONEN
@@ -28,6 +49,77 @@ OPTIONEN
       installiert und im System-Pfad verfügbar ist. Liefert je nach
       installiertem Soundfont deutlich besseren Klang.
 
+  --wave=<sine|square|saw|triangle>
+      Wellenform des internen Synthesizers. Vorgabe ist "sine" (Sinus
+      mit Obertönen); "square", "saw" und "triangle" ergeben einen
+      Chiptune-artigen Klang. Schlagzeug bleibt unverändert.
+
+  --threads=<n>
+      Anzahl der Threads für den internen Synthesizer (Vorgabe 1).
+      Das Ergebnis ist für jede Anzahl identisch.
+
+  --stream
+      Der interne Synthesizer berechnet das Audio erst beim Abspielen
+      Block für Block, statt das ganze Stück vorab. Die Wiedergabe
+      beginnt dadurch sofort und braucht auch bei langen Stücken kaum
+      Speicher. Da die Lautstärke nicht vorab auf das Stück abgestimmt
+      werden kann, werden laute Stellen weich begrenzt.
+
+  --bend-range=<n>
+      Pitch-Bend-Bereich des internen Synthesizers in Halbtönen
+      (Vorgabe 2).
+
+  --rate=<Hz>
+      Abtastrate der Audioausgabe (Vorgabe 44100), z.B. 48000 oder
+      22050. Timidity erzeugt das Audio direkt in dieser Rate, sofern
+      sie zwischen 4000 und 65000 liegt.
+
+  --resample=<linear|cubic>
+      Interpolation beim Umrechnen der Abtastrate des von Timidity
+      erzeugten Audios (nur mit -tm und einer Rate außerhalb dessen,
+      was Timidity unterstützt). "linear" ist schnell und die
+      Vorgabe, "cubic" verringert hörbare Artefakte.
+
+  --fullscreen
+      Startet im Vollbildmodus (umschalten mit F).
+
+  --midi-out[=<Port>]
+      Schickt die Noten zusätzlich zum eigenen Klang synchron zur
+      Wiedergabe als Note On/Off an einen MIDI-Ausgang, bspw. einen
+      Hardware- oder Software-Synthesizer. Ohne Namen wird der erste
+      Ausgang verwendet, sonst der erste, dessen Name <Port> enthält.
+      Die Stummschaltung per Ziffern betrifft nur den eigenen Klang.
+      Erfordert den Build mit "--features midi-out".
+
+  --resume
+      Setzt die Wiedergabe dort fort, wo sie zuletzt beendet wurde, mit
+      Tempo, Ansicht, Zoom, stummen Kanälen und Schleife. Der Stand wird
+      beim Beenden neben der MIDI-Datei als "<Datei>.mivi" gespeichert.
+
+  --render-frames=<Verzeichnis>
+      Spielt nicht ab, sondern schreibt das Video Bild für Bild als
+      PNG-Dateien (frame_000000.png, ...) in das Verzeichnis. Die Zeit
+      läuft dabei in festen Schritten von 1/fps Sekunden, unabhängig
+      davon, wie lange das Zeichnen dauert. Die Bilder lassen sich mit
+      einer WAV-Datei des Stücks (bspw. von midisynth) zu einem Video
+      zusammenfügen:
+        ffmpeg -framerate 30 -i frame_%06d.png -i song.wav out.mp4
+      Ohne Bildschirm, etwa auf einem Server, zusätzlich die Umgebungs-
+      variable SDL_VIDEODRIVER=offscreen setzen.
+
+  --fps=<n>
+      Bilder pro Sekunde für --render-frames (Vorgabe 30).
+
+  --no-vsync
+      Zeichnet, ohne auf die Bildwiederholrate des Bildschirms zu
+      warten. Hilft, wenn die Darstellung mit vsync ruckelt; ohne
+      --fps-cap läuft mivi dann aber so schnell es kann.
+
+  --fps-cap=<n>
+      Zeichnet höchstens n Bilder pro Sekunde und schläft für den Rest
+      jedes Bildes. Spart auf Bildschirmen mit hoher Bildwiederholrate
+      Rechenzeit, bspw. "--fps-cap=30" mit --no-vsync.
+
   -aq
       Auto-Quit: Beendet das Programm automatisch, sobald das Ende der
       MIDI-Datei erreicht ist. Bietet sich zum Abspielen von Playlisten
@@ -47,13 +139,65 @@ OPTIONEN
 
   --treble
       Deaktiviert das Bass-System (Bassschlüssel). Es wird nur der
-      Violinschlüssel angezeigt.
+      Violinschlüssel angezeigt, bspw. für eine Flötenstimme.
+
+  --bass
+      Zeigt nur das Bass-System (Bassschlüssel), bspw. für Bassgitarre.
+
+  --split=<MIDI-Nummer>
+      Trennpunkt zwischen Violin- und Bass-System (Vorgabe 60 = C4):
+      Tiefere Noten gehören zum Bass-System. Danach richten sich die
+      Hilfslinien und die Richtung des Notenhalses.
 
   -k<Tonart>
       Setzt die Tonart für die Bestimmung der Vorzeichen (Kreuz / Be).
       Bspw. "-kA" für A-Dur bzw. "-kfis" oder "-kF#m" für Fis-Moll.
-      Vorgabe ist C-Dur, alle Noten der schwarzen Tasten bekommen ein
-      Kreuz; ein Be bekommen sie nur in Be-Tonarten.
+      Ohne diese Option gilt die Tonart aus der MIDI-Datei (Key-Signature-
+      Event, bei Wechseln jeweils die zuletzt gültige); fehlt sie, ist
+      die Vorgabe C-Dur. Dann bekommen alle Noten der schwarzen Tasten
+      ein Kreuz; ein Be bekommen sie nur in Be-Tonarten.
+
+  --debug-notes
+      Gibt für die gerade klingenden Noten Kanal, MIDI-Nummer, die für
+      die Synthese berechnete Frequenz und Velocity auf der Konsole aus,
+      sobald sich die Menge der klingenden Noten ändert. Hilft bei der
+      Fehlersuche in Tonhöhe und Stimmung. Auch per Taste D schaltbar.
+
+  --beat-flash
+      Visuelles Metronom: Die Tastaturkante bzw. die "Jetzt"-Linie
+      blinkt kurz auf jeder Eins eines Taktes auf. Die Taktschläge
+      werden aus Taktart und Tempo der MIDI-Datei berechnet.
+
+  --ripple
+      Über jeder angeschlagenen Taste der Klavier-Ansicht breitet sich
+      kurz eine verblassende Welle in der Farbe der Note aus (umschalten
+      mit R).
+
+  --labels
+      Schreibt den Notennamen (bspw. C4 oder F#5) in jede Note der
+      Klavier-Ansicht, die groß genug dafür ist. Schwarze Tasten heißen
+      je nach Tonart Kreuz- oder Be-Ton, wie im Notensystem.
+
+  --beat-color=<RRGGBB>
+      Farbe des Aufblinkens als Hex-Wert, bspw. "--beat-color=ff8000".
+
+  --colors=<Kanal>=<RRGGBB>,...
+      Ersetzt die Farben einzelner Kanäle, bspw.
+      "--colors=0=ff0000,9=ffffff". Kanäle werden hier ab 0 gezählt,
+      das Schlagzeug ist 9.
+      Nicht genannte Kanäle behalten ihre Standardfarbe.
+
+  --track-colors
+      Färbt die Noten nach ihrer Spur in der MIDI-Datei statt nach dem
+      Kanal. Unterscheidet Instrumente, die in verschiedenen Spuren auf
+      demselben Kanal liegen. Das Schlagzeug bleibt grau, --colors gilt
+      weiterhin nur für Kanäle.
+
+  --past=<Sekunden>
+      Wie lange bereits gespielte Noten in der Klavier-Ansicht als
+      schrumpfender Rest an der Tastaturkante sichtbar bleiben, bevor
+      sie ausgeblendet werden. Vorgabe ist 1 Sekunde, 0 blendet sie
+      sofort aus.
 
   --tempo=<Faktor>
       Modifiziert das Tempo der MIDI-Datei um den Faktor.
@@ -62,10 +206,13 @@ OPTIONEN
   --transpose=<Halbtöne>
       Transponiert sowohl das Audio als auch die visuelle Darstellung.
       Beispiel: "--transpose=+2" oder "--transpose=-12".
+      Noten, die dabei den MIDI-Bereich verlassen, entfallen (mit Warnung).
+      Schlagzeug auf Kanal 10 bleibt unverändert.
 
   --transpose-staff=<Halbtöne>
       Transponiert NUR die visuelle Darstellung im Notensystem, wogegen
-      das Audio unverändert bleibt. Diese Funktion ist praktisch für
+      das Audio unverändert bleibt. Schlagzeug auf Kanal 10 bleibt, wie
+      bei --transpose, unverändert. Diese Funktion ist praktisch für
       1. Oktavierende Instrumente:
          Manche Instrumente wie bspw. Sopran-Blockflöte, Piccolo-Flöte,
          Tin Whistle und Glockenspiel werden eine Oktave tiefer notiert,
@@ -81,10 +228,10 @@ OPTIONEN
          in B gestimmt, "--transpose-staff=2".
 "#.trim_ascii();
 
-use sdl2::audio::{AudioCallback, AudioSpecDesired, AudioCVT};
+use sdl2::audio::{AudioCallback, AudioSpecDesired};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::{Point, Rect};
 use sdl2::render::Canvas;
 use sdl2::video::{Window, FullscreenType};
@@ -92,27 +239,80 @@ use sdl2::video::{Window, FullscreenType};
 use std::cmp::Ordering;
 use std::env;
 use std::f64::consts::PI;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::process::{Command, Stdio};
 use std::time::{Duration, Instant};
 use std::ops::ControlFlow;
 
+mod midi_out;
+mod png;
+mod session;
 mod staff;
+mod theory;
+use crate::midi_out::MidiOut;
+use crate::session::{Session, session_path};
 use crate::staff::{
     ImageSystem, Textures, StackRingBuffer, BufferedHead,
-    render_staff, KeyInfo
+    render_staff, KeyInfo, StaffMode
 };
+use crate::theory::note_name;
 
 // =====================================================================
 // KONFIGURATION UND KONSTANTEN
 // =====================================================================
-const SAMPLE_RATE: i32 = 44100;
+const SAMPLE_RATE: i32 = 44100; // Vorgabe, siehe --rate
 const AUDIO_CHANNELS: u8 = 1;
+const TIMIDITY_RATE_MIN: i32 = 4000; // Bereich, den Timidity mit -s annimmt
+const TIMIDITY_RATE_MAX: i32 = 65000;
+const DECLICK_SECONDS: f64 = 0.005; // Ein-/Ausblenden am Anfang und Ende
+const STREAM_DRIVE: f32 = 1.2; // Verstärkung vor der weichen Begrenzung bei --stream
+// Wie DEFAULT_RELEASE und DEFAULT_TAIL in midisynth, damit beide Programme
+// gleich lange Stücke liefern
+const NOTE_RELEASE: f64 = 0.1; // Ausklingzeit einer Note des internen Synthesizers
+const END_TAIL: f64 = 1.0; // Nachklang nach dem letzten Event
+const END_PAUSE: f64 = 1.0; // Stille nach dem Ende, bevor das Stück endet
+const TIMIDITY_END_PAUSE: f64 = 1.5; // dito mit Timidity, dessen Hall länger ausklingt
 const WINDOW_WIDTH: u32 = 1200;
 const WINDOW_HEIGHT: u32 = 800;
 const KEYBOARD_HEIGHT: i32 = 100;
-const PIXELS_PER_SECOND: f64 = 150.0;
+const PIXELS_PER_SECOND: f64 = 150.0; // Vorgabe, mit +/- änderbar
+const ZOOM_STEP: f64 = 1.25;
+const PIXELS_PER_SECOND_MIN: f64 = 20.0;
+const PIXELS_PER_SECOND_MAX: f64 = 600.0;
+
+const SEEK_FINE: f64 = 0.25; // Spulen mit Shift + Pfeiltaste, in Sekunden
+
+const TEMPO_STEP: f64 = 1.05;
+const TEMPO_MIN: f64 = 0.25;
+const TEMPO_MAX: f64 = 4.0;
+
+const BEAT_FLASH_DURATION: f64 = 0.1; // Abklingzeit des Aufblinkens
+const BEAT_FLASH_HEIGHT: u32 = 6;
+
+const PEDAL_BAR_HEIGHT: u32 = 6; // Leiste unter der Tastatur bei getretenem Haltepedal
+const PEDAL_BAR_COLOR: Color = Color::RGB(255, 190, 60);
+
+const RIPPLE_SECONDS: f64 = 0.3; // Dauer der Welle über einer angeschlagenen Taste
+const RIPPLE_HEIGHT: i32 = 16;
+const PAST_TRAIL_HEIGHT: f64 = 8.0; // Rest einer gespielten Note an der Tastaturkante, siehe --past
+
+const VELOCITY_BRIGHTNESS_MIN: f32 = 0.35; // Helligkeit bei Velocity 0
+
+const SCOPE_HEIGHT: u32 = 80; // Wellenform am unteren Rand (Taste W)
+const SCOPE_SECONDS: f64 = 1.0;
+const SCOPE_COLOR: Color = Color::RGB(120, 230, 160);
+
+const PROGRESS_HEIGHT: u32 = 4;
+const PROGRESS_TEXT_SCALE: i32 = 2; // Pixelgröße der Zeitanzeige
+
+const FRAME_RATE: f64 = 30.0; // Vorgabe für --render-frames, siehe --fps
+
+const LIVE_BASE_KEY: i32 = 60;   // Spielmodus: untere Tastenreihe beginnt bei C4
+const LIVE_VOLUME: f32 = 6000.0; // Amplitude einer live gespielten Stimme
+const LIVE_ATTACK: f64 = 0.01;   // Ein- und Ausschwingzeit in Sekunden
+const LIVE_RELEASE: f64 = 0.1;
+const LIVE_KEY_COLOR: Color = Color::RGB(255, 200, 0);
 
 const MIN_MIDI: i32 = 21;  // A0
 const MAX_MIDI: i32 = 108; // C8
@@ -125,14 +325,28 @@ const MAX_MIDI: i32 = 108; // C8
 enum EventType {
     NoteOn,
     NoteOff,
-    SetTempo
+    SetTempo,
+    TimeSignature,
+    KeySignature,
+    ProgramChange,
+    PitchBend,
+    Sustain
 }
 
+// Bei TimeSignature steht der Zähler in `note` und der
+// Nenner als Zweierpotenz in `velocity` (wie in der MIDI-Datei).
+// Bei KeySignature steht die Anzahl der Vorzeichen (als i8, negativ für
+// Bes) in `note` und 1 für Moll in `velocity`.
+// Bei ProgramChange steht die Programmnummer in `note`, bei PitchBend
+// das LSB in `note` und das MSB in `velocity`. Bei Sustain (Control
+// Change 64) steht die Pedalstellung in `velocity`, ab 64 getreten.
+// `track` ist die Nummer der Spur in der Datei, ab 0 gezählt.
 #[derive(Debug, Clone)]
 struct MidiEvent {
     abs_tick: u32,
     event_type: EventType,
     channel: u8,
+    track: u16,
     note: u8,
     velocity: u8,
     tempo_micros: u32
@@ -143,9 +357,13 @@ struct Note {
     start_time: f64,
     duration: f64,
     midi_key: i32,
-    _velocity: i32, // Wird nach der Synthese nicht mehr zwingend gebraucht
-    _channel: i32,
-    color: Color
+    velocity: i32,
+    channel: i32,
+    track: u16, // Spur des Note On
+    program: u8,
+    // Pitch-Bend-Verlauf als (Sekunden ab Notenbeginn, -1.0 bis 1.0),
+    // leer ohne Pitch Bend
+    bends: Vec<(f64, f64)>
 }
 
 struct Env {
@@ -160,16 +378,40 @@ struct Env {
     paused: bool,
     fullscreen: bool,
     black_notes: bool,
-    show_bass_staff: bool,
+    staff_mode: StaffMode,
+    split_key: i32, // tiefere Noten gehören in der Akkolade zum Bass
     view_mode: u8,
     root_key: KeyInfo,
-
-    // Unveränderliche Audio-Daten
+    tempo: f64,
+    pending_tempo: Option<f64>,
+    debug_notes: bool,
+    past_seconds: f64,
+    pixels_per_second: f64,
+    beat_flash: bool,
+    ripple: bool,
+    labels: bool,
+    beat_color: Color,
+    palette: [Color; 16],
+    track_colors: bool,
+    flash_intensity: f32,
+    pedal_down: bool,
+    loop_a: Option<f64>,
+    loop_b: Option<f64>,
+    show_progress: bool,
+    show_scope: bool,
+    muted: [bool; 16],
+    sample_rate: i32,
+    live_mode: bool,
+    live_keys: [bool; 128],
+
+    // Audio-Daten (ändern sich nur bei Tempowechsel)
     end_limit: f64,
 
     // Wiederverwendbare Arbeitsspeicher
+    debug_active: Vec<(i32, i32)>,
     active_keys: [bool; 128],
     active_colors: [Color; 128],
+    ripple_start: [f64; 128], // Beginn der klingenden Note je Taste
     ring_buffer: StackRingBuffer::<BufferedHead, 256>
 }
 
@@ -177,21 +419,171 @@ struct Env {
 // AUDIO-CALLBACK
 // =====================================================================
 
+// Vorgemischte Samples eines MIDI-Kanals (bei Timidity: der gesamte Mix,
+// `channel` ist dann None). Die Samples sind voll ausgesteuert, `gain`
+// skaliert sie auf ihren Anteil am Gesamtmix.
+struct Stem {
+    channel: Option<i32>,
+    gain: f32,
+    samples: Vec<i16>
+}
+
+fn stems_len(stems: &[Stem]) -> usize {
+    stems.iter().map(|s| s.samples.len()).max().unwrap_or(0)
+}
+
+// Synthese beim Abspielen (--stream): statt fertiger Spuren liegen nur
+// die Noten vor, jeder vom Audiogerät angeforderte Block wird ab dem
+// Cursor frisch gemischt. `buf` hält den zuletzt berechneten Block.
+struct NoteStream {
+    notes: Vec<Note>, // nach Startzeit sortiert
+    channels: Vec<i32>,
+    wave: Wave,
+    bend_range: f64,
+    rate: i32,
+    len: usize,
+    max_len: f64, // längste Note samt Ausklingen, in Sekunden
+    buf: Vec<f32>
+}
+
+impl NoteStream {
+    fn new(notes: &[Note], duration: f64, wave: Wave, bend_range: f64, rate: i32) -> Self {
+        let mut channels: Vec<i32> = notes.iter().map(|n| n.channel).collect();
+        channels.sort_unstable();
+        channels.dedup();
+        // Schlagzeug klingt höchstens so lange wie das längste Becken
+        let max_len = notes.iter().map(|n| n.duration).fold(0.6, f64::max) + NOTE_RELEASE;
+        NoteStream {
+            notes: notes.to_vec(), channels, wave, bend_range, rate,
+            len: (duration * rate as f64) as usize, max_len, buf: Vec::new()
+        }
+    }
+
+    // Mischt `count` Samples ab `offset` nach `buf`. Nur die Noten, die in
+    // den Block hineinreichen, werden überhaupt betrachtet.
+    fn render(&mut self, offset: usize, count: usize, muted: &[bool; 16]) {
+        self.buf.clear();
+        self.buf.resize(count, 0.0);
+        let rate = self.rate as f64;
+        let block_start = offset as f64 / rate;
+        let first = self.notes.partition_point(|n| n.start_time + self.max_len < block_start);
+        let last = self.notes.partition_point(|n| ((n.start_time * rate) as usize) < offset + count);
+        if first >= last { return; }
+        for &channel in &self.channels {
+            if muted[channel as usize] { continue; }
+            mix_notes(&self.notes[first..last], channel, self.wave, self.bend_range,
+                self.rate, offset, &mut self.buf);
+        }
+    }
+
+    // Sample `i` des Blocks, weich begrenzt
+    fn sample(&self, i: usize) -> f32 {
+        (self.buf[i] * STREAM_DRIVE).tanh() * 32000.0
+    }
+}
+
+// Über die Computertastatur gespielte Stimme (Spielmodus, siehe Tab)
+struct LiveVoice {
+    key: i32,
+    phase_inc: f64,
+    phase: f64,
+    level: f32,
+    held: bool
+}
+
+// Mischt die nicht stumm geschalteten Spuren erst beim Abspielen, damit
+// Stummschalten sofort und ohne neue Synthese wirkt. Live gespielte
+// Stimmen kommen additiv hinzu; sie klingen auch während der Pause,
+// deshalb läuft das Gerät durch und nur `playing` hält den Cursor an.
+// Mit `stream` (--stream) gibt es keine Spuren, gemischt wird direkt
+// aus den Noten.
 struct SoundProvider {
-    samples: Vec<i16>,
-    cursor: usize
+    stems: Vec<Stem>,
+    stream: Option<NoteStream>,
+    muted: [bool; 16],
+    cursor: usize,
+    playing: bool,
+    live: Vec<LiveVoice>,
+    wave: Wave,
+    rate: f64,
+    // Ringpuffer der zuletzt ausgegebenen Samples für die Wellenform
+    // (Taste W), leer solange sie ausgeblendet ist; `recent_pos` ist die
+    // nächste Schreibposition und damit das älteste Sample
+    recent: Vec<i16>,
+    recent_pos: usize
+}
+
+impl SoundProvider {
+    fn len(&self) -> usize {
+        match &self.stream {
+            Some(stream) => stream.len,
+            None => stems_len(&self.stems)
+        }
+    }
+
+    fn note_on(&mut self, key: i32) {
+        // Eine noch ausklingende Stimme derselben Taste wird weiterverwendet
+        if let Some(v) = self.live.iter_mut().find(|v| v.key == key) {
+            v.held = true;
+        } else {
+            let phase_inc = midi_to_freq(key) / self.rate;
+            self.live.push(LiveVoice {key, phase_inc, phase: 0.0, level: 0.0, held: true});
+        }
+    }
+
+    fn note_off(&mut self, key: i32) {
+        for v in self.live.iter_mut().filter(|v| v.key == key) {
+            v.held = false;
+        }
+    }
+
+    // Nächstes Sample aller live gespielten Stimmen
+    fn next_live_sample(&mut self) -> f32 {
+        if self.live.is_empty() { return 0.0; }
+        let attack = (1.0 / (LIVE_ATTACK * self.rate)) as f32;
+        let release = (1.0 / (LIVE_RELEASE * self.rate)) as f32;
+        let mut sum = 0.0;
+        for v in &mut self.live {
+            v.level = if v.held {
+                (v.level + attack).min(1.0)
+            } else {
+                (v.level - release).max(0.0)
+            };
+            sum += live_oscillator(self.wave, v.phase) as f32 * v.level;
+            v.phase = (v.phase + v.phase_inc).fract();
+        }
+        self.live.retain(|v| v.held || v.level > 0.0);
+        sum * LIVE_VOLUME
+    }
 }
 
 impl AudioCallback for SoundProvider {
     type Channel = i16;
 
     fn callback(&mut self, out: &mut [i16]) {
-        for dst in out.iter_mut() {
-            if self.cursor < self.samples.len() {
-                *dst = self.samples[self.cursor];
+        let len = self.len();
+        if let Some(stream) = &mut self.stream && self.playing {
+            let count = out.len().min(len.saturating_sub(self.cursor));
+            stream.render(self.cursor, count, &self.muted);
+        }
+        for (i, dst) in out.iter_mut().enumerate() {
+            let mut sum = self.next_live_sample();
+            if self.playing && self.cursor < len {
+                if let Some(stream) = &self.stream {
+                    sum += stream.sample(i);
+                }
+                for stem in &self.stems {
+                    if stem.channel.is_some_and(|c| self.muted[c as usize]) { continue; }
+                    if let Some(&v) = stem.samples.get(self.cursor) {
+                        sum += v as f32 * stem.gain;
+                    }
+                }
                 self.cursor += 1;
-            } else {
-                *dst = 0;
+            }
+            *dst = sum.clamp(-32768.0, 32767.0) as i16;
+            if !self.recent.is_empty() {
+                self.recent[self.recent_pos] = *dst;
+                self.recent_pos = (self.recent_pos + 1) % self.recent.len();
             }
         }
     }
@@ -201,7 +593,39 @@ impl AudioCallback for SoundProvider {
 // HELPER: FARBEN UND KEYBOARD
 // =====================================================================
 
-fn get_channel_color(channel: i32) -> Color {
+// Farbe eines Kanals aus der mit --colors angepassten Palette
+fn get_channel_color(palette: &[Color; 16], channel: i32) -> Color {
+    palette[(channel & 15) as usize]
+}
+
+// Farbe einer Spur für --track-colors: reihum die Standardfarben der
+// Kanäle, ohne das Grau des Schlagzeugs
+fn get_track_color(track: u16) -> Color {
+    default_channel_color((track % 9) as i32)
+}
+
+// Grundfarbe einer Note, je nach --track-colors die ihrer Spur oder
+// ihres Kanals. Schlagzeug bleibt immer in der Kanalfarbe.
+fn get_note_base_color(env: &Env, n: &Note) -> Color {
+    if env.track_colors && n.channel != 9 {
+        get_track_color(n.track)
+    } else {
+        get_channel_color(&env.palette, n.channel)
+    }
+}
+
+// Angezeigte Taste mit --transpose-staff (`vis_offset`). Schlagzeug-
+// noten stehen für Instrumente, nicht für Tonhöhen, und bleiben wie bei
+// --transpose an ihrem Platz.
+fn display_key(n: &Note, vis_offset: i32) -> i32 {
+    if n.channel == 9 {
+        n.midi_key
+    } else {
+        n.midi_key + vis_offset
+    }
+}
+
+fn default_channel_color(channel: i32) -> Color {
     if channel == 9 {
         return Color::RGB(150, 150, 150);
     }
@@ -219,19 +643,77 @@ fn get_channel_color(channel: i32) -> Color {
     }
 }
 
-fn is_black_key(midi: i32) -> bool {
+// Farbe einer Note im Piano-Roll: Grundfarbe, mit der Velocity skaliert.
+// Leise Noten bleiben durch den Mindestwert sichtbar, Schlagzeug bleibt grau.
+fn note_color(base: Color, channel: i32, velocity: i32) -> Color {
+    if channel == 9 {
+        return base;
+    }
+    let factor = VELOCITY_BRIGHTNESS_MIN
+        + (1.0 - VELOCITY_BRIGHTNESS_MIN) * (velocity.clamp(0, 127) as f32 / 127.0);
+    let scale = |v: u8| (v as f32 * factor) as u8;
+    Color::RGB(scale(base.r), scale(base.g), scale(base.b))
+}
+
+// Farbe im Format "RRGGBB" (Hex), optional mit führendem '#'
+fn parse_hex_color(s: &str) -> Option<Color> {
+    let s = s.trim_start_matches('#');
+    if s.len() != 6 { return None; }
+    let v = u32::from_str_radix(s, 16).ok()?;
+    Some(Color::RGB((v >> 16) as u8, (v >> 8) as u8, v as u8))
+}
+
+// Liest "<Kanal>=<RRGGBB>,..." (Kanal 0 bis 15) in die Palette ein.
+// Nicht genannte Kanäle behalten ihre bisherige Farbe.
+fn parse_palette(spec: &str, palette: &mut [Color; 16]) -> Result<(), String> {
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (channel, color) = entry.split_once('=')
+            .ok_or_else(|| format!("Ungültiger Paletteneintrag: {}", entry))?;
+        let channel = channel.trim().parse::<usize>().ok().filter(|&c| c < 16)
+            .ok_or_else(|| format!("Ungültiger Kanal: {}", channel))?;
+        palette[channel] = parse_hex_color(color.trim())
+            .ok_or_else(|| format!("Ungültige Farbe: {}", color))?;
+    }
+    Ok(())
+}
+
+const fn is_black_key(midi: i32) -> bool {
     matches!(midi % 12, 1 | 3 | 6 | 8 | 10)
 }
 
-fn get_key_geometry(midi_note: i32, total_width: f32) -> (f32, f32, bool) {
-    let mut white_keys_total = 0;
-    for i in MIN_MIDI..=MAX_MIDI {
+const fn count_white_keys(min: i32, max: i32) -> i32 {
+    let mut count = 0;
+    let mut i = min;
+    while i <= max {
         if !is_black_key(i) {
-            white_keys_total += 1;
+            count += 1;
         }
+        i += 1;
+    }
+    count
+}
+
+// Anzahl weißer Tasten zwischen MIN_MIDI und MAX_MIDI. Tastatur und
+// fallende Noten müssen dieselbe Zahl verwenden, sonst laufen sie auseinander.
+const WHITE_KEYS_TOTAL: i32 = count_white_keys(MIN_MIDI, MAX_MIDI);
+
+// Versatz der Mitte einer schwarzen Taste gegenüber der Grenze der
+// weißen Tasten, in Breiten einer weißen Taste. Wie beim echten Klavier
+// teilen sich die schwarzen Tasten den oberen Teil jeder Gruppe (C bis E
+// in 5, F bis H in 7 gleiche Teile), daher sitzt Cis näher am C, Dis
+// näher am E, Fis näher am F, Gis mittig und Ais näher am H.
+fn black_key_offset(midi_note: i32) -> f32 {
+    match midi_note.rem_euclid(12) {
+        1 => -1.0 / 10.0,
+        3 => 1.0 / 10.0,
+        6 => -1.0 / 7.0,
+        10 => 1.0 / 7.0,
+        _ => 0.0
     }
+}
 
-    let wk_width = total_width / white_keys_total as f32;
+fn get_key_geometry(midi_note: i32, total_width: f32) -> (f32, f32, bool) {
+    let wk_width = total_width / WHITE_KEYS_TOTAL as f32;
     let bk_width = wk_width * 0.65;
 
     let mut current_wk_index = 0;
@@ -245,7 +727,7 @@ fn get_key_geometry(midi_note: i32, total_width: f32) -> (f32, f32, bool) {
     let is_black = is_black_key(midi_note);
 
     if is_black {
-        (pos - (bk_width / 2.0), bk_width, true)
+        (pos + black_key_offset(midi_note) * wk_width - (bk_width / 2.0), bk_width, true)
     } else {
         (pos, wk_width, false)
     }
@@ -256,19 +738,45 @@ fn get_key_geometry(midi_note: i32, total_width: f32) -> (f32, f32, bool) {
 // =====================================================================
 
 // Hilfsfunktionen zum Lesen von Big-Endian Werten
-fn read_be16(f: &mut File) -> std::io::Result<u16> {
+fn read_be16<R: Read>(f: &mut R) -> std::io::Result<u16> {
     let mut buf = [0u8; 2];
     f.read_exact(&mut buf)?;
     Ok(u16::from_be_bytes(buf))
 }
 
-fn read_be32(f: &mut File) -> std::io::Result<u32> {
+fn read_be32<R: Read>(f: &mut R) -> std::io::Result<u32> {
     let mut buf = [0u8; 4];
     f.read_exact(&mut buf)?;
     Ok(u32::from_be_bytes(buf))
 }
 
-fn read_varlen(f: &mut File) -> std::io::Result<u32> {
+// Prüft, ob ein Chunk noch in die Datei passt. So meldet eine abgeschnittene
+// Datei ihre Länge, statt mitten in der Spur auf das Dateiende zu stoßen.
+fn check_chunk_len<R: Seek>(f: &mut R, len: u32, file_len: u64)
+    -> Result<(), Box<dyn std::error::Error>>
+{
+    let left = file_len.saturating_sub(f.stream_position()?);
+    if len as u64 > left {
+        return Err(format!("Spurlänge überschreitet die Dateigröße ({} Bytes angegeben, {} übrig)",
+            len, left).into());
+    }
+    Ok(())
+}
+
+// Meta- und SysEx-Events dürfen nicht über das Ende ihrer Spur hinaus-
+// reichen, sonst liefe der Parser danach mitten in fremden Daten weiter
+fn check_event_len<R: Seek>(f: &mut R, len: u32, end_pos: u64)
+    -> Result<(), Box<dyn std::error::Error>>
+{
+    let left = end_pos.saturating_sub(f.stream_position()?);
+    if len as u64 > left {
+        return Err(format!("Eventlänge überschreitet die Spur ({} Bytes angegeben, {} übrig)",
+            len, left).into());
+    }
+    Ok(())
+}
+
+fn read_varlen<R: Read>(f: &mut R) -> std::io::Result<u32> {
     let mut value: u32 = 0;
     let mut byte = [0u8; 1];
     loop {
@@ -283,6 +791,15 @@ fn read_varlen(f: &mut File) -> std::io::Result<u32> {
 
 fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), Box<dyn std::error::Error>> {
     let mut f = File::open(filename)?;
+    parse_midi_from(&mut f)
+}
+
+fn parse_midi_from<R: Read + Seek>(f: &mut R)
+    -> Result<(Vec<MidiEvent>, u16), Box<dyn std::error::Error>>
+{
+    let start = f.stream_position()?;
+    let file_len = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(start))?;
 
     // Header Check
     let mut chunk_id = [0u8; 4];
@@ -291,34 +808,44 @@ fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), Box<dyn std::erro
         return Err("Kein gültiges MIDI".into());
     }
 
-    read_be32(&mut f)?; // Header length (skip)
-    read_be16(&mut f)?; // Format (skip)
-    let num_tracks = read_be16(&mut f)?;
-    let division = read_be16(&mut f)?;
-
-    if division & 0x8000 != 0 {
-        return Err("SMPTE nicht unterstützt".into());
+    let header_len = read_be32(f)?;
+    check_chunk_len(f, header_len, file_len)?;
+    let format = read_be16(f)?;
+    let num_tracks = read_be16(f)?;
+    let division = read_be16(f)?;
+    // Null Ticks pro Schlag ließen jeden Tick unendlich lang dauern. Bei
+    // SMPTE ist ein oberes Byte von 0x80 (-128) keine gültige Bildrate.
+    if division == 0 || division >> 8 == 0x80 {
+        return Err(format!("Ungültige Zeitbasis im MIDI-Header: {:#06x}", division).into());
     }
+    // Spätere Versionen des Standards dürfen den Header verlängern
+    f.seek(SeekFrom::Current(header_len.saturating_sub(6) as i64))?;
 
     let mut all_events = Vec::new();
 
-    for _ in 0..num_tracks {
+    // Format 2: Jede Spur ist ein eigenständiges Pattern, die Spuren
+    // werden nacheinander abgespielt statt gleichzeitig.
+    let mut track_offset = 0;
+
+    for track in 0..num_tracks {
         f.read_exact(&mut chunk_id)?;
         while &chunk_id != b"MTrk" {
-            let skip = read_be32(&mut f)?;
+            let skip = read_be32(f)?;
+            check_chunk_len(f, skip, file_len)?;
             f.seek(SeekFrom::Current(skip as i64))?;
             f.read_exact(&mut chunk_id)?;
         }
 
-        let track_len = read_be32(&mut f)?;
+        let track_len = read_be32(f)?;
+        check_chunk_len(f, track_len, file_len)?;
         let start_pos = f.stream_position()?;
         let end_pos = start_pos + track_len as u64;
 
-        let mut abs_tick = 0;
+        let mut abs_tick = if format == 2 { track_offset } else { 0 };
         let mut running_status = 0u8;
 
         while f.stream_position()? < end_pos {
-            let delta = read_varlen(&mut f)?;
+            let delta = read_varlen(f)?;
             abs_tick += delta;
 
             let mut byte = [0u8; 1];
@@ -328,7 +855,8 @@ fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), Box<dyn std::erro
             if status < 0x80 {
                 status = running_status;
                 f.seek(SeekFrom::Current(-1))?;
-            } else {
+            } else if status < 0xF0 {
+                // Meta- und SysEx-Events lassen den Running Status stehen
                 running_status = status;
             }
 
@@ -336,7 +864,8 @@ fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), Box<dyn std::erro
                 // Meta Event
                 f.read_exact(&mut byte)?; // Type
                 let meta_type = byte[0];
-                let len = read_varlen(&mut f)?;
+                let len = read_varlen(f)?;
+                check_event_len(f, len, end_pos)?;
 
                 if meta_type == 0x51 && len == 3 {
                     let mut tb = [0u8; 3];
@@ -346,16 +875,48 @@ fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), Box<dyn std::erro
                         abs_tick,
                         event_type: EventType::SetTempo,
                         channel: 0,
+                        track,
                         note: 0,
                         velocity: 0,
                         tempo_micros: micros,
                     });
+                } else if meta_type == 0x58 && len == 4 {
+                    // Taktart: Zähler, Nenner (Zweierpotenz), Clocks, 32tel
+                    let mut ts = [0u8; 4];
+                    f.read_exact(&mut ts)?;
+                    all_events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::TimeSignature,
+                        channel: 0,
+                        track,
+                        note: ts[0],
+                        velocity: ts[1],
+                        tempo_micros: 0,
+                    });
+                } else if meta_type == 0x2F {
+                    // End of Track: Rest der Spur (ggf. Datenmüll) überspringen
+                    f.seek(SeekFrom::Start(end_pos))?;
+                    break;
+                } else if meta_type == 0x59 && len == 2 {
+                    // Tonart: Vorzeichen (sf), Dur/Moll (mi)
+                    let mut ks = [0u8; 2];
+                    f.read_exact(&mut ks)?;
+                    all_events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::KeySignature,
+                        channel: 0,
+                        track,
+                        note: ks[0],
+                        velocity: ks[1],
+                        tempo_micros: 0,
+                    });
                 } else {
                     f.seek(SeekFrom::Current(len as i64))?;
                 }
             } else if status == 0xF0 || status == 0xF7 {
                 // SysEx
-                let len = read_varlen(&mut f)?;
+                let len = read_varlen(f)?;
+                check_event_len(f, len, end_pos)?;
                 f.seek(SeekFrom::Current(len as i64))?;
             } else {
                 // Channel Event
@@ -373,56 +934,163 @@ fn parse_midi(filename: &str) -> Result<(Vec<MidiEvent>, u16), Box<dyn std::erro
                         abs_tick,
                         event_type: if is_note_on { EventType::NoteOn } else { EventType::NoteOff },
                         channel: ch,
+                        track,
                         note,
                         velocity: vel,
                         tempo_micros: 0,
                     });
-                } else if cmd == 0xC0 || cmd == 0xD0 {
+                } else if cmd == 0xB0 {
+                    // Control Change, nur das Haltepedal (64) wird gebraucht
+                    let mut params = [0u8; 2];
+                    f.read_exact(&mut params)?;
+                    if params[0] == 64 {
+                        all_events.push(MidiEvent {
+                            abs_tick,
+                            event_type: EventType::Sustain,
+                            channel: ch,
+                            track,
+                            note: 0,
+                            velocity: params[1],
+                            tempo_micros: 0,
+                        });
+                    }
+                } else if cmd == 0xC0 {
+                    // Program Change
+                    let mut params = [0u8; 1];
+                    f.read_exact(&mut params)?;
+                    all_events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::ProgramChange,
+                        channel: ch,
+                        track,
+                        note: params[0],
+                        velocity: 0,
+                        tempo_micros: 0,
+                    });
+                } else if cmd == 0xD0 {
                     f.seek(SeekFrom::Current(1))?;
+                } else if cmd == 0xE0 {
+                    // Pitch Bend (14 Bit)
+                    let mut params = [0u8; 2];
+                    f.read_exact(&mut params)?;
+                    all_events.push(MidiEvent {
+                        abs_tick,
+                        event_type: EventType::PitchBend,
+                        channel: ch,
+                        track,
+                        note: params[0] & 0x7F,
+                        velocity: params[1] & 0x7F,
+                        tempo_micros: 0,
+                    });
                 } else {
                     f.seek(SeekFrom::Current(2))?;
                 }
             }
         }
+        track_offset = abs_tick;
     }
 
-    // Sortieren
-    all_events.sort_by_key(|e| e.abs_tick);
+    // Sortieren (stabil); bei gleichem Tick kommt Note Off vor Note On,
+    // damit eine im selben Tick beendete und neu angeschlagene Note aus
+    // verschiedenen Spuren nicht auf Länge null schrumpft
+    all_events.sort_by_key(|e| (e.abs_tick, e.event_type != EventType::NoteOff));
     Ok((all_events, division))
 }
 
+// Bei SMPTE-Zeitbasis (Bit 15 gesetzt) steht im oberen Byte die negierte
+// Bildrate (24, 25, 29 = 29,97 Drop-Frame, 30), im unteren die Ticks pro
+// Bild. Ein Tick hat dann eine feste Dauer, unabhängig von Tempo-Events.
+// -128 (0x80) lässt sich nicht negieren, der Header wird dann abgelehnt.
+fn smpte_seconds_per_tick(division: u16) -> Option<f64> {
+    if division & 0x8000 == 0 {
+        return None;
+    }
+    let fps = match ((division >> 8) as u8 as i8).checked_neg()? {
+        29 => 29.97,
+        fps => fps as f64
+    };
+    let ticks_per_frame = (division & 0xFF).max(1) as f64;
+    Some(1.0 / (fps * ticks_per_frame))
+}
+
+// Tempowechsel als (Tick, Zeit in Sekunden bei Originaltempo,
+// Mikrosekunden pro Schlag ab dort), beginnend mit 120 BPM bei Tick 0.
+// Mehrere Wechsel im selben Tick: der letzte gilt. Alle Zeiten werden
+// aus dieser Tabelle berechnet, genau wie in midisynth, damit beide
+// Programme dieselben Notenlängen liefern.
+fn build_tempo_map(events: &[MidiEvent], division: u16) -> Vec<(u32, f64, f64)> {
+    let mut map = vec![(0, 0.0, 500_000.0)];
+    for e in events.iter().filter(|e| e.event_type == EventType::SetTempo) {
+        let seconds = tick_to_seconds(e.abs_tick, &map, division, None);
+        if map.last().is_some_and(|&(tick, _, _)| tick == e.abs_tick) {
+            map.pop();
+        }
+        map.push((e.abs_tick, seconds, e.tempo_micros as f64));
+    }
+    map
+}
+
+// Absoluter Tick in Sekunden beim Wiedergabetempo `tempo` (Faktor, 1.0
+// ist das Originaltempo). Bei SMPTE-Zeitbasis zählt die Tabelle nicht.
+fn tick_to_seconds(tick: u32, map: &[(u32, f64, f64)], division: u16, tempo: Option<f64>) -> f64 {
+    let seconds = if let Some(spt) = smpte_seconds_per_tick(division) {
+        tick as f64 * spt
+    } else {
+        let index = map.partition_point(|&(t, _, _)| t <= tick).saturating_sub(1);
+        let (start, seconds, micros_per_beat) = map[index];
+        let seconds_per_tick = (micros_per_beat / 1_000_000.0) / (division as f64);
+        seconds + (tick - start) as f64 * seconds_per_tick
+    };
+    seconds / tempo.unwrap_or(1.0)
+}
+
+// Ausschnitt des Pitch-Bend-Verlaufs eines Kanals (absolute Zeiten) für
+// eine Note von `start` bis `end`, mit Zeiten relativ zum Notenbeginn
+fn bend_curve(history: &[(f64, f64)], start: f64, end: f64) -> Vec<(f64, f64)> {
+    if history.is_empty() {
+        return Vec::new();
+    }
+    let first = history.partition_point(|&(t, _)| t <= start);
+    let initial = if first > 0 { history[first - 1].1 } else { 0.0 };
+    let mut curve = vec![(0.0, initial)];
+    curve.extend(history[first..].iter()
+        .take_while(|&&(t, _)| t < end)
+        .map(|&(t, value)| (t - start, value)));
+    curve
+}
+
 fn convert_to_notes(events: &[MidiEvent], division: u16,
     tempo: Option<f64>, transpose: i32
 ) -> (Vec<Note>, f64) {
     let mut notes = Vec::new();
     let mut cur_time = 0.0;
-    let mut cur_tick = 0;
-    let mut micros_per_beat = 500_000.0;
-
-    // [Channel][Note] -> (Startzeit, Velocity)
-    let mut active_notes: [[Option<(f64, u8)>; 128]; 16] = [[None; 128]; 16];
+    let tempo_map = build_tempo_map(events, division);
 
-    let conv = match tempo {
-        Some(tempo) => 1_000_000.0*tempo,
-        None => 1_000_000.0
-    };
+    // [Channel][Note] -> (Startzeit, Velocity, Spur)
+    let mut active_notes: [[Option<(f64, u8, u16)>; 128]; 16] = [[None; 128]; 16];
+    // Noch offene Note Ons pro Taste (doppelt angeschlagen = zwei Note Offs)
+    let mut active_counts = [[0u32; 128]; 16];
+    let mut programs = [0u8; 16];
+    // Pitch-Bend-Änderungen pro Kanal als (Zeit, -1.0 bis 1.0)
+    let mut bend_history: Vec<Vec<(f64, f64)>> = vec![Vec::new(); 16];
 
     for e in events {
-        if e.abs_tick > cur_tick {
-            let delta_ticks = e.abs_tick - cur_tick;
-            let delta_time = (delta_ticks as f64) * (micros_per_beat / conv) / (division as f64);
-            cur_time += delta_time;
-            cur_tick = e.abs_tick;
-        }
+        cur_time = tick_to_seconds(e.abs_tick, &tempo_map, division, tempo);
 
         match e.event_type {
-            EventType::SetTempo => micros_per_beat = e.tempo_micros as f64,
+            EventType::SetTempo | EventType::TimeSignature | EventType::KeySignature
+                | EventType::Sustain => {},
+            EventType::ProgramChange => programs[e.channel as usize] = e.note,
+            EventType::PitchBend => {
+                let value = ((e.velocity as u16) << 7 | e.note as u16) as f64;
+                bend_history[e.channel as usize].push((cur_time, (value - 8192.0) / 8192.0));
+            },
             EventType::NoteOn => {
                 let ch = e.channel as usize;
                 let n = e.note as usize;
 
                 // Falls Note schon an, vorherige beenden (Retrigger)
-                if let Some((start, vel)) = active_notes[ch][n] {
+                if let Some((start, vel, track)) = active_notes[ch][n] {
                     let dur = cur_time - start;
                     if dur > 0.0 {
                         let final_key = if e.channel == 9 {
@@ -434,18 +1102,23 @@ fn convert_to_notes(events: &[MidiEvent], division: u16,
                             start_time: start,
                             duration: dur,
                             midi_key: final_key,
-                            _velocity: vel as i32,
-                            _channel: e.channel as i32,
-                            color: get_channel_color(e.channel as i32),
+                            velocity: vel as i32,
+                            channel: e.channel as i32,
+                            track,
+                            program: programs[ch],
+                            bends: bend_curve(&bend_history[ch], start, cur_time),
                         });
                     }
                 }
-                active_notes[ch][n] = Some((cur_time, e.velocity));
+                active_notes[ch][n] = Some((cur_time, e.velocity, e.track));
+                active_counts[ch][n] += 1;
             },
             EventType::NoteOff => {
                 let ch = e.channel as usize;
                 let n = e.note as usize;
-                if let Some((start, vel)) = active_notes[ch][n] {
+                if active_counts[ch][n] > 1 {
+                    active_counts[ch][n] -= 1;
+                } else if let Some((start, vel, track)) = active_notes[ch][n] {
                     let dur = cur_time - start;
                     if dur > 0.0 {
                         let final_key = if e.channel == 9 {
@@ -457,209 +1130,725 @@ fn convert_to_notes(events: &[MidiEvent], division: u16,
                             start_time: start,
                             duration: dur,
                             midi_key: final_key,
-                            _velocity: vel as i32,
-                            _channel: e.channel as i32,
-                            color: get_channel_color(e.channel as i32),
+                            velocity: vel as i32,
+                            channel: e.channel as i32,
+                            track,
+                            program: programs[ch],
+                            bends: bend_curve(&bend_history[ch], start, cur_time),
                         });
                     }
                     active_notes[ch][n] = None;
+                    active_counts[ch][n] = 0;
                 }
             },
         }
     }
 
+    let end_time = cur_time + END_TAIL;
+
+    // Hängende Noten ohne Note Off bis zum Ende klingen lassen
+    let mut stuck = 0;
+    for (ch, keys) in active_notes.iter().enumerate() {
+        for (n, active) in keys.iter().enumerate() {
+            if let Some((start, vel, track)) = *active {
+                stuck += 1;
+                let final_key = if ch == 9 {
+                    n as i32
+                } else {
+                    n as i32 + transpose
+                };
+                notes.push(Note {
+                    start_time: start,
+                    duration: end_time - start,
+                    midi_key: final_key,
+                    velocity: vel as i32,
+                    channel: ch as i32,
+                    track,
+                    program: programs[ch],
+                    bends: bend_curve(&bend_history[ch], start, end_time),
+                });
+            }
+        }
+    }
+    if stuck > 0 {
+        eprintln!("Warnung: {} Noten ohne Note Off, bis zum Ende gehalten", stuck);
+    }
+
+    // Durch --transpose aus dem MIDI-Bereich geschobene Noten weglassen
+    let before = notes.len();
+    notes.retain(|n| (0..=127).contains(&n.midi_key));
+    if notes.len() < before {
+        eprintln!("Warnung: {} Noten durch --transpose außerhalb des MIDI-Bereichs, ausgelassen",
+            before - notes.len());
+    }
+
     // Sortieren nach Startzeit (für Renderer)
     notes.sort_by(|a, b| a.start_time.partial_cmp(&b.start_time).unwrap_or(Ordering::Equal));
 
-    (notes, cur_time + 1.0)
+    (notes, end_time)
 }
 
-// =====================================================================
-// AUDIO-SYNTHESE (Intern)
-// =====================================================================
-
-fn synthesize_to_ram(notes: &[Note], duration: f64) -> Vec<i16> {
-    let total_samples = (duration * SAMPLE_RATE as f64) as usize;
-    let mut mix_buf = vec![0.0f32; total_samples];
+// Zeitpunkte aller Taktschläge in Sekunden bis `end_time`, jeweils
+// mit der Angabe, ob es sich um die Eins eines Taktes handelt.
+fn build_beat_grid(events: &[MidiEvent], division: u16,
+    tempo: Option<f64>, end_time: f64
+) -> Vec<(f64, bool)> {
+    // Bei SMPTE-Zeitbasis gibt es keinen Bezug zwischen Ticks und Schlägen
+    if smpte_seconds_per_tick(division).is_some() {
+        return Vec::new();
+    }
 
-    println!("Synthetisiere {} Noten ({:.1} s)...", notes.len(), duration);
+    let tempo_map = build_tempo_map(events, division);
+    let mut grid = Vec::new();
 
-    let overtones = [1.0, 0.5, 0.3, 0.1];
-    let release = 0.1;
+    // Vorgabe 4/4
+    let mut beats_per_bar = 4u32;
+    let mut beat_ticks = division as u32;
+    let mut next_beat_tick = 0u32;
+    let mut beat_in_bar = 0u32;
 
-    for n in notes {
-        let is_drum = n._channel == 9;
-        let freq = if is_drum { 100.0 } else {
-            440.0 * 2.0f64.powf((n.midi_key as f64 - 69.0) / 12.0)
-        };
-        let dur = if is_drum { 0.05 } else { n.duration };
-        let amp = (n._velocity as f64 / 127.0) * 0.3;
+    let tick_to_time = |tick: u32| tick_to_seconds(tick, &tempo_map, division, tempo);
 
-        let start_s = (n.start_time * SAMPLE_RATE as f64) as usize;
-        let len_s = ((dur + release) * SAMPLE_RATE as f64) as usize;
+    for e in events.iter().filter(|e| e.event_type == EventType::TimeSignature) {
+        // Schläge bis zur Änderung mit der bisherigen Taktart
+        while next_beat_tick < e.abs_tick {
+            grid.push((tick_to_time(next_beat_tick), beat_in_bar == 0));
+            beat_in_bar = (beat_in_bar + 1) % beats_per_bar;
+            next_beat_tick += beat_ticks;
+        }
 
-        for t in 0..len_s {
-            if start_s + t >= total_samples { break; }
+        // Neue Taktart beginnt mit einem neuen Takt
+        beats_per_bar = (e.note as u32).max(1);
+        beat_ticks = ((division as u32 * 4) >> e.velocity.min(6)).max(1);
+        next_beat_tick = e.abs_tick;
+        beat_in_bar = 0;
+    }
 
-            let time = t as f64 / SAMPLE_RATE as f64;
-            let mut val = 0.0;
+    loop {
+        let t = tick_to_time(next_beat_tick);
+        if t > end_time { break; }
+        grid.push((t, beat_in_bar == 0));
+        beat_in_bar = (beat_in_bar + 1) % beats_per_bar;
+        next_beat_tick += beat_ticks;
+    }
+    grid
+}
 
-            if is_drum {
-                val = (2.0 * PI * freq * time).sin();
-            } else {
-                for (i, ov) in overtones.iter().enumerate() {
-                    let h = freq * (i as f64 + 1.0);
-                    if h < SAMPLE_RATE as f64 / 2.0 {
-                        val += ov * (2.0 * PI * h * time).sin();
-                    }
-                }
-                val /= 1.9;
-            }
+// Tonartwechsel als (Zeit, Tonart), zeitlich sortiert
+fn build_key_changes(events: &[MidiEvent], division: u16,
+    tempo: Option<f64>
+) -> Vec<(f64, KeyInfo)> {
+    let tempo_map = build_tempo_map(events, division);
+    events.iter()
+        .filter(|e| e.event_type == EventType::KeySignature)
+        .map(|e| (tick_to_seconds(e.abs_tick, &tempo_map, division, tempo),
+            KeyInfo::from_midi(e.note as i8)))
+        .collect()
+}
 
-            // Envelope
-            let mut env = 1.0;
-            if time < 0.05 {
-                env = time / 0.05;
-            } else if time > dur {
-                env = 1.0 - ((time - dur) / release);
-            }
-            if env < 0.0 { env = 0.0; }
+// Tempo der MIDI-Datei in BPM ab dem jeweiligen Zeitpunkt, beginnend
+// mit der Vorgabe von 120 BPM. Leer bei SMPTE-Zeitbasis.
+fn build_tempo_changes(events: &[MidiEvent], division: u16,
+    tempo: Option<f64>
+) -> Vec<(f64, f64)> {
+    if smpte_seconds_per_tick(division).is_some() {
+        return Vec::new();
+    }
+    build_tempo_map(events, division).into_iter()
+        .map(|(_, seconds, micros_per_beat)|
+            (seconds / tempo.unwrap_or(1.0), 60_000_000.0 / micros_per_beat))
+        .collect()
+}
 
-            mix_buf[start_s + t] += (val * amp * env) as f32;
+// Zeitpunkte, zu denen sich ändert, ob auf irgendeinem Kanal das
+// Haltepedal getreten ist, als (Zeit, getreten), zeitlich sortiert
+fn build_pedal_changes(events: &[MidiEvent], division: u16,
+    tempo: Option<f64>
+) -> Vec<(f64, bool)> {
+    let tempo_map = build_tempo_map(events, division);
+    let mut down = [false; 16];
+    let mut changes: Vec<(f64, bool)> = Vec::new();
+    for e in events.iter().filter(|e| e.event_type == EventType::Sustain) {
+        down[e.channel as usize] = e.velocity >= 64;
+        let any = down.iter().any(|&d| d);
+        if changes.last().is_some_and(|&(_, last)| last) != any {
+            changes.push((tick_to_seconds(e.abs_tick, &tempo_map, division, tempo), any));
         }
     }
+    changes
+}
 
-    // Normalisieren und Konvertieren
-    let max_val = mix_buf.iter().fold(0.0f32, |m, &x| m.max(x.abs()));
-    let norm = if max_val > 0.0 { 32000.0 / max_val } else { 1.0 };
-    let norm = norm.min(32000.0);
-
-    mix_buf.into_iter().map(|v| (v * norm) as i16).collect()
+fn pedal_at(pedal_changes: &[(f64, bool)], current_time: f64) -> bool {
+    let idx = pedal_changes.partition_point(|&(t, _)| t <= current_time);
+    idx > 0 && pedal_changes[idx - 1].1
 }
 
-// =====================================================================
-// AUDIO-GENERIERUNG (Timidity-Pipe)
-// =====================================================================
+fn tempo_at(tempo_changes: &[(f64, f64)], current_time: f64) -> Option<f64> {
+    let idx = tempo_changes.partition_point(|&(t, _)| t <= current_time);
+    tempo_changes.get(idx.checked_sub(1)?).map(|&(_, bpm)| bpm)
+}
 
-fn generate_audio_with_timidity(midifile: &str, tempo: Option<f64>, transpose: i32)
--> Result<Vec<i16>, Box<dyn std::error::Error>>
-{
-    println!("Starte Timidity via Pipe (Raw PCM)...");
-    let tempo_opt = match tempo {
-        Some(tempo) => format!("{}", (tempo * 100.0) as u32),
-        None => "100".to_string()
-    };
-    let transpose_opt = format!("{}", transpose);
-    let output = Command::new("timidity")
-        .args(&[
-            midifile, "-Or", "-s", "44100", "-A160", "--preserve-silence",
-            "-T", &tempo_opt, "-K", &transpose_opt, "-o", "-"
-        ])
-        .stdout(Stdio::piped())
-        .output()?;
+// Letzte Tonart vor `current_time`, C-Dur vor dem ersten Wechsel
+fn key_at(key_changes: &[(f64, KeyInfo)], current_time: f64) -> KeyInfo {
+    let idx = key_changes.partition_point(|&(t, _)| t <= current_time);
+    if idx > 0 { key_changes[idx - 1].1 } else { KeyInfo(0, 0) }
+}
 
-    if !output.status.success() {
-        return Err("Timidity fehlgeschlagen (ist es installiert?)".into());
+// Wie `downbeat_flash`, aber auf jedem Schlag
+fn beat_pulse(beat_grid: &[(f64, bool)], current_time: f64) -> f32 {
+    let idx = beat_grid.partition_point(|&(t, _)| t <= current_time);
+    match idx.checked_sub(1).map(|i| beat_grid[i].0) {
+        Some(t) if current_time - t < BEAT_FLASH_DURATION => {
+            (1.0 - (current_time - t) / BEAT_FLASH_DURATION) as f32
+        },
+        _ => 0.0
     }
+}
 
-    let raw_data = output.stdout;
-    if raw_data.is_empty() {
-        return Err("Keine Daten von Timidity empfangen".into());
+// Helligkeit des Aufblinkens (0 bis 1), abklingend nach der letzten Eins
+fn downbeat_flash(beat_grid: &[(f64, bool)], current_time: f64) -> f32 {
+    let idx = beat_grid.partition_point(|&(t, _)| t <= current_time);
+    match beat_grid[..idx].iter().rev().find(|&&(_, downbeat)| downbeat) {
+        Some(&(t, _)) if current_time - t < BEAT_FLASH_DURATION => {
+            (1.0 - (current_time - t) / BEAT_FLASH_DURATION) as f32
+        },
+        _ => 0.0
     }
-
-    // Timidity Raw ist Stereo S16SYS, wir wollen Mono S16SYS
-    // Wir nutzen SDL AudioCVT für die Konvertierung
-    let target_format = if cfg!(target_endian = "little") {
-        sdl2::audio::AudioFormat::S16LSB
-    } else {
-        sdl2::audio::AudioFormat::S16MSB
-    };
-
-    let src_format = target_format;
-    let dst_format = target_format;
-    // Unser Zielformat (definiert im struct SoundProvider)
-    // (i16 im RAM ist auch native endian)
-
-    let cvt = AudioCVT::new(
-        src_format, 2, 44100,
-        dst_format, AUDIO_CHANNELS, SAMPLE_RATE
-    ).map_err(|e| format!("CVT Build Error: {}", e))?;
-
-    let output_samples = cvt.convert(raw_data);
-
-    // Vec<u8> zu Vec<i16>
-    // Sicherheitsannahme: System ist Little Endian, oder S16SYS passt.
-    // Ein cast über slices ist in Rust unsafe oder benötigt byteorder.
-    // Wir machen es sicher aber langsamer per chunks:
-    let i16_samples: Vec<i16> = output_samples
-        .chunks_exact(2)
-        .map(|c| i16::from_ne_bytes([c[0], c[1]]))
-        .collect();
-
-    println!("Audio von Timidity geladen: {} Samples", i16_samples.len());
-    Ok(i16_samples)
 }
 
 // =====================================================================
-// RENDER HELPERS
+// AUDIO-SYNTHESE (Intern)
 // =====================================================================
 
-const CORNER_TL: u8 = 1;
-const CORNER_TR: u8 = 2;
-const CORNER_BL: u8 = 4;
-const CORNER_BR: u8 = 8;
-const CORNER_ALL: u8 = 15;
+fn midi_to_freq(key: i32) -> f64 {
+    440.0 * 2.0f64.powf((key as f64 - 69.0) / 12.0)
+}
 
-fn fill_quarter_circle(
-    canvas: &mut Canvas<Window>, cx: i32, cy: i32,
-    r: i32, quadrant: u8
-) -> Result<(), String> {
-    for dy in 0..=r {
-        let dx = ((r * r - dy * dy) as f64).sqrt() as i32;
-        match quadrant {
-            0 => canvas.draw_line(Point::new(cx - dx, cy - dy), Point::new(cx, cy - dy))?, // TL
-            1 => canvas.draw_line(Point::new(cx, cy - dy), Point::new(cx + dx, cy - dy))?, // TR
-            2 => canvas.draw_line(Point::new(cx - dx, cy + dy), Point::new(cx, cy + dy))?, // BL
-            3 => canvas.draw_line(Point::new(cx, cy + dy), Point::new(cx + dx, cy + dy))?, // BR
-            _ => {},
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Wave {
+    Sine,
+    Square,
+    Sawtooth,
+    Triangle,
+    Noise // nur für Schlagzeug, siehe `drum_params`
+}
+
+impl Wave {
+    fn from_name(name: &str) -> Option<Wave> {
+        match name {
+            "sine" => Some(Wave::Sine),
+            "square" => Some(Wave::Square),
+            "saw" | "sawtooth" => Some(Wave::Sawtooth),
+            "triangle" => Some(Wave::Triangle),
+            _ => None
         }
     }
-    Ok(())
 }
 
-fn render_fill_rounded_rect(
-    canvas: &mut Canvas<Window>, x: i32, y: i32,
-    mut w: i32, mut h: i32, mut r: i32, corners: u8
-) -> Result<(), String> {
-    if r * 2 > w { r = w / 2; }
-    if r * 2 > h { r = h / 2; }
+// Obertonanteile je General-MIDI-Instrumentenfamilie (8 Programme pro
+// Familie), grob angenähert
+fn overtones_for_program(program: u8) -> &'static [f64] {
+    match program / 8 {
+        1 => &[1.0, 0.0, 0.4, 0.0, 0.2],                   // Chromatische Perkussion
+        2 => &[1.0, 0.8, 0.6, 0.0, 0.4, 0.0, 0.0, 0.3],    // Orgel
+        3 => &[1.0, 0.6, 0.4, 0.3, 0.2],                   // Gitarre
+        4 => &[1.0, 0.3, 0.1],                             // Bass
+        5 | 6 => &[1.0, 0.7, 0.5, 0.4, 0.3, 0.2, 0.15],    // Streicher, Ensemble
+        7 => &[1.0, 0.9, 0.8, 0.6, 0.5, 0.4, 0.3],         // Blechbläser
+        8 => &[1.0, 0.1, 0.6, 0.1, 0.4, 0.1, 0.2],         // Rohrblatt
+        9 => &[1.0, 0.2, 0.05],                            // Flöten
+        _ => &[1.0, 0.5, 0.3, 0.1]                         // Klavier und Rest
+    }
+}
 
-    // Sicherheit gegen negative Größen
-    if w < 0 { w = 0; }
-    if h < 0 { h = 0; }
+// Gewicht eines Obertons nach Anschlagstärke: Grundton und erster
+// Oberton klingen immer, die höheren kommen mit der Velocity hinzu,
+// lautere Noten klingen also heller
+fn overtone_weight(index: usize, velocity: i32) -> f64 {
+    if index < 2 { 1.0 } else { velocity as f64 / 127.0 }
+}
 
-    // 1. Vertikaler Mittelstreifen
-    canvas.fill_rect(Rect::new(x + r, y, (w - 2 * r) as u32, h as u32))?;
-    // 2. Seitenstreifen
-    canvas.fill_rect(Rect::new(x, y + r, r as u32, (h - 2 * r) as u32))?;
-    canvas.fill_rect(Rect::new(x + w - r, y + r, r as u32, (h - 2 * r) as u32))?;
+// Eine Periode der Wellenform für die normierte Phase in [0, 1)
+fn oscillator(kind: Wave, phase: f64) -> f64 {
+    match kind {
+        Wave::Sine => (2.0 * PI * phase).sin(),
+        Wave::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+        Wave::Sawtooth => 2.0 * (phase + 0.5).fract() - 1.0,
+        Wave::Triangle => 4.0 * ((phase + 0.75).fract() - 0.5).abs() - 1.0,
+        Wave::Noise => noise((phase * u32::MAX as f64) as u32)
+    }
+}
 
-    // 3. Ecken
-    // TL
-    if corners & CORNER_TL != 0 { fill_quarter_circle(canvas, x + r, y + r, r, 0)?; }
-    else { canvas.fill_rect(Rect::new(x, y, r as u32, r as u32))?; }
+// Pseudozufallswert in [-1, 1] aus einem Integer-Hash, damit dasselbe
+// Sample unabhängig von der Aufteilung in Abschnitte gleich klingt
+fn noise(seed: u32) -> f64 {
+    let mut x = seed.wrapping_mul(0x9E37_79B9);
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85EB_CA6B);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xC2B2_AE35);
+    x ^= x >> 16;
+    x as f64 / u32::MAX as f64 * 2.0 - 1.0
+}
 
-    // TR
-    if corners & CORNER_TR != 0 { fill_quarter_circle(canvas, x + w - r - 1, y + r, r, 1)?; }
-    else { canvas.fill_rect(Rect::new(x + w - r, y, r as u32, r as u32))?; }
+// Frequenz, Länge in Sekunden und Wellenform je General-MIDI-Schlagzeug-
+// taste. Bei Rauschen bestimmt die Frequenz, wie oft ein neuer Zufalls-
+// wert gezogen wird (also die Helligkeit). Unbekannte Tasten bleiben
+// ein kurzer 100-Hz-Klick.
+fn drum_params(note: u8) -> (f64, f64, Wave) {
+    match note {
+        35 => (55.0, 0.15, Wave::Sine),          // Akustische Bassdrum
+        36 => (60.0, 0.15, Wave::Sine),          // Bassdrum
+        37 => (400.0, 0.03, Wave::Triangle),     // Rimshot (Side Stick)
+        38 => (3000.0, 0.12, Wave::Noise),       // Akustische Snare
+        39 => (1200.0, 0.08, Wave::Noise),       // Händeklatschen
+        40 => (4000.0, 0.1, Wave::Noise),        // Elektrische Snare
+        41 => (90.0, 0.2, Wave::Sine),           // Tiefes Standtom
+        43 => (110.0, 0.2, Wave::Sine),          // Hohes Standtom
+        45 => (130.0, 0.18, Wave::Sine),         // Tiefes Tom
+        47 => (160.0, 0.18, Wave::Sine),         // Mittleres Tom
+        48 => (190.0, 0.15, Wave::Sine),         // Hohes mittleres Tom
+        50 => (220.0, 0.15, Wave::Sine),         // Hohes Tom
+        42 => (9000.0, 0.04, Wave::Noise),       // Geschlossene Hi-Hat
+        44 => (9000.0, 0.06, Wave::Noise),       // Pedal-Hi-Hat
+        46 => (9000.0, 0.25, Wave::Noise),       // Offene Hi-Hat
+        49 | 57 => (7000.0, 0.6, Wave::Noise),   // Crash-Becken
+        51 | 59 => (6000.0, 0.35, Wave::Noise),  // Ride-Becken
+        53 => (2400.0, 0.3, Wave::Triangle),     // Ride-Glocke
+        54 => (8000.0, 0.12, Wave::Noise),       // Tamburin
+        56 => (800.0, 0.15, Wave::Square),       // Kuhglocke
+        _ => (100.0, 0.05, Wave::Sine)
+    }
+}
 
-    // BL
-    if corners & CORNER_BL != 0 { fill_quarter_circle(canvas, x + r, y + h - r - 1, r, 2)?; }
-    else { canvas.fill_rect(Rect::new(x, y + h - r, r as u32, r as u32))?; }
+// Folgt dem Pitch-Bend-Verlauf einer Note und liefert das Phaseninkrement
+// für jedes Sample. Ohne Pitch Bend ist es immer `base_inc`.
+struct BendCursor<'a> {
+    bends: &'a [(f64, f64)],
+    next: usize,
+    base_inc: f64,
+    range: f64,
+    inc: f64
+}
+
+impl<'a> BendCursor<'a> {
+    fn new(bends: &'a [(f64, f64)], base_inc: f64, range: f64) -> Self {
+        BendCursor { bends, next: 0, base_inc, range, inc: base_inc }
+    }
+
+    // `time` in Sekunden ab Notenbeginn, darf nicht kleiner werden
+    fn phase_inc(&mut self, time: f64) -> f64 {
+        while self.next < self.bends.len() && self.bends[self.next].0 <= time {
+            let semitones = self.bends[self.next].1 * self.range;
+            self.inc = self.base_inc * 2.0f64.powf(semitones / 12.0);
+            self.next += 1;
+        }
+        self.inc
+    }
+}
+
+// Klang des Spielmodus: wie eine Klaviernote in `mix_notes` mit
+// mittlerer Anschlagstärke
+fn live_oscillator(wave: Wave, phase: f64) -> f64 {
+    if wave != Wave::Sine { return oscillator(wave, phase); }
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    for (i, ov) in overtones_for_program(0).iter().enumerate() {
+        let weight = ov * overtone_weight(i, 100);
+        sum += weight * oscillator(Wave::Sine, (phase * (i + 1) as f64).fract());
+        total += weight;
+    }
+    sum / total
+}
+
+// Mischt alle Noten in `buf`, das die Samples ab Index `offset` enthält.
+// Jeder Abschnitt rechnet die Noten in derselben Reihenfolge, daher ist
+// das Ergebnis unabhängig davon, wie der Puffer aufgeteilt wird.
+fn mix_notes(notes: &[Note], channel: i32, wave: Wave, bend_range: f64,
+    rate: i32, offset: usize, buf: &mut [f32]
+) {
+    let release = NOTE_RELEASE;
+    let buf_end = offset + buf.len();
+    let rate = rate as f64;
+
+    for n in notes {
+        if n.channel != channel { continue; }
+        let is_drum = n.channel == 9;
+        let (freq, dur, drum_wave) = if is_drum {
+            drum_params(n.midi_key as u8)
+        } else {
+            (midi_to_freq(n.midi_key), n.duration, wave)
+        };
+        let amp = (n.velocity as f64 / 127.0) * 0.3;
+        let overtones = overtones_for_program(n.program);
+        let overtone_sum: f64 = overtones.iter().enumerate()
+            .map(|(i, ov)| ov * overtone_weight(i, n.velocity))
+            .sum();
+
+        let start_s = (n.start_time * rate) as usize;
+        let tail = if is_drum { 0.0 } else { release };
+        let len_s = ((dur + tail) * rate) as usize;
+
+        // Anteil der Note in diesem Abschnitt
+        let first = start_s.max(offset);
+        let last = (start_s + len_s).min(buf_end);
+        if first >= last { continue; }
+
+        // Phasenakkumulator (auf eine Periode normiert). Ohne Pitch Bend
+        // ergibt sich die Phase direkt aus der Zeit. Sonst wird sie, wenn
+        // die Note in einem früheren Abschnitt beginnt, Sample für Sample
+        // nachgeführt, damit sie exakt übereinstimmt.
+        let bends: &[(f64, f64)] = if is_drum { &[] } else { &n.bends };
+        let mut bend = BendCursor::new(bends, freq / rate, bend_range);
+        let mut phase = 0.0;
+        if !bends.is_empty() {
+            for t in 0..(first - start_s) {
+                phase = (phase + bend.phase_inc(t as f64 / rate)).fract();
+            }
+        }
+
+        for t in (first - start_s)..(last - start_s) {
+            let time = t as f64 / rate;
+            let phase_inc = bend.phase_inc(time);
+            if bends.is_empty() {
+                phase = (t as f64 * phase_inc).fract();
+            }
+            let freq = phase_inc * rate;
+
+            let val = if drum_wave == Wave::Noise {
+                // Sample & Hold mit doppelter Frequenz
+                noise((time * 2.0 * freq) as u32)
+            } else if is_drum {
+                oscillator(drum_wave, phase)
+            } else if wave == Wave::Sine {
+                let mut sum = 0.0;
+                for (i, ov) in overtones.iter().enumerate() {
+                    let harmonic = i as f64 + 1.0;
+                    if freq * harmonic < rate / 2.0 {
+                        sum += ov * overtone_weight(i, n.velocity)
+                            * oscillator(Wave::Sine, (phase * harmonic).fract());
+                    }
+                }
+                sum / overtone_sum
+            } else {
+                oscillator(wave, phase)
+            };
+            phase = (phase + phase_inc).fract();
+
+            // Envelope; Schlagzeug: kurzer Anstieg, dann linear abklingend
+            let mut env = 1.0;
+            if is_drum {
+                env = if time < 0.002 {
+                    time / 0.002
+                } else {
+                    1.0 - (time - 0.002) / (dur - 0.002)
+                };
+            } else if time < 0.05 {
+                env = time / 0.05;
+            } else if time > dur {
+                env = 1.0 - ((time - dur) / release);
+            }
+            if env < 0.0 { env = 0.0; }
+
+            buf[start_s + t - offset] += (val * amp * env) as f32;
+        }
+    }
+}
+
+// Mischt die Noten eines Kanals in `buf`, bei mehreren Threads in
+// zusammenhängenden Abschnitten parallel
+fn render_channel(notes: &[Note], channel: i32, wave: Wave, threads: usize,
+    bend_range: f64, rate: i32, buf: &mut [f32]
+) {
+    buf.fill(0.0);
+    if threads <= 1 {
+        mix_notes(notes, channel, wave, bend_range, rate, 0, buf);
+    } else {
+        let chunk_len = buf.len().div_ceil(threads).max(1);
+        std::thread::scope(|scope| {
+            for (i, chunk) in buf.chunks_mut(chunk_len).enumerate() {
+                scope.spawn(move || {
+                    mix_notes(notes, channel, wave, bend_range, rate, i * chunk_len, chunk)
+                });
+            }
+        });
+    }
+}
+
+// Blendet die ersten und letzten Millisekunden ein bzw. aus, damit das
+// Audio bei null beginnt und endet und nicht knackt
+fn declick(buf: &mut [f32], rate: i32) {
+    let fade = (DECLICK_SECONDS * rate as f64) as usize;
+    let len = buf.len();
+    for (i, sample) in buf.iter_mut().enumerate() {
+        let edge = i.min(len - 1 - i);
+        if edge < fade {
+            *sample *= edge as f32 / fade as f32;
+        }
+    }
+}
+
+// Synthetisiert eine Spur pro verwendetem Kanal. Normalisiert wird auf
+// den Gesamtmix, die Lautstärke ändert sich beim Stummschalten also nicht.
+fn synthesize_stems(notes: &[Note], duration: f64, wave: Wave, threads: usize,
+    bend_range: f64, rate: i32
+) -> Vec<Stem> {
+    let total_samples = (duration * rate as f64) as usize;
+    let mut mix_buf = vec![0.0f32; total_samples];
+    let mut stem_buf = vec![0.0f32; total_samples];
+
+    println!("Synthetisiere {} Noten ({:.1} s)...", notes.len(), duration);
+
+    let mut channels: Vec<i32> = notes.iter().map(|n| n.channel).collect();
+    channels.sort_unstable();
+    channels.dedup();
+
+    let mut stems = Vec::with_capacity(channels.len());
+    for channel in channels {
+        render_channel(notes, channel, wave, threads, bend_range, rate, &mut stem_buf);
+        declick(&mut stem_buf, rate);
+        for (m, &v) in mix_buf.iter_mut().zip(&stem_buf) {
+            *m += v;
+        }
+
+        let peak = stem_buf.iter().fold(0.0f32, |m, &x| m.max(x.abs()));
+        let scale = if peak > 0.0 { 32000.0 / peak } else { 1.0 };
+        stems.push(Stem {
+            channel: Some(channel),
+            gain: peak / 32000.0,
+            samples: stem_buf.iter().map(|&v| (v * scale) as i16).collect()
+        });
+    }
+
+    // Normalisieren
+    let max_val = mix_buf.iter().fold(0.0f32, |m, &x| m.max(x.abs()));
+    let norm = if max_val > 0.0 { 32000.0 / max_val } else { 1.0 };
+    let norm = norm.min(32000.0);
+    for stem in &mut stems {
+        stem.gain *= norm;
+    }
+    stems
+}
+
+// =====================================================================
+// AUDIO-GENERIERUNG (Timidity-Pipe)
+// =====================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resample {
+    Linear,
+    Cubic
+}
+
+// Rechnet ein Mono-Signal von `src_rate` auf `dst_rate` um.
+fn resample(src: &[f32], src_rate: i32, dst_rate: i32, kind: Resample) -> Vec<f32> {
+    if src_rate == dst_rate || src.is_empty() {
+        return src.to_vec();
+    }
+    let step = src_rate as f64 / dst_rate as f64;
+    let out_len = (src.len() as f64 / step) as usize;
+    let last = src.len() as isize - 1;
+    let at = |i: isize| src[i.clamp(0, last) as usize];
+
+    (0..out_len).map(|k| {
+        let pos = k as f64 * step;
+        let i = pos.floor() as isize;
+        let t = (pos - i as f64) as f32;
+        match kind {
+            Resample::Linear => {
+                at(i) + (at(i + 1) - at(i)) * t
+            },
+            Resample::Cubic => {
+                // Catmull-Rom-Spline durch vier Stützstellen
+                let (y0, y1, y2, y3) = (at(i - 1), at(i), at(i + 1), at(i + 2));
+                y1 + 0.5 * t * (y2 - y0
+                    + t * (2.0 * y0 - 5.0 * y1 + 4.0 * y2 - y3
+                    + t * (3.0 * (y1 - y2) + y3 - y0)))
+            }
+        }
+    }).collect()
+}
+
+fn generate_audio_with_timidity(midifile: &str, tempo: Option<f64>, transpose: i32,
+    rate: i32, resample_kind: Resample
+) -> Result<Vec<i16>, Box<dyn std::error::Error>>
+{
+    println!("Starte Timidity via Pipe (Raw PCM)...");
+    let tempo_opt = match tempo {
+        Some(tempo) => format!("{}", (tempo * 100.0) as u32),
+        None => "100".to_string()
+    };
+    let transpose_opt = format!("{}", transpose);
+    // Timidity rechnet direkt mit der gewählten Rate, soweit sie das kann
+    let timidity_rate = rate.clamp(TIMIDITY_RATE_MIN, TIMIDITY_RATE_MAX);
+    let rate_opt = format!("{}", timidity_rate);
+    let output = Command::new("timidity")
+        .args([
+            midifile, "-Or", "-s", &rate_opt, "-A160", "--preserve-silence",
+            "-T", &tempo_opt, "-K", &transpose_opt, "-o", "-"
+        ])
+        .stdout(Stdio::piped())
+        .output()?;
+
+    if !output.status.success() {
+        return Err("Timidity fehlgeschlagen (ist es installiert?)".into());
+    }
+
+    let raw_data = output.stdout;
+    if raw_data.is_empty() {
+        return Err("Keine Daten von Timidity empfangen".into());
+    }
+
+    // Timidity Raw ist Stereo S16SYS (native endian), wir wollen Mono.
+    // Heruntermischen durch Mittelwert beider Kanäle.
+    let mono: Vec<f32> = raw_data
+        .chunks_exact(4)
+        .map(|c| {
+            let l = i16::from_ne_bytes([c[0], c[1]]) as f32;
+            let r = i16::from_ne_bytes([c[2], c[3]]) as f32;
+            (l + r) * 0.5
+        })
+        .collect();
+
+    // Abtastrate anpassen, falls sie von unserer abweicht
+    let mut resampled = resample(&mono, timidity_rate, rate, resample_kind);
+    declick(&mut resampled, rate);
+    let i16_samples: Vec<i16> = resampled
+        .into_iter()
+        .map(|v| v.clamp(-32768.0, 32767.0) as i16)
+        .collect();
+
+    println!("Audio von Timidity geladen: {} Samples", i16_samples.len());
+    Ok(i16_samples)
+}
+
+// =====================================================================
+// RENDER HELPERS
+// =====================================================================
+
+const CORNER_TL: u8 = 1;
+const CORNER_TR: u8 = 2;
+const CORNER_BL: u8 = 4;
+const CORNER_BR: u8 = 8;
+const CORNER_ALL: u8 = 15;
+
+// Unterabtastungen je Achse für die Kantenglättung der Ecken
+const CORNER_SUBSAMPLES: i32 = 4;
+
+// Anteil (0.0 bis 1.0), zu dem das Pixel im Abstand (dx, dy) vom Mittel-
+// punkt im Viertelkreis mit Radius r liegt. Der Kreis reicht bis an die
+// Außenkante des Pixels r, wie bei der früheren Zeilenfüllung.
+fn corner_coverage(dx: i32, dy: i32, r: i32) -> f64 {
+    let n = CORNER_SUBSAMPLES;
+    let limit = (r as f64 + 0.5).powi(2);
+    let mut inside = 0;
+    for sy in 0..n {
+        let y = dy as f64 - 0.5 + (sy as f64 + 0.5) / n as f64;
+        for sx in 0..n {
+            let x = dx as f64 - 0.5 + (sx as f64 + 0.5) / n as f64;
+            if x * x + y * y <= limit {
+                inside += 1;
+            }
+        }
+    }
+    inside as f64 / (n * n) as f64
+}
+
+fn fill_quarter_circle(
+    canvas: &mut Canvas<Window>, cx: i32, cy: i32,
+    r: i32, quadrant: u8
+) -> Result<(), String> {
+    let (sx, sy) = match quadrant {
+        0 => (-1, -1), // TL
+        1 => (1, -1),  // TR
+        2 => (-1, 1),  // BL
+        3 => (1, 1),   // BR
+        _ => return Ok(()),
+    };
+    let color = canvas.draw_color();
+    let blend = canvas.blend_mode();
+
+    // Zeile und Spalte durch den Mittelpunkt liegen ganz auf den Streifen
+    // von `render_fill_rounded_rect`. Bei halbtransparenter Farbe würden
+    // sie dort doppelt gemischt und als dunklere Linien sichtbar.
+    let first = if color.a < 255 { 1 } else { 0 };
+
+    for dy in first..=r {
+        // Voll abgedeckte Pixel als Linie, den Rand halbtransparent
+        let mut full = first;
+        while full <= r && corner_coverage(full, dy, r) >= 1.0 {
+            full += 1;
+        }
+        if full > first {
+            canvas.draw_line(Point::new(cx + sx * first, cy + sy * dy),
+                Point::new(cx + sx * (full - 1), cy + sy * dy))?;
+        }
+
+        canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+        for dx in full..=r {
+            let coverage = corner_coverage(dx, dy, r);
+            if coverage <= 0.0 {
+                break;
+            }
+            let alpha = (color.a as f64 * coverage).round() as u8;
+            canvas.set_draw_color(Color::RGBA(color.r, color.g, color.b, alpha));
+            canvas.draw_point(Point::new(cx + sx * dx, cy + sy * dy))?;
+        }
+        canvas.set_draw_color(color);
+        canvas.set_blend_mode(blend);
+    }
+    Ok(())
+}
+
+// Rect::new macht aus Breite oder Höhe 0 ein Pixel; leere Streifen
+// würden also neben das Rechteck gezeichnet
+fn fill_rect_nonempty(canvas: &mut Canvas<Window>, x: i32, y: i32, w: i32, h: i32
+) -> Result<(), String> {
+    if w <= 0 || h <= 0 { return Ok(()); }
+    canvas.fill_rect(Rect::new(x, y, w as u32, h as u32))
+}
+
+fn render_fill_rounded_rect(
+    canvas: &mut Canvas<Window>, x: i32, y: i32,
+    mut w: i32, mut h: i32, mut r: i32, corners: u8
+) -> Result<(), String> {
+    if r * 2 > w { r = w / 2; }
+    if r * 2 > h { r = h / 2; }
+
+    // Sicherheit gegen negative Größen
+    if w < 0 { w = 0; }
+    if h < 0 { h = 0; }
+
+    // 1. Vertikaler Mittelstreifen
+    fill_rect_nonempty(canvas, x + r, y, w - 2 * r, h)?;
+    // 2. Seitenstreifen
+    fill_rect_nonempty(canvas, x, y + r, r, h - 2 * r)?;
+    fill_rect_nonempty(canvas, x + w - r, y + r, r, h - 2 * r)?;
+
+    // 3. Ecken. Jeder Viertelkreis hat seinen Mittelpunkt auf dem
+    // äußersten Pixel der angrenzenden Streifen (erste bzw. letzte Spalte
+    // des Mittelstreifens, erste bzw. letzte Zeile der Seitenstreifen) und
+    // überlappt sie dadurch um ein Pixel, damit keine Fuge bleibt; bei
+    // halbtransparenter Farbe schließt er bündig an (siehe
+    // `fill_quarter_circle`).
+    let (left, right) = (x + r, x + w - r - 1);
+    let (top, bottom) = (y + r, y + h - r - 1);
+
+    // TL
+    if corners & CORNER_TL != 0 { fill_quarter_circle(canvas, left, top, r, 0)?; }
+    else { fill_rect_nonempty(canvas, x, y, r, r)?; }
+
+    // TR
+    if corners & CORNER_TR != 0 { fill_quarter_circle(canvas, right, top, r, 1)?; }
+    else { fill_rect_nonempty(canvas, right + 1, y, r, r)?; }
+
+    // BL
+    if corners & CORNER_BL != 0 { fill_quarter_circle(canvas, left, bottom, r, 2)?; }
+    else { fill_rect_nonempty(canvas, x, bottom + 1, r, r)?; }
 
     // BR
-    if corners & CORNER_BR != 0 { fill_quarter_circle(canvas, x + w - r - 1, y + h - r - 1, r, 3)?; }
-    else { canvas.fill_rect(Rect::new(x + w - r, y + h - r, r as u32, r as u32))?; }
+    if corners & CORNER_BR != 0 { fill_quarter_circle(canvas, right, bottom, r, 3)?; }
+    else { fill_rect_nonempty(canvas, right + 1, bottom + 1, r, r)?; }
 
     Ok(())
 }
@@ -675,29 +1864,51 @@ fn handle_input(env: &mut Env) -> ControlFlow<()> {
             Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                 return ControlFlow::Break(());
             },
-            Event::KeyDown { keycode: Some(k), .. } => {
+            // SPIELMODUS: Tasten mit Notenbelegung spielen statt zu steuern
+            Event::KeyDown { scancode: Some(sc), .. }
+            if env.live_mode && live_key(sc).is_some() => {
+                play_live(env, live_key(sc).unwrap(), true);
+            },
+            Event::KeyUp { scancode: Some(sc), .. } if env.live_mode => {
+                if let Some(key) = live_key(sc) { play_live(env, key, false); }
+            },
+            Event::KeyDown { keycode: Some(k), keymod, .. } => {
+                let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
                 match k {
                     // PAUSE / PLAY
                     Keycode::Space | Keycode::K => {
                         env.paused = !env.paused;
                         if env.paused {
                             env.pause_start_time = Instant::now();
-                            env.device.pause();
+                            env.device.lock().playing = false;
                         } else {
                             // Die Zeit, die wir pausiert waren, auf den Start-Zeitpunkt addieren,
                             // damit der Song nicht visuell nach vorne springt.
                             let paused_duration = Instant::now().duration_since(env.pause_start_time);
                             env.start_instant += paused_duration;
-                            env.device.resume();
+                            env.device.lock().playing = true;
                         }
                     },
+                    // TEMPO
+                    // Auf deutscher Tastatur liegt '<' auf eigener Taste ('>' mit Shift),
+                    // auf US-Tastatur auf Shift+Komma bzw. Shift+Punkt.
+                    Keycode::Less | Keycode::Greater | Keycode::Comma | Keycode::Period
+                    if shift || k == Keycode::Less || k == Keycode::Greater => {
+                        let faster = k == Keycode::Greater || k == Keycode::Period
+                            || (k == Keycode::Less && shift);
+                        let factor = if faster {TEMPO_STEP} else {1.0 / TEMPO_STEP};
+                        let base = env.pending_tempo.unwrap_or(env.tempo);
+                        env.pending_tempo = Some((base * factor).clamp(TEMPO_MIN, TEMPO_MAX));
+                    },
                     // SPULEN
                     Keycode::Left | Keycode::J | Keycode::Right | Keycode::L |
                     Keycode::Comma | Keycode::Period => {
-                        let jump = Duration::from_secs(
-                            if k == Keycode::Comma || k == Keycode::Period {1}
-                            else if k == Keycode::Left || k == Keycode::Right {4}
-                            else {10});
+                        let is_arrow = k == Keycode::Left || k == Keycode::Right;
+                        let jump = Duration::from_secs_f64(
+                            if k == Keycode::Comma || k == Keycode::Period {1.0}
+                            else if is_arrow && shift {SEEK_FINE}
+                            else if is_arrow {4.0}
+                            else {10.0});
                         let is_forward = k == Keycode::Right || k == Keycode::L || k == Keycode::Period;
 
                         if !is_forward {
@@ -736,7 +1947,7 @@ fn handle_input(env: &mut Env) -> ControlFlow<()> {
                         };
 
                         // Cursor berechnen
-                        let mut new_cursor = (new_time_secs * SAMPLE_RATE as f64) as usize;
+                        let mut new_cursor = (new_time_secs * env.sample_rate as f64) as usize;
 
                         // Bounds Check
                         // Da pcm_buffer in 'device' gemoved wurde, kennen wir die Länge hier eigentlich nicht direkt,
@@ -744,7 +1955,7 @@ fn handle_input(env: &mut Env) -> ControlFlow<()> {
                         // Oben haben wir `pcm_buffer` an den SoundProvider übergeben.
                         // Lösung: Wir greifen über den Lock auf die Samples zu.
                         let mut lock = env.device.lock();
-                        let total_len = lock.samples.len();
+                        let total_len = lock.len();
 
                         if new_cursor >= total_len { new_cursor = total_len.saturating_sub(1); }
 
@@ -766,6 +1977,86 @@ fn handle_input(env: &mut Env) -> ControlFlow<()> {
                     Keycode::S => {
                         env.view_mode = (env.view_mode + 1) % 3;
                     },
+                    Keycode::D => {
+                        env.debug_notes = !env.debug_notes;
+                        env.debug_active.clear();
+                    },
+                    Keycode::M => {
+                        env.beat_flash = !env.beat_flash;
+                    },
+                    Keycode::R => {
+                        env.ripple = !env.ripple;
+                    },
+                    // SCHLEIFE A-B
+                    Keycode::LeftBracket | Keycode::A => {
+                        let (_, current_time) = calculate_time(env);
+                        env.loop_a = Some(current_time);
+                        order_loop(env);
+                        println!("Schleife A: {:.2} s", current_time);
+                    },
+                    Keycode::RightBracket | Keycode::B => {
+                        let (_, current_time) = calculate_time(env);
+                        env.loop_b = Some(current_time);
+                        order_loop(env);
+                        println!("Schleife B: {:.2} s", current_time);
+                    },
+                    // ZOOM DER ZEITACHSE
+                    Keycode::Plus | Keycode::Equals | Keycode::KpPlus |
+                    Keycode::Minus | Keycode::KpMinus => {
+                        let factor = if k == Keycode::Minus || k == Keycode::KpMinus {
+                            1.0 / ZOOM_STEP
+                        } else {
+                            ZOOM_STEP
+                        };
+                        env.pixels_per_second = (env.pixels_per_second * factor)
+                            .clamp(PIXELS_PER_SECOND_MIN, PIXELS_PER_SECOND_MAX);
+                    },
+                    // KANÄLE STUMM / SOLO
+                    Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4 |
+                    Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8 |
+                    Keycode::Num9 | Keycode::Num0 => {
+                        let ch = digit_channel(k);
+                        if shift {
+                            let is_solo = (0..16).all(|c| env.muted[c] == (c != ch));
+                            for c in 0..16 {
+                                env.muted[c] = !is_solo && c != ch;
+                            }
+                        } else {
+                            env.muted[ch] = !env.muted[ch];
+                        }
+                        env.device.lock().muted = env.muted;
+                        let muted: Vec<String> = (0..16).filter(|&c| env.muted[c])
+                            .map(|c| (c + 1).to_string()).collect();
+                        println!("Stumm: {}", if muted.is_empty() { "-".to_string() } else { muted.join(", ") });
+                    },
+                    Keycode::P => {
+                        env.show_progress = !env.show_progress;
+                    },
+                    Keycode::W => {
+                        env.show_scope = !env.show_scope;
+                        let len = if env.show_scope {
+                            (env.sample_rate as f64 * SCOPE_SECONDS) as usize
+                        } else {
+                            0
+                        };
+                        let mut lock = env.device.lock();
+                        lock.recent = vec![0; len];
+                        lock.recent_pos = 0;
+                    },
+                    Keycode::Tab => {
+                        env.live_mode = !env.live_mode;
+                        if !env.live_mode {
+                            for key in 0..128 {
+                                if env.live_keys[key] { play_live(env, key as i32, false); }
+                            }
+                        }
+                        println!("Spielmodus: {}", if env.live_mode { "an" } else { "aus" });
+                    },
+                    Keycode::Backspace => {
+                        env.loop_a = None;
+                        env.loop_b = None;
+                        println!("Schleife aufgehoben");
+                    },
                     _ => {}
                 }
             }
@@ -775,6 +2066,110 @@ fn handle_input(env: &mut Env) -> ControlFlow<()> {
     ControlFlow::Continue(())
 }
 
+// Spielmodus: Tastenposition zu MIDI-Note. Die Scancodes bezeichnen die
+// Lage auf einer US-Tastatur, damit die Belegung auf jedem Layout gleich
+// liegt: untere Buchstabenreihe weiße, Reihe darüber schwarze Tasten ab
+// C4, dasselbe eine Oktave höher mit Q-Reihe und Ziffernreihe.
+fn live_key(sc: Scancode) -> Option<i32> {
+    let offset = match sc {
+        Scancode::Z => 0, Scancode::S => 1, Scancode::X => 2, Scancode::D => 3,
+        Scancode::C => 4, Scancode::V => 5, Scancode::G => 6, Scancode::B => 7,
+        Scancode::H => 8, Scancode::N => 9, Scancode::J => 10, Scancode::M => 11,
+        Scancode::Comma | Scancode::Q => 12, Scancode::Num2 => 13, Scancode::W => 14,
+        Scancode::Num3 => 15, Scancode::E => 16, Scancode::R => 17, Scancode::Num5 => 18,
+        Scancode::T => 19, Scancode::Num6 => 20, Scancode::Y => 21, Scancode::Num7 => 22,
+        Scancode::U => 23, Scancode::I => 24,
+        _ => return None
+    };
+    Some(LIVE_BASE_KEY + offset)
+}
+
+// Schlägt eine Note im Spielmodus an bzw. lässt sie los. Die
+// Tastenwiederholung des Systems wird dabei ignoriert.
+fn play_live(env: &mut Env, key: i32, pressed: bool) {
+    if env.live_keys[key as usize] == pressed { return; }
+    env.live_keys[key as usize] = pressed;
+    let mut lock = env.device.lock();
+    if pressed { lock.note_on(key); } else { lock.note_off(key); }
+}
+
+// Ziffertaste zu Kanalindex: 1 bis 9 sind Kanal 1 bis 9, 0 ist Kanal 10
+// (Schlagzeug)
+fn digit_channel(k: Keycode) -> usize {
+    match k {
+        Keycode::Num1 => 0, Keycode::Num2 => 1, Keycode::Num3 => 2,
+        Keycode::Num4 => 3, Keycode::Num5 => 4, Keycode::Num6 => 5,
+        Keycode::Num7 => 6, Keycode::Num8 => 7, Keycode::Num9 => 8,
+        _ => 9
+    }
+}
+
+// Stand für --resume, Zeiten beim Originaltempo. Wer bis zum Ende gehört
+// hat, beginnt beim nächsten Mal wieder von vorn.
+fn current_session(env: &Env) -> Session {
+    let (_, current_time) = calculate_time(env);
+    let position = if current_time >= env.end_limit { 0.0 } else { current_time };
+    Session {
+        position: position * env.tempo,
+        tempo: env.tempo,
+        view_mode: env.view_mode,
+        pixels_per_second: env.pixels_per_second,
+        muted: env.muted,
+        loop_a: env.loop_a.map(|a| a * env.tempo),
+        loop_b: env.loop_b.map(|b| b * env.tempo)
+    }
+}
+
+// Ein abweichendes Tempo wird wie per Taste angefordert; der Tempowechsel
+// im Hauptprogramm rechnet Position und Schleife dann mit um.
+fn apply_session(env: &mut Env, session: &Session) {
+    env.view_mode = session.view_mode;
+    env.pixels_per_second = session.pixels_per_second
+        .clamp(PIXELS_PER_SECOND_MIN, PIXELS_PER_SECOND_MAX);
+    env.muted = session.muted;
+    env.device.lock().muted = session.muted;
+    env.loop_a = session.loop_a.map(|a| a / env.tempo);
+    env.loop_b = session.loop_b.map(|b| b / env.tempo);
+    order_loop(env);
+    seek_to(env, session.position / env.tempo);
+    if session.tempo != env.tempo {
+        env.pending_tempo = Some(session.tempo.clamp(TEMPO_MIN, TEMPO_MAX));
+    }
+}
+
+// Vertauscht A und B, falls B vor A gesetzt wurde
+fn order_loop(env: &mut Env) {
+    if let (Some(a), Some(b)) = (env.loop_a, env.loop_b) && b < a {
+        env.loop_a = Some(b);
+        env.loop_b = Some(a);
+    }
+}
+
+// Springt zurück auf A, sobald die Wiedergabe B erreicht.
+// Gibt true zurück, wenn gesprungen wurde.
+fn handle_loop(env: &mut Env, current_time: f64) -> bool {
+    match (env.loop_a, env.loop_b) {
+        (Some(a), Some(b)) if b > a && current_time >= b => {
+            seek_to(env, a);
+            true
+        },
+        _ => false
+    }
+}
+
+// Setzt die Wiedergabe auf die angegebene Zeit (in Sekunden),
+// visuell wie auch den Audio-Cursor.
+fn seek_to(env: &mut Env, time: f64) {
+    let time = time.clamp(0.0, env.end_limit);
+    let ref_time = if env.paused { env.pause_start_time } else { Instant::now() };
+    env.start_instant = ref_time.checked_sub(Duration::from_secs_f64(time))
+        .unwrap_or(ref_time);
+
+    let mut lock = env.device.lock();
+    let total_len = lock.len();
+    lock.cursor = ((time * env.sample_rate as f64) as usize).min(total_len.saturating_sub(1));
+}
+
 // =====================================================================
 // Grafik-Ausgabe
 // =====================================================================
@@ -805,6 +2200,38 @@ impl RenderView {
     pub fn height(&self) -> i32 { self.rect.height() as i32 }
 }
 
+// Pixelzeilen [oben, unten) des Blocks einer fallenden Note im Noten-
+// bereich und ob sie gerade klingt. Die Unterkante erreicht die Tastatur-
+// kante (`note_area_h`) genau zur Startzeit und wird danach dort abge-
+// schnitten, statt in die Tastatur zu laufen; vorher bleibt sie mindes-
+// tens eine Zeile darüber. Bei hoher Geschwindigkeit legt ein Block
+// zwischen zwei Bildern viele Pixel zurück; so berührt er die Tastatur
+// trotzdem genau in den Bildern, in denen die Taste leuchtet und der
+// Ton erklingt. Nach dem Ende bleibt an der Kante ein Rest stehen, der
+// innerhalb von `past_seconds` auf die Höhe null schrumpft.
+fn note_block_rows(start: f64, duration: f64, current_time: f64, pixels_per_second: f64,
+    note_area_h: i32, past_seconds: f64
+) -> (i32, i32, bool) {
+    let y = |t: f64| note_area_h as f64 - (t - current_time) * pixels_per_second;
+    let started = current_time >= start;
+    let is_playing = started && current_time < start + duration;
+    if started && !is_playing {
+        let left = if past_seconds > 0.0 {
+            (1.0 - (current_time - start - duration) / past_seconds).max(0.0)
+        } else {
+            0.0
+        };
+        return (note_area_h - (PAST_TRAIL_HEIGHT * left).ceil() as i32, note_area_h, false);
+    }
+    let bottom = if started {
+        note_area_h
+    } else {
+        (y(start).floor() as i32).min(note_area_h - 1)
+    };
+    let top = (y(start + duration).floor() as i32).min(bottom);
+    (top, bottom, is_playing)
+}
+
 fn render_notes(env: &mut Env, notes: &Vec<Note>,
     w: i32, note_area_h: i32,
     current_time: f64, lookahead_time: f64,
@@ -813,26 +2240,26 @@ fn render_notes(env: &mut Env, notes: &Vec<Note>,
     // Noten Zeichnen
     for n in notes {
         if n.start_time > current_time + lookahead_time { break; }
-        if (n.start_time + n.duration) < current_time - 1.0 { continue; }
+        if env.muted[n.channel as usize] { continue; }
+        if (n.start_time + n.duration) < current_time - env.past_seconds { continue; }
 
-        let time_diff = (n.start_time - current_time) as f32;
-        let note_y = note_area_h as f32 - (time_diff * PIXELS_PER_SECOND as f32);
-        let note_h = (n.duration * PIXELS_PER_SECOND) as f32;
-        let draw_y = note_y - note_h;
+        let (top, bottom, is_playing) = note_block_rows(n.start_time, n.duration,
+            current_time, env.pixels_per_second, note_area_h, env.past_seconds);
 
-        let display_key = n.midi_key + vis_offset;
-        let is_playing = current_time >= n.start_time && current_time < (n.start_time + n.duration);
+        let display_key = display_key(n, vis_offset);
         if is_playing {
             if display_key >= 0 && display_key <= 127 {
                 env.active_keys[display_key as usize] = true;
-                env.active_colors[display_key as usize] = n.color;
+                env.active_colors[display_key as usize] = get_note_base_color(env, n);
+                let start = &mut env.ripple_start[display_key as usize];
+                *start = start.max(n.start_time);
             }
         }
 
-        if display_key >= MIN_MIDI && display_key <= MAX_MIDI {
+        if display_key >= MIN_MIDI && display_key <= MAX_MIDI && top < bottom {
             let (x, width, _) = get_key_geometry(display_key, w as f32);
 
-            let mut c = n.color;
+            let mut c = note_color(get_note_base_color(env, n), n.channel, n.velocity);
             if is_playing {
                 c.r = c.r.saturating_add(60);
                 c.g = c.g.saturating_add(60);
@@ -841,13 +2268,40 @@ fn render_notes(env: &mut Env, notes: &Vec<Note>,
 
             env.canvas.set_draw_color(c);
             render_fill_rounded_rect(&mut env.canvas,
-                x as i32 + 1, draw_y as i32,
-                width as i32 - 2, note_h as i32,
+                x as i32 + 1, top,
+                width as i32 - 2, bottom - top,
                 4, CORNER_ALL).unwrap_or(());
+
+            if env.labels {
+                // Mitte des sichtbaren Teils der Note
+                let top = top.max(0) as f32;
+                let bottom = bottom as f32;
+                render_note_label(env, display_key, c, x + width / 2.0, (top + bottom) / 2.0,
+                    width - 2.0, bottom - top);
+            }
         }
     }
 }
 
+// Notenname zentriert bei (cx, cy), in der größten Pixelschrift, die in
+// width x height passt, sonst gar nicht. Dunkle Schrift auf hellen
+// Noten, helle auf dunklen.
+fn render_note_label(env: &mut Env, key: i32, note_color: Color, cx: f32, cy: f32,
+    width: f32, height: f32
+) {
+    let name = note_name(key, env.root_key.0);
+    let Some(scale) = (1..=2).rev().find(|&s| {
+        pixel_text_width(&name, s) as f32 + 4.0 <= width && (5 * s) as f32 + 4.0 <= height
+    }) else { return; };
+
+    let Color {r, g, b, ..} = note_color;
+    let luma = 0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32;
+    env.canvas.set_draw_color(if luma > 140.0 { Color::RGB(0, 0, 0) } else { Color::RGB(255, 255, 255) });
+    let x = (cx - pixel_text_width(&name, scale) as f32 / 2.0).round() as i32;
+    let y = (cy - (5 * scale) as f32 / 2.0).round() as i32;
+    render_pixel_text(&mut env.canvas, &name, x, y, scale);
+}
+
 fn render_keys(env: &mut Env, w: i32, note_area_h: i32, keyboard_height: i32) {
     // Tastatur Zeichnen
     // 1. Weiße Tasten
@@ -893,6 +2347,27 @@ fn render_keys(env: &mut Env, w: i32, note_area_h: i32, keyboard_height: i32) {
     }
 }
 
+// Welle über jeder Taste, deren Note vor weniger als RIPPLE_SECONDS
+// angeschlagen wurde: wird breiter und verblasst dabei. Das Alter folgt
+// der Songzeit und steht deshalb bei Pause still.
+fn render_ripples(env: &mut Env, w: i32, note_area_h: i32, current_time: f64) {
+    env.canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+    for key in MIN_MIDI..=MAX_MIDI {
+        let age = current_time - env.ripple_start[key as usize];
+        if !(0.0..RIPPLE_SECONDS).contains(&age) { continue; }
+        let progress = (age / RIPPLE_SECONDS) as f32;
+
+        let (x, width, _) = get_key_geometry(key, w as f32);
+        let ripple_w = width * (1.0 + progress);
+        let ripple_h = RIPPLE_HEIGHT as f32 * (0.5 + progress);
+        let Color {r, g, b, ..} = env.active_colors[key as usize];
+        env.canvas.set_draw_color(Color::RGBA(r, g, b, ((1.0 - progress) * 180.0) as u8));
+        env.canvas.fill_rect(Rect::new(
+            (x + (width - ripple_w) / 2.0) as i32, note_area_h - ripple_h as i32,
+            ripple_w as u32, ripple_h as u32)).unwrap_or(());
+    }
+}
+
 fn render_piano(env: &mut Env, view: &RenderView, notes: &Vec<Note>, current_time: f64, vis_offset: i32) {
     // Zeichnen
     view.begin(&mut env.canvas, Color::RGB(30, 30, 35));
@@ -903,20 +2378,222 @@ fn render_piano(env: &mut Env, view: &RenderView, notes: &Vec<Note>, current_tim
     let keyboard_height = KEYBOARD_HEIGHT * w / (WINDOW_WIDTH as i32);
     let note_area_h = h - keyboard_height;
 
-    let visible_time_range = note_area_h as f64 / PIXELS_PER_SECOND;
+    let visible_time_range = note_area_h as f64 / env.pixels_per_second;
     let lookahead_time = visible_time_range + 1.0;
 
     // Reset Keys
     env.active_keys.fill(false);
+    env.ripple_start.fill(f64::NEG_INFINITY);
 
     render_notes(env, notes, w, note_area_h, current_time, lookahead_time, vis_offset);
+    for key in 0..128 {
+        if env.live_keys[key] {
+            env.active_keys[key] = true;
+            env.active_colors[key] = LIVE_KEY_COLOR;
+        }
+    }
     render_keys(env, w, note_area_h, keyboard_height);
+    if env.pedal_down {
+        env.canvas.set_draw_color(PEDAL_BAR_COLOR);
+        env.canvas.fill_rect(Rect::new(0, h - PEDAL_BAR_HEIGHT as i32,
+            w as u32, PEDAL_BAR_HEIGHT)).unwrap_or(());
+    }
+    if env.ripple {
+        render_ripples(env, w, note_area_h, current_time);
+    }
+
+    // Visuelles Metronom an der Tastaturkante
+    if env.flash_intensity > 0.0 {
+        let Color {r, g, b, ..} = env.beat_color;
+        env.canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+        env.canvas.set_draw_color(Color::RGBA(r, g, b, (env.flash_intensity * 255.0) as u8));
+        env.canvas.fill_rect(Rect::new(0, note_area_h - BEAT_FLASH_HEIGHT as i32,
+            w as u32, BEAT_FLASH_HEIGHT)).unwrap_or(());
+    }
+}
+
+// 3x5-Pixelschrift für Zeitanzeige und Notennamen. Pro Zeile 3 Bit,
+// das höchste Bit ist das linke Pixel.
+fn pixel_glyph(c: char) -> [u8; 5] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'b' => [0b100, 0b100, 0b110, 0b101, 0b110],
+        _ => [0; 5]
+    }
+}
+
+// Breite eines Textes in der Pixelschrift (4 Pixel pro Zeichen inkl. Abstand)
+fn pixel_text_width(text: &str, scale: i32) -> i32 {
+    (text.chars().count() as i32 * 4 - 1).max(0) * scale
+}
+
+fn render_pixel_text(canvas: &mut Canvas<Window>, text: &str, x: i32, y: i32, scale: i32) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i as i32 * 4 * scale;
+        for (row, bits) in pixel_glyph(c).iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    canvas.fill_rect(Rect::new(glyph_x + col * scale, y + row as i32 * scale,
+                        scale as u32, scale as u32)).unwrap_or(());
+                }
+            }
+        }
+    }
+}
+
+fn format_mmss(seconds: f64) -> String {
+    let total = seconds.max(0.0) as u64;
+    format!("{:02}:{:02}", total / 60, total % 60)
+}
+
+// Minimum und Maximum der Samples eines Ringpuffers für jede von `width`
+// Spalten, vom ältesten Sample (bei `pos`) zum neuesten
+fn scope_columns(recent: &[i16], pos: usize, width: usize) -> Vec<(i16, i16)> {
+    let len = recent.len();
+    if len == 0 {
+        return Vec::new();
+    }
+    (0..width).map(|x| {
+        let first = x * len / width;
+        let last = ((x + 1) * len / width).max(first + 1);
+        (first..last).map(|i| recent[(pos + i) % len])
+            .fold((i16::MAX, i16::MIN), |(lo, hi), v| (lo.min(v), hi.max(v)))
+    }).collect()
+}
+
+// Wellenform der zuletzt gespielten Sekunde als Leiste der Höhe `h` ab
+// Zeile `y`, eine senkrechte Linie von Minimum bis Maximum pro Spalte
+fn render_scope(env: &mut Env, y: i32, w: u32, h: u32) {
+    let columns = {
+        let lock = env.device.lock();
+        scope_columns(&lock.recent, lock.recent_pos, w as usize)
+    };
+    env.canvas.set_viewport(None);
+    env.canvas.set_draw_color(Color::RGB(15, 15, 20));
+    env.canvas.fill_rect(Rect::new(0, y, w, h)).unwrap_or(());
+
+    let center = y + h as i32 / 2;
+    let to_y = |v: i16| center - (v as f32 / 32768.0 * (h as f32 / 2.0)) as i32;
+    env.canvas.set_draw_color(Color::RGB(60, 60, 70));
+    env.canvas.draw_line(Point::new(0, center), Point::new(w as i32, center)).unwrap_or(());
+    env.canvas.set_draw_color(SCOPE_COLOR);
+    for (x, &(lo, hi)) in columns.iter().enumerate() {
+        let x = x as i32;
+        env.canvas.draw_line(Point::new(x, to_y(hi)), Point::new(x, to_y(lo))).unwrap_or(());
+    }
+}
+
+// Fortschrittsbalken am oberen Fensterrand mit Schleifenmarken und
+// Zeitanzeige "MM:SS / MM:SS" in der rechten oberen Ecke. Darunter das
+// Tempo der MIDI-Datei mit einem Punkt, der auf jedem Schlag aufleuchtet
+// (`tempo` ist BPM und Leuchtstärke, fehlt bei SMPTE-Zeitbasis).
+fn render_progress(env: &mut Env, current_time: f64, win_w: u32, tempo: Option<(f64, f32)>) {
+    env.canvas.set_viewport(None);
+    let progress = if env.end_limit > 0.0 { (current_time / env.end_limit).clamp(0.0, 1.0) } else { 0.0 };
+    let to_x = |t: f64| ((t / env.end_limit).clamp(0.0, 1.0) * win_w as f64) as i32;
+
+    env.canvas.set_draw_color(Color::RGB(60, 60, 70));
+    env.canvas.fill_rect(Rect::new(0, 0, win_w, PROGRESS_HEIGHT)).unwrap_or(());
+    let filled = (progress * win_w as f64) as u32;
+    if filled > 0 {
+        env.canvas.set_draw_color(Color::RGB(200, 200, 210));
+        env.canvas.fill_rect(Rect::new(0, 0, filled, PROGRESS_HEIGHT)).unwrap_or(());
+    }
+
+    env.canvas.set_draw_color(Color::RGB(255, 200, 0));
+    for marker in [env.loop_a, env.loop_b].into_iter().flatten() {
+        env.canvas.fill_rect(Rect::new(to_x(marker) - 1, 0, 2, PROGRESS_HEIGHT * 2)).unwrap_or(());
+    }
+
+    let text = format!("{} / {}", format_mmss(current_time), format_mmss(env.end_limit));
+    let scale = PROGRESS_TEXT_SCALE;
+    let pad = 2 * scale;
+    let text_w = pixel_text_width(&text, scale);
+    let x = win_w as i32 - text_w - 2 * pad;
+    let y = PROGRESS_HEIGHT as i32 + pad;
+
+    env.canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
+    env.canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    env.canvas.fill_rect(Rect::new(x - pad, y - pad,
+        (text_w + 2 * pad) as u32, (5 * scale + 2 * pad) as u32)).unwrap_or(());
+    env.canvas.set_draw_color(Color::RGB(230, 230, 230));
+    render_pixel_text(&mut env.canvas, &text, x, y, scale);
+
+    let Some((bpm, pulse)) = tempo else { return; };
+    let text = format!("{:.0} BPM", bpm);
+    let text_w = pixel_text_width(&text, scale);
+    let dot = 5 * scale; // so hoch wie die Schrift
+    let x = win_w as i32 - text_w - 2 * pad;
+    let y = y + 5 * scale + 3 * pad;
+
+    env.canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    env.canvas.fill_rect(Rect::new(x - dot - 3 * pad, y - pad,
+        (text_w + dot + 4 * pad) as u32, (5 * scale + 2 * pad) as u32)).unwrap_or(());
+    let Color {r, g, b, ..} = env.beat_color;
+    env.canvas.set_draw_color(Color::RGBA(r, g, b, (60.0 + pulse * 195.0) as u8));
+    env.canvas.fill_rect(Rect::new(x - dot - 2 * pad, y, dot as u32, dot as u32)).unwrap_or(());
+    env.canvas.set_draw_color(Color::RGB(230, 230, 230));
+    render_pixel_text(&mut env.canvas, &text, x, y, scale);
+}
+
+// Debug-Ausgabe: klingende Noten mit der Frequenz, die auch die
+// Synthese verwendet. Nur bei Änderung der Notenmenge.
+fn print_debug_notes(env: &mut Env, notes: &[Note], current_time: f64) {
+    let sounding: Vec<&Note> = notes.iter()
+        .take_while(|n| n.start_time <= current_time)
+        .filter(|n| current_time < n.start_time + n.duration)
+        .collect();
+
+    let ids: Vec<(i32, i32)> = sounding.iter().map(|n| (n.channel, n.midi_key)).collect();
+    if ids == env.debug_active { return; }
+    env.debug_active = ids;
+
+    println!("[{:8.3} s] {} Note(n)", current_time, sounding.len());
+    for n in sounding {
+        if n.channel == 9 {
+            println!("  Kanal {:2}  MIDI {:3}  Schlagzeug  Vel {:3}",
+                n.channel, n.midi_key, n.velocity);
+        } else {
+            println!("  Kanal {:2}  MIDI {:3}  {:9.3} Hz  Vel {:3}",
+                n.channel, n.midi_key, midi_to_freq(n.midi_key), n.velocity);
+        }
+    }
 }
 
 // =====================================================================
 // MAIN
 // =====================================================================
 
+fn calculate_end_limit(pcm_len: usize, rate: i32, duration: f64, use_timidity: bool) -> f64 {
+    let audio_duration = pcm_len as f64 / rate as f64;
+
+    // Damit die Audio-Länge bestimmt, wann Ende ist
+    let loop_limit = if audio_duration > duration { audio_duration } else { duration };
+    if use_timidity { loop_limit + TIMIDITY_END_PAUSE } else { duration + END_PAUSE }
+}
+
 fn calculate_time(env: &Env) -> (f64, f64) {
     // Wenn pausiert, ist die "aktuelle Zeit" fixiert auf den Start der Pause.
     // Wenn nicht pausiert, ist es Jetzt minus Startzeitpunkt.
@@ -943,7 +2620,6 @@ fn handle_end(env: &mut Env, raw_time: f64, auto_quit: bool) -> ControlFlow<()>
         // Wenn das Ende erreicht ist und wir noch nicht pausiert sind
         if !env.paused && raw_time >= env.end_limit {
             env.paused = true;
-            env.device.pause(); // Audio stoppen
 
             // Trick: Wir setzen 'pause_start_time' so, dass die verstrichene Zeit
             // relativ zu 'start_instant' exakt dem 'end_limit' entspricht.
@@ -951,124 +2627,388 @@ fn handle_end(env: &mut Env, raw_time: f64, auto_quit: bool) -> ControlFlow<()>
             env.pause_start_time = env.start_instant + Duration::from_secs_f64(env.end_limit);
 
             // Audio-Cursor sicherheitshalber ans Ende schieben (Stille)
+            // und Audio stoppen
             let mut lock = env.device.lock();
-            let total_len = lock.samples.len();
+            let total_len = lock.len();
             lock.cursor = total_len;
+            lock.playing = false;
         }
     }
     ControlFlow::Continue(())
 }
 
+// Kommandozeilenoptionen, Vorgaben wie ohne Angabe der Option
+struct Options {
+    midifile: String,
+    help: bool,
+    use_timidity: bool,
+    auto_quit: bool,
+    black_notes: bool,
+    fullscreen: bool,
+    view_mode: u8,
+    root_key: Option<KeyInfo>, // sonst aus der MIDI-Datei
+    tempo: Option<f64>,
+    transpose: i32,       // Wirkt auf Audio UND Grafik
+    transpose_staff: i32, // Wirkt nur auf Grafik
+    staff_mode: StaffMode,
+    split_key: i32,
+    debug_notes: bool,
+    beat_flash: bool,
+    ripple: bool,
+    labels: bool,
+    resample_kind: Resample,
+    wave: Wave,
+    threads: usize,
+    stream: bool,
+    bend_range: f64,
+    sample_rate: i32,
+    past_seconds: f64,
+    beat_color: Color,
+    palette: [Color; 16],
+    track_colors: bool,
+    render_frames: Option<String>, // Verzeichnis für den Bildexport
+    fps: f64,
+    vsync: bool,
+    fps_cap: Option<f64>,
+    midi_out: Option<String>, // Teil des Portnamens, leer für den ersten
+    resume: bool
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            midifile: String::new(),
+            help: false,
+            use_timidity: false,
+            auto_quit: false,
+            black_notes: false,
+            fullscreen: false,
+            view_mode: 0,
+            root_key: None,
+            tempo: None,
+            transpose: 0,
+            transpose_staff: 0,
+            staff_mode: StaffMode::Grand,
+            split_key: 60,
+            debug_notes: false,
+            beat_flash: false,
+            ripple: false,
+            labels: false,
+            resample_kind: Resample::Linear,
+            wave: Wave::Sine,
+            threads: 1,
+            stream: false,
+            bend_range: 2.0,
+            sample_rate: SAMPLE_RATE,
+            past_seconds: 1.0,
+            beat_color: Color::RGB(255, 200, 80),
+            palette: std::array::from_fn(|c| default_channel_color(c as i32)),
+            track_colors: false,
+            render_frames: None,
+            fps: FRAME_RATE,
+            vsync: true,
+            fps_cap: None,
+            midi_out: None,
+            resume: false
+        }
+    }
+}
+
+// Wertet die Argumente (ohne Programmnamen) aus. Optionen beginnen mit
+// "-", alles andere ist die MIDI-Datei, von der es genau eine geben muss.
+// Unbekannte Optionen sind ein Fehler, damit Tippfehler auffallen.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut opts = Options::default();
+    let mut files = Vec::new();
+
+    for arg in args {
+        let arg = arg.as_str();
+        if !arg.starts_with('-') || arg == "-" {
+            files.push(arg);
+            continue;
+        }
+        match arg {
+            "-h" | "--help" => {opts.help = true;},
+            "-tm" => {opts.use_timidity = true;},
+            "-aq" => {opts.auto_quit = true;},
+            "-b"  => {opts.black_notes = true;},
+            "-s"  => {opts.view_mode = 1;},
+            "-ps" => {opts.view_mode = 2;},
+            "--fullscreen" => {opts.fullscreen = true;},
+            "--resume" => {opts.resume = true;},
+            "--midi-out" => {opts.midi_out = Some(String::new());},
+            val if val.starts_with("--midi-out=") => {
+                opts.midi_out = Some(val[11..].to_string());
+            },
+            "--treble" => {opts.staff_mode = StaffMode::Treble;},
+            "--bass" => {opts.staff_mode = StaffMode::Bass;},
+            val if val.starts_with("--split=") => {
+                opts.split_key = val[8..].parse::<i32>().ok()
+                    .filter(|k| (0..=127).contains(k))
+                    .ok_or_else(|| format!("Ungültiger Trennpunkt: {}", &val[8..]))?;
+            },
+            "--debug-notes" => {opts.debug_notes = true;},
+            "--beat-flash" => {opts.beat_flash = true;},
+            "--ripple" => {opts.ripple = true;},
+            "--labels" => {opts.labels = true;},
+            "--track-colors" => {opts.track_colors = true;},
+            "--stream" => {opts.stream = true;},
+            val if val.starts_with("--threads=") => {
+                opts.threads = val[10..].parse::<usize>().map_err(|_| format!(
+                    "Ungültige Thread-Anzahl: {}", &val[10..]))?.max(1);
+            },
+            val if val.starts_with("--bend-range=") => {
+                opts.bend_range = val[13..].parse::<f64>().map_err(|_| format!(
+                    "Ungültiger Pitch-Bend-Bereich: {}", &val[13..]))?;
+            },
+            val if val.starts_with("--rate=") => {
+                opts.sample_rate = val[7..].parse::<i32>().ok()
+                    .filter(|r| (1000..=384_000).contains(r))
+                    .ok_or_else(|| format!("Ungültige Abtastrate: {}", &val[7..]))?;
+            },
+            val if val.starts_with("--wave=") => {
+                opts.wave = Wave::from_name(&val[7..]).ok_or_else(|| format!(
+                    "Unbekannte Wellenform: {}", &val[7..]))?;
+            },
+            val if val.starts_with("--render-frames=") && val.len() > 16 => {
+                opts.render_frames = Some(val[16..].to_string());
+            },
+            val if val.starts_with("--fps=") => {
+                opts.fps = val[6..].parse::<f64>().ok()
+                    .filter(|&fps| fps > 0.0 && fps <= 1000.0)
+                    .ok_or_else(|| format!("Ungültige Bildrate: {}", &val[6..]))?;
+            },
+            "--no-vsync" => {opts.vsync = false;},
+            val if val.starts_with("--fps-cap=") => {
+                opts.fps_cap = Some(val[10..].parse::<f64>().ok()
+                    .filter(|&fps| fps > 0.0 && fps <= 1000.0)
+                    .ok_or_else(|| format!("Ungültige Bildrate: {}", &val[10..]))?);
+            },
+            "--resample=linear" => {opts.resample_kind = Resample::Linear;},
+            "--resample=cubic" => {opts.resample_kind = Resample::Cubic;},
+            val if val.starts_with("--beat-color=") => {
+                opts.beat_color = parse_hex_color(&val[13..]).ok_or_else(|| format!(
+                    "Ungültige Farbe: {}", &val[13..]))?;
+            },
+            val if val.starts_with("--colors=") => {
+                parse_palette(&val[9..], &mut opts.palette)?;
+            },
+            key if key.starts_with("-k") => {
+                opts.root_key = Some(KeyInfo::from_name(&key[2..]));
+            },
+            val if val.starts_with("--past=") => {
+                if let Ok(v) = val[7..].parse::<f64>() {
+                    opts.past_seconds = v.max(0.0);
+                }
+            },
+            val if val.starts_with("--tempo=") => {
+                if let Ok(v) = val[8..].parse::<f64>() {
+                    if v > 0.0 {opts.tempo = Some(v);}
+                }
+            },
+            val if val.starts_with("--transpose=") => {
+                // .trim_start_matches('+') erlaubt auch "+2" statt nur "2"
+                if let Ok(v) = val[12..].trim_start_matches('+').parse::<i32>() {
+                    opts.transpose = v;
+                }
+            },
+            val if val.starts_with("--transpose-staff=") => {
+                if let Ok(v) = val[18..].trim_start_matches('+').parse::<i32>() {
+                    opts.transpose_staff = v;
+                }
+            },
+            val => return Err(format!("Unbekannte Option: {val}"))
+        }
+    }
+
+    if opts.help {
+        return Ok(opts);
+    }
+    match files[..] {
+        [file] => opts.midifile = file.to_string(),
+        [] => return Err("Keine MIDI-Datei angegeben (--help zeigt die Verwendung)".into()),
+        _ => return Err(format!("Mehr als eine MIDI-Datei angegeben: {}", files.join(", ")))
+    }
+    Ok(opts)
+}
+
+// Was ein Bild außer dem Zustand in `Env` zum Zeichnen braucht
+struct Scene<'a> {
+    notes: &'a Vec<Note>,
+    beat_grid: &'a [(f64, bool)],
+    key_changes: &'a [(f64, KeyInfo)],
+    tempo_changes: &'a [(f64, f64)],
+    pedal_changes: &'a [(f64, bool)],
+    transpose_staff: i32,
+    fixed_key: bool // Tonart per -k, nicht aus der MIDI-Datei
+}
+
+// Zeichnet das Bild zur Zeit `current_time`, ohne es anzuzeigen
+fn draw_frame(env: &mut Env, scene: &Scene, current_time: f64, textures: &mut Textures
+) -> Result<(), String> {
+    let Scene {notes, beat_grid, key_changes, tempo_changes, pedal_changes, transpose_staff,
+        fixed_key} = *scene;
+    if env.debug_notes {
+        print_debug_notes(env, notes, current_time);
+    }
+    if !fixed_key {
+        env.root_key = key_at(key_changes, current_time);
+    }
+    env.flash_intensity = if env.beat_flash {
+        downbeat_flash(beat_grid, current_time)
+    } else {
+        0.0
+    };
+    env.pedal_down = pedal_at(pedal_changes, current_time);
+
+    let (win_w, full_h) = env.canvas.output_size()?;
+    let scope_h = if env.show_scope { SCOPE_HEIGHT.min(full_h / 2) } else { 0 };
+    let win_h = full_h - scope_h;
+    let view = RenderView::new(0, 0, win_w, win_h);
+
+    if env.view_mode == 0 {
+        render_piano(env, &view, notes, current_time, transpose_staff);
+    } else if env.view_mode == 1 {
+        render_staff(env, &view, notes, current_time, textures, transpose_staff,
+            beat_grid);
+    } else {
+        let staff_h = win_h / 2;
+        let piano_y = staff_h as i32;
+        let piano_h = win_h - staff_h;
+
+        let view = RenderView::new(0, 0, win_w, staff_h);
+        render_staff(env, &view, notes, current_time, textures, transpose_staff,
+            beat_grid);
+
+        let view = RenderView::new(0, piano_y, win_w, piano_h);
+        render_piano(env, &view, notes, current_time, transpose_staff);
+    }
+    if scope_h > 0 {
+        render_scope(env, win_h as i32, win_w, scope_h);
+    }
+    if env.show_progress {
+        let tempo = tempo_at(tempo_changes, current_time)
+            .map(|bpm| (bpm, beat_pulse(beat_grid, current_time)));
+        render_progress(env, current_time, win_w, tempo);
+    }
+    Ok(())
+}
+
+// Headless-Modus: die Zeit in festen Schritten von 1/fps vorrücken und
+// jedes Bild als PNG in `dir` schreiben
+fn render_frames_to(env: &mut Env, scene: &Scene, textures: &mut Textures, dir: &str, fps: f64
+) -> Result<(), Box<dyn std::error::Error>> {
+    std::fs::create_dir_all(dir)?;
+    let frames = (env.end_limit * fps).ceil() as usize;
+    println!("Schreibe {} Bilder ({} fps) nach {}", frames, fps, dir);
+
+    for frame in 0..frames {
+        let current_time = frame as f64 / fps;
+        draw_frame(env, scene, current_time, textures)?;
+
+        let (w, h) = env.canvas.output_size()?;
+        let pixels = env.canvas.read_pixels(None, PixelFormatEnum::RGB24)?;
+        let path = std::path::Path::new(dir).join(format!("frame_{:06}.png", frame));
+        let mut file = std::io::BufWriter::new(File::create(path)?);
+        png::write_png(&mut file, w, h, &pixels)?;
+        file.flush()?;
+
+        if frame % (fps.ceil() as usize * 10) == 0 {
+            println!("{} / {}", format_mmss(current_time), format_mmss(env.end_limit));
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args: Vec<String> = env::args().collect();
-    let mut midifile = "";
-    let mut use_timidity = false;
-    let mut auto_quit = false;
-    let mut black_notes = false;
-    let mut view_mode = 0;
-    let mut root_key = KeyInfo(0, 0);
-    let mut tempo: Option<f64> = None;
-    let mut transpose: i32 = 0; // Wirkt auf Audio UND Grafik
-    let mut transpose_staff: i32 = 0; // Wirkt nur auf Grafik
-    let mut show_bass_staff = true;
-
     if args.len() < 2 {
         println!("{}", HELP);
         return Ok(());
     }
 
-    for arg in &args[1..] {
-        let arg = arg.as_str();
-        if arg.as_bytes()[0] == b'-' {
-            match arg {
-                "-tm" => {use_timidity = true;},
-                "-aq" => {auto_quit = true;},
-                "-b"  => {black_notes = true;},
-                "-s"  => {view_mode = 1;},
-                "-ps" => {view_mode = 2;},
-                "--treble" => {show_bass_staff = false;},
-                "-h" | "--help" => {
-                    println!("{}", HELP);
-                    return Ok(());
-                },
-                key if key.starts_with("-k") => {
-                    root_key = KeyInfo::from_name(&key[2..]);
-                },
-                val if val.starts_with("--tempo=") => {
-                    if let Ok(v) = val[8..].parse::<f64>() {
-                        if v > 0.0 {tempo = Some(v);}
-                    }
-                },
-                val if val.starts_with("--transpose=") => {
-                    // .trim_start_matches('+') erlaubt auch "+2" statt nur "2"
-                    if let Ok(v) = val[12..].trim_start_matches('+').parse::<i32>() {
-                        transpose = v;
-                    }
-                },
-                val if val.starts_with("--transpose-staff=") => {
-                    if let Ok(v) = val[18..].trim_start_matches('+').parse::<i32>() {
-                        transpose_staff = v;
-                    }
-                },
-                val => return Err(format!(
-                    "Unbekannte Option: {val}").into())
-            }
-        } else {
-            midifile = arg;
-        }
+    let opts = parse_args(&args[1..])?;
+    if opts.help {
+        println!("{}", HELP);
+        return Ok(());
     }
+    let Options {
+        midifile, use_timidity, auto_quit, black_notes, fullscreen, view_mode, root_key,
+        tempo, transpose, transpose_staff, staff_mode, split_key, debug_notes, beat_flash,
+        ripple, labels, resample_kind, wave, threads, stream, bend_range, sample_rate, past_seconds, beat_color,
+        palette, track_colors, render_frames, fps, vsync, fps_cap, midi_out, resume, ..
+    } = opts;
 
     // 1. MIDI Parsen
-    let (events, division) = parse_midi(midifile)?;
-    let (notes, duration) = convert_to_notes(&events, division, tempo, transpose);
+    let (events, division) = parse_midi(&midifile)?;
+    let (mut notes, duration) = convert_to_notes(&events, division, tempo, transpose);
 
     if notes.is_empty() {
         return Err("Keine Noten gefunden.".into());
     }
 
     // 2. Audio Generieren
-    let pcm_buffer = if use_timidity {
-        generate_audio_with_timidity(midifile, tempo, transpose)?
+    let stems = if use_timidity {
+        let samples = generate_audio_with_timidity(&midifile, tempo, transpose, sample_rate,
+            resample_kind)?;
+        vec![Stem {channel: None, gain: 1.0, samples}]
+    } else if stream {
+        Vec::new()
     } else {
-        synthesize_to_ram(&notes, duration)
+        synthesize_stems(&notes, duration, wave, threads, bend_range, sample_rate)
     };
+    let stream = stream && !use_timidity;
+    let note_stream = stream.then(|| NoteStream::new(&notes, duration, wave, bend_range, sample_rate));
 
-    let audio_duration = pcm_buffer.len() as f64 / SAMPLE_RATE as f64;
+    let end_limit = calculate_end_limit(stems_len(&stems), sample_rate, duration, use_timidity);
 
     // 3. SDL Init
+    // Beim Bildexport wird nichts abgespielt und kein Fenster gezeigt
+    let headless = render_frames.is_some();
+    if headless {
+        sdl2::hint::set("SDL_AUDIODRIVER", "dummy");
+    }
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
     let audio_subsystem = sdl_context.audio()?;
 
-    let window = video_subsystem.window("Mivi", WINDOW_WIDTH, WINDOW_HEIGHT)
-        .position_centered()
-        .resizable()
-        .build()?;
+    let mut window = video_subsystem.window("Mivi", WINDOW_WIDTH, WINDOW_HEIGHT);
+    if headless {
+        window.hidden();
+    } else {
+        window.position_centered().resizable();
+    }
+    let window = window.build()?;
 
-    let canvas = window.into_canvas()
-        .accelerated()
-        .present_vsync()
-        .build()?;
+    // Ohne vsync, damit der Export nicht an die Bildwiederholrate gebunden ist
+    let canvas = if headless {
+        window.into_canvas().software().build()?
+    } else if vsync {
+        window.into_canvas().accelerated().present_vsync().build()?
+    } else {
+        window.into_canvas().accelerated().build()?
+    };
 
     // Audio-Setup
     let desired_spec = AudioSpecDesired {
-        freq: Some(SAMPLE_RATE),
+        freq: Some(sample_rate),
         channels: Some(AUDIO_CHANNELS),
         samples: Some(2048),
     };
 
     let device = audio_subsystem.open_playback(None, &desired_spec, |_spec| {
-        SoundProvider {samples: pcm_buffer, cursor: 0}
+        SoundProvider {
+            stems, stream: note_stream, muted: [false; 16], cursor: 0, playing: true,
+            live: Vec::new(), wave, rate: sample_rate as f64, recent: Vec::new(), recent_pos: 0
+        }
     })?;
 
-    device.resume();
+    if !headless {
+        device.resume();
+    }
 
     let event_pump = sdl_context.event_pump()?;
 
-    // Damit die Audio-Länge bestimmt, wann Ende ist
-    let loop_limit = if audio_duration > duration { audio_duration } else { duration };
-    let end_limit = if use_timidity { loop_limit + 1.5 } else { duration + 1.0 };
-
     let mut env = Env {
         canvas,
         event_pump,
@@ -1078,29 +3018,131 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         paused: false,
         fullscreen: false,
         black_notes,
-        show_bass_staff,
+        staff_mode,
+        split_key,
         view_mode,
         end_limit,
         active_keys: [false; 128],
         active_colors: [Color::RGB(0, 0, 0); 128],
+        ripple_start: [f64::NEG_INFINITY; 128],
         ring_buffer: StackRingBuffer::new(),
-        root_key
+        root_key: root_key.unwrap_or(KeyInfo(0, 0)),
+        tempo: tempo.unwrap_or(1.0),
+        pending_tempo: None,
+        debug_notes,
+        past_seconds,
+        pixels_per_second: PIXELS_PER_SECOND,
+        debug_active: Vec::new(),
+        beat_flash,
+        ripple,
+        labels,
+        beat_color,
+        palette,
+        track_colors,
+        flash_intensity: 0.0,
+        pedal_down: false,
+        loop_a: None,
+        loop_b: None,
+        show_progress: true,
+        show_scope: false,
+        muted: [false; 16],
+        sample_rate,
+        live_mode: false,
+        live_keys: [false; 128]
     };
+    if fullscreen && !headless {
+        if env.canvas.window_mut().set_fullscreen(FullscreenType::Desktop).is_err() {
+            println!("Wechsel in den Vollbildmodus nicht möglich.");
+        } else {
+            env.fullscreen = true;
+        }
+    }
+    let mut beat_grid = build_beat_grid(&events, division, tempo, env.end_limit);
+    let mut key_changes = build_key_changes(&events, division, tempo);
+    let mut tempo_changes = build_tempo_changes(&events, division, tempo);
+    let mut pedal_changes = build_pedal_changes(&events, division, tempo);
 
     // Texturen laden
     let img_sys = ImageSystem::init(&env);
     let mut textures = Textures::load(&img_sys);
 
+    if let Some(dir) = render_frames {
+        let scene = Scene {notes: &notes, beat_grid: &beat_grid, key_changes: &key_changes,
+            tempo_changes: &tempo_changes, pedal_changes: &pedal_changes, transpose_staff,
+            fixed_key: root_key.is_some()};
+        return render_frames_to(&mut env, &scene, &mut textures, &dir, fps);
+    }
+
+    if resume && let Ok(text) = fs::read_to_string(session_path(&midifile)) {
+        let session = current_session(&env).parse(&text);
+        apply_session(&mut env, &session);
+        println!("Fortgesetzt bei {:.1} s", session.position);
+    }
+
+    let mut midi_out = match &midi_out {
+        Some(port) => Some(MidiOut::connect(port, &notes)?),
+        None => None
+    };
+
     // 4. Main Loop
     loop {
+        let frame_start = Instant::now();
+
         // Eingabeverarbeitung
         match handle_input(&mut env) {
             ControlFlow::Continue(()) => {},
             ControlFlow::Break(()) => break
         }
 
+        // Tempowechsel: Noten neu berechnen und Audio neu erzeugen.
+        // Die Position im Stück bleibt musikalisch erhalten.
+        if let Some(new_tempo) = env.pending_tempo.take() {
+            let (_, current_time) = calculate_time(&env);
+            let new_time = current_time * env.tempo / new_tempo;
+
+            let duration;
+            (notes, duration) = convert_to_notes(&events, division, Some(new_tempo), transpose);
+            let stems = if use_timidity {
+                let samples = generate_audio_with_timidity(&midifile, Some(new_tempo), transpose,
+                    sample_rate, resample_kind)?;
+                vec![Stem {channel: None, gain: 1.0, samples}]
+            } else if stream {
+                Vec::new()
+            } else {
+                synthesize_stems(&notes, duration, wave, threads, bend_range, sample_rate)
+            };
+
+            env.end_limit = calculate_end_limit(stems_len(&stems), sample_rate, duration, use_timidity);
+            {
+                let mut lock = env.device.lock();
+                lock.stems = stems;
+                if stream {
+                    lock.stream = Some(NoteStream::new(&notes, duration, wave, bend_range, sample_rate));
+                }
+            }
+            // Schleifenpunkte liegen musikalisch an derselben Stelle
+            let scale = env.tempo / new_tempo;
+            env.loop_a = env.loop_a.map(|a| a * scale);
+            env.loop_b = env.loop_b.map(|b| b * scale);
+            env.tempo = new_tempo;
+            beat_grid = build_beat_grid(&events, division, Some(new_tempo), env.end_limit);
+            key_changes = build_key_changes(&events, division, Some(new_tempo));
+            tempo_changes = build_tempo_changes(&events, division, Some(new_tempo));
+            pedal_changes = build_pedal_changes(&events, division, Some(new_tempo));
+            if let Some(out) = &mut midi_out {
+                out.set_notes(&notes);
+            }
+            seek_to(&mut env, new_time);
+            println!("Tempo: {:.0} %", new_tempo * 100.0);
+        }
+
         // Zeit berechnen
-        let (raw_time, current_time) = calculate_time(&env);
+        let (mut raw_time, mut current_time) = calculate_time(&env);
+
+        // Schleife A-B: an B zurück auf A
+        if handle_loop(&mut env, current_time) {
+            (raw_time, current_time) = calculate_time(&env);
+        }
 
         // Verhalten am Ende der MIDI-Datei
         match handle_end(&mut env, raw_time, auto_quit) {
@@ -1108,6 +3150,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             ControlFlow::Break(()) => break
         }
 
+        if let Some(out) = &mut midi_out {
+            out.update(current_time, env.paused);
+        }
+
         /* // Hintergrund; nicht gebraucht, da Vordergrund ausfüllend
         env.canvas.set_viewport(None);
         env.canvas.set_clip_rect(None);
@@ -1115,25 +3161,506 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         env.canvas.clear();
         // */
 
-        let (win_w, win_h) = env.canvas.output_size()?;
-        let view = RenderView::new(0, 0, win_w, win_h);
+        let scene = Scene {notes: &notes, beat_grid: &beat_grid, key_changes: &key_changes,
+            tempo_changes: &tempo_changes, pedal_changes: &pedal_changes, transpose_staff,
+            fixed_key: root_key.is_some()};
+        draw_frame(&mut env, &scene, current_time, &mut textures)?;
+        env.canvas.present();
 
-        if env.view_mode == 0 {
-            render_piano(&mut env, &view, &notes, current_time, transpose_staff);
-        } else if env.view_mode == 1 {
-            render_staff(&mut env, &view, &notes, current_time, &mut textures, transpose_staff);
-        } else {
-            let staff_h = win_h / 2;
-            let piano_y = staff_h as i32;
-            let piano_h = win_h - staff_h;
+        // --fps-cap: den Rest der Zeit dieses Bildes schlafen
+        if let Some(cap) = fps_cap {
+            let budget = Duration::from_secs_f64(1.0 / cap);
+            std::thread::sleep(budget.saturating_sub(frame_start.elapsed()));
+        }
+    }
 
-            let view = RenderView::new(0, 0, win_w, staff_h);
-            render_staff(&mut env, &view, &notes, current_time, &mut textures, transpose_staff);
+    if resume && let Err(e) = fs::write(session_path(&midifile), current_session(&env).to_string()) {
+        println!("Sitzung konnte nicht gespeichert werden: {}", e);
+    }
+    Ok(())
+}
 
-            let view = RenderView::new(0, piano_y, win_w, piano_h);
-            render_piano(&mut env, &view, &notes, current_time, transpose_staff);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MIDI-Datei aus Header und den gegebenen Spuren
+    fn midi_file(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut file = b"MThd".to_vec();
+        file.extend_from_slice(&6u32.to_be_bytes());
+        file.extend_from_slice(&format.to_be_bytes());
+        file.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        file.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            file.extend_from_slice(b"MTrk");
+            file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            file.extend_from_slice(track);
         }
-        env.canvas.present();
+        file
+    }
+
+    #[test]
+    fn white_key_count_matches_keyboard() {
+        // A0 bis C8: 52 weiße Tasten
+        assert_eq!(WHITE_KEYS_TOTAL, 52);
+
+        // Die letzte weiße Taste muss genau am rechten Rand enden.
+        let width = 1040.0;
+        let (x, w, is_black) = get_key_geometry(MAX_MIDI, width);
+        assert!(!is_black);
+        assert!((x + w - width).abs() < 1e-3);
+
+        // Schwarze Tasten wie beim Klavier versetzt: Cis zum C hin,
+        // Gis mittig auf der Grenze zwischen G und A
+        let center = |key| {
+            let (x, w, _) = get_key_geometry(key, width);
+            x + w / 2.0
+        };
+        let (c_x, c_w, _) = get_key_geometry(60, width);
+        assert!((center(61) - (c_x + c_w * 0.9)).abs() < 1e-3);
+        let (a_x, _, _) = get_key_geometry(69, width);
+        assert!((center(68) - a_x).abs() < 1e-3);
+    }
+
+    #[test]
+    fn falling_note_reaches_the_keyboard_when_it_sounds() {
+        // 600 Pixel pro Sekunde: 10 Pixel pro Bild bei 60 fps
+        let rows = |t| note_block_rows(2.0, 0.5, t, 600.0, 400, 1.0);
+
+        // Kurz vor dem Start bleibt eine Zeile Abstand zur Tastatur
+        assert_eq!(rows(2.0 - 0.25 / 600.0), (99, 399, false));
+        assert_eq!(rows(2.0 - 1.0 / 60.0), (90, 390, false));
+        // Ab dem Start sitzt der Block auf der Tastaturkante auf
+        assert_eq!(rows(2.0), (100, 400, true));
+        assert_eq!(rows(2.25), (250, 400, true));
+        // Nach dem Ende schrumpft der Rest über die Dauer von --past
+        assert_eq!(rows(2.5), (392, 400, false));
+        assert_eq!(rows(3.0), (396, 400, false));
+        assert_eq!(rows(3.5), (400, 400, false));
+        assert_eq!(note_block_rows(2.0, 0.5, 2.5, 600.0, 400, 0.0), (400, 400, false));
+    }
+
+    #[test]
+    fn scope_shows_the_latest_samples_oldest_first() {
+        let mut provider = SoundProvider {
+            stems: vec![Stem {channel: None, samples: (1..=6).collect(), gain: 1.0}],
+            stream: None, muted: [false; 16], cursor: 0, playing: true,
+            live: Vec::new(), wave: Wave::Sine, rate: 8000.0, recent: vec![0; 4], recent_pos: 0
+        };
+        provider.callback(&mut [0i16; 6]);
+        assert_eq!((&provider.recent[..], provider.recent_pos), (&[5, 6, 3, 4][..], 2));
+
+        // Zwei Spalten über je zwei Samples, mehr Spalten als Samples
+        assert_eq!(scope_columns(&provider.recent, 2, 2), vec![(3, 4), (5, 6)]);
+        assert_eq!(scope_columns(&[1, -1], 0, 4), vec![(1, 1), (1, 1), (-1, -1), (-1, -1)]);
+        assert!(scope_columns(&[], 0, 4).is_empty());
+    }
+
+    #[test]
+    fn live_voice_sounds_until_released() {
+        let mut provider = SoundProvider {
+            stems: Vec::new(), stream: None, muted: [false; 16], cursor: 0, playing: false,
+            live: Vec::new(), wave: Wave::Sine, rate: 8000.0, recent: Vec::new(), recent_pos: 0
+        };
+        let mut out = [0i16; 800];
+        provider.note_on(live_key(Scancode::N).unwrap());
+        provider.callback(&mut out);
+        assert!(out.iter().any(|&v| v.abs() > 1000));
+        assert_eq!(provider.cursor, 0);
+
+        // Nach dem Ausschwingen ist die Stimme entfernt
+        provider.note_off(69);
+        provider.callback(&mut out);
+        assert!(provider.live.is_empty());
+        provider.callback(&mut out);
+        assert!(out.iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn streamed_blocks_match_prerendered_channel() {
+        let note = |start_time: f64, duration: f64, midi_key: i32, channel: i32| Note {
+            start_time, duration, midi_key, velocity: 100, channel, track: 0, program: 0,
+            bends: Vec::new()
+        };
+        let notes = vec![note(0.0, 0.5, 60, 0), note(0.1, 0.05, 38, 9), note(0.3, 0.2, 67, 0)];
+        let rate = 8000;
+        let mut expected = vec![0.0f32; 5000];
+        render_channel(&notes, 0, Wave::Sine, 3, 2.0, rate, &mut expected);
+
+        // Blöcke, die nicht auf Notengrenzen fallen; Schlagzeug stumm
+        let mut stream = NoteStream::new(&notes, 0.625, Wave::Sine, 2.0, rate);
+        let mut muted = [false; 16];
+        muted[9] = true;
+        let mut streamed = Vec::new();
+        for offset in (0..stream.len).step_by(333) {
+            stream.render(offset, 333.min(stream.len - offset), &muted);
+            streamed.extend_from_slice(&stream.buf);
+        }
+        assert_eq!(streamed, expected);
+    }
+
+    #[test]
+    fn ring_buffer_wraps_around() {
+        let mut buffer = StackRingBuffer::<i32, 4>::new();
+        assert!(buffer.is_empty());
+        for i in 0..3 {
+            assert_eq!(buffer.push_overflow(i), None);
+        }
+        assert_eq!(buffer.pop(), Some(0));
+        assert_eq!(buffer.pop(), Some(1));
+
+        // Lese- und Schreibposition liegen jetzt beiderseits des Array-Endes
+        for i in 3..6 {
+            assert_eq!(buffer.push_overflow(i), None);
+        }
+        assert_eq!(buffer.len(), 4);
+        assert_eq!(buffer.iter().copied().collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+
+        // Voll: das älteste Element fällt heraus
+        assert_eq!(buffer.push_overflow(6), Some(2));
+        assert_eq!(buffer.drain().collect::<Vec<_>>(), vec![3, 4, 5, 6]);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.iter().count(), 0);
+    }
+
+    #[test]
+    fn corner_coverage_fades_out_at_the_edge() {
+        let r = 6;
+        assert_eq!(corner_coverage(0, 0, r), 1.0);
+        // Auf den Achsen endet der Kreis an der Außenkante von Pixel r
+        assert_eq!(corner_coverage(r, 0, r), 1.0);
+        // Schräg dazwischen liegt der Rand mitten in einem Pixel
+        let edge = corner_coverage(4, 5, r);
+        assert!(edge > 0.0 && edge < 1.0);
+        assert_eq!(corner_coverage(r, r, r), 0.0);
+    }
+
+    #[test]
+    fn parse_args_needs_exactly_one_file_and_known_options() {
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        let opts = parse_args(&args(&["-tm", "song.mid", "--fullscreen"])).unwrap();
+        assert_eq!(opts.midifile, "song.mid");
+        assert!(opts.use_timidity && opts.fullscreen);
+
+        assert!(parse_args(&args(&["-x", "song.mid"])).is_err());
+        assert!(parse_args(&args(&["a.mid", "b.mid"])).is_err());
+        assert!(parse_args(&args(&["-tm"])).is_err());
+        assert!(parse_args(&args(&["--help"])).unwrap().help);
+
+        let opts = parse_args(&args(&["--render-frames=out", "--fps=60", "song.mid"])).unwrap();
+        assert_eq!(opts.render_frames.as_deref(), Some("out"));
+        assert_eq!(opts.fps, 60.0);
+        assert!(parse_args(&args(&["--fps=0", "song.mid"])).is_err());
+
+        let opts = parse_args(&args(&["--no-vsync", "--fps-cap=30", "song.mid"])).unwrap();
+        assert_eq!((opts.vsync, opts.fps_cap), (false, Some(30.0)));
+        assert!(parse_args(&args(&["--fps-cap=-5", "song.mid"])).is_err());
+
+        let opts = parse_args(&args(&["--bass", "--split=55", "song.mid"])).unwrap();
+        assert_eq!((opts.staff_mode, opts.split_key), (StaffMode::Bass, 55));
+        assert!(parse_args(&args(&["--split=C4", "song.mid"])).is_err());
+
+        let opts = parse_args(&args(&["--colors=0=ff0000, 9=#ffffff", "song.mid"])).unwrap();
+        assert_eq!(opts.palette[0], Color::RGB(255, 0, 0));
+        assert_eq!(opts.palette[9], Color::RGB(255, 255, 255));
+        assert_eq!(opts.palette[1], default_channel_color(1));
+        assert!(parse_args(&args(&["--colors=16=ff0000", "song.mid"])).is_err());
+        assert!(parse_args(&args(&["--colors=0=red", "song.mid"])).is_err());
+    }
+
+    #[test]
+    fn session_survives_a_round_trip() {
+        let session = Session {
+            position: 83.25, tempo: 0.8, view_mode: 2, pixels_per_second: 234.375,
+            muted: std::array::from_fn(|c| c == 1 || c == 9),
+            loop_a: Some(80.0), loop_b: None
+        };
+        let text = session.to_string();
+        assert!(text.contains("muted=2,10\n"));
+
+        let base = Session {loop_b: Some(1.0), ..session.clone()};
+        assert_eq!(base.parse(&text), Session {loop_b: None, ..session.clone()});
+
+        // Ungültige Einträge behalten den bisherigen Wert
+        let parsed = session.clone().parse("tempo=-1\nview=7\nposition=abc\nunbekannt");
+        assert_eq!(parsed, session);
+    }
+
+    #[test]
+    fn tempo_readout_follows_tempo_changes() {
+        let tempo = |abs_tick, tempo_micros| MidiEvent {
+            abs_tick, event_type: EventType::SetTempo, channel: 0, track: 0, note: 0,
+            velocity: 0, tempo_micros
+        };
+        // 96 Ticks pro Viertel: nach zwei Schlägen zu 120 BPM zwei
+        // Änderungen zugleich, von denen die letzte gilt
+        let events = [tempo(192, 1_000_000), tempo(192, 400_000)];
+        let changes = build_tempo_changes(&events, 96, None);
+        assert_eq!(changes, vec![(0.0, 120.0), (1.0, 150.0)]);
+        assert_eq!(tempo_at(&changes, 0.99), Some(120.0));
+        assert_eq!(tempo_at(&changes, 1.0), Some(150.0));
+
+        // Bei halbem Tempo wird die Änderung später erreicht
+        assert_eq!(build_tempo_changes(&events, 96, Some(0.5))[1].0, 2.0);
+        assert!(build_tempo_changes(&events, 0xE250, None).is_empty());
+    }
+
+    #[test]
+    fn transpose_leaves_drums_in_place() {
+        let event = |abs_tick, event_type, channel, note| MidiEvent {
+            abs_tick, event_type, channel, track: 0, note, velocity: 100, tempo_micros: 0
+        };
+        // Klavier und Bass Drum, die Bass Drum ohne Note Off
+        let events = [event(0, EventType::NoteOn, 0, 60), event(0, EventType::NoteOn, 9, 36),
+            event(96, EventType::NoteOff, 0, 60)];
+        let (notes, _) = convert_to_notes(&events, 96, None, 12);
+        let keys: Vec<(i32, i32)> = notes.iter().map(|n| (n.channel, n.midi_key)).collect();
+        assert_eq!(keys, vec![(0, 72), (9, 36)]);
+        // Aus dem MIDI-Bereich fällt nur das Klavier
+        let (notes, _) = convert_to_notes(&events, 96, None, 100);
+        assert_eq!(notes.iter().map(|n| n.channel).collect::<Vec<_>>(), vec![9]);
+
+        // --transpose-staff verschiebt das Schlagzeug ebenso wenig
+        assert_eq!(display_key(&notes[0], -12), 36);
+        let piano = Note {channel: 0, ..notes[0].clone()};
+        assert_eq!(display_key(&piano, -12), 24);
+    }
+
+    #[test]
+    fn pedal_bar_follows_the_sustain_pedal_of_any_channel() {
+        let pedal = |abs_tick, channel, velocity| MidiEvent {
+            abs_tick, event_type: EventType::Sustain, channel, track: 0, note: 0,
+            velocity, tempo_micros: 0
+        };
+        // 96 Ticks pro Viertel bei 120 BPM: ein Schlag = 0,5 s
+        let events = [pedal(96, 0, 127), pedal(192, 1, 100), pedal(288, 0, 0),
+            pedal(384, 1, 0), pedal(480, 1, 10)];
+        let changes = build_pedal_changes(&events, 96, None);
+        assert_eq!(changes, vec![(0.5, true), (2.0, false)]);
+        assert!(!pedal_at(&changes, 0.49));
+        assert!(pedal_at(&changes, 1.5));
+        assert!(!pedal_at(&changes, 2.0));
+    }
+
+    #[test]
+    fn midi_schedule_sends_notes_up_to_the_playhead() {
+        let note = |start_time: f64, duration: f64, midi_key: i32| Note {
+            start_time, duration, midi_key, velocity: 100, channel: 1, track: 0, program: 0,
+            bends: Vec::new()
+        };
+        let mut schedule = midi_out::MidiSchedule::new(&[note(0.0, 0.5, 60), note(0.5, 0.5, 60),
+            note(0.2, 2.0, 64)]);
+        let mut out = Vec::new();
+        schedule.advance(0.0, &mut out);
+        assert_eq!(out, vec![[0x91, 60, 100]]);
+
+        // Bei gleicher Zeit erst das Note Off, dann der neue Anschlag
+        out.clear();
+        schedule.advance(0.3, &mut out);
+        schedule.advance(0.5, &mut out);
+        assert_eq!(out, vec![[0x91, 64, 100], [0x81, 60, 0], [0x91, 60, 100]]);
+
+        // Zurückspulen: erst alles aus, Noten vor dem Sprungziel bleiben stumm
+        out.clear();
+        schedule.advance(0.1, &mut out);
+        schedule.advance(0.4, &mut out);
+        assert_eq!(out, vec![[0x81, 60, 0], [0x81, 64, 0], [0x91, 64, 100]]);
+        out.clear();
+        schedule.silence(&mut out);
+        assert_eq!(out, vec![[0x81, 64, 0]]);
+    }
+
+    #[test]
+    fn split_point_chooses_the_staff_for_ledger_lines() {
+        // A3 (Taste 57) liegt auf der obersten Bass-Linie, Step -2
+        let grand = StaffMode::Grand;
+        assert!(grand.in_bass(57, 60));
+        assert_eq!(grand.ledger_steps(true, -2), None);
+        // Mit --split=55 gehört sie zum Violinschlüssel und braucht die
+        // Hilfslinien von C4 (0) abwärts
+        assert!(!grand.in_bass(57, 55));
+        assert_eq!(grand.ledger_steps(false, -2), Some((-2, 0)));
+        // Umgekehrt E4 (Step 2) im Bass mit --split=65
+        assert!(grand.in_bass(64, 65));
+        assert_eq!(grand.ledger_steps(true, 2), Some((0, 2)));
+        // Zwischen den Systemen bleibt es bei der Linie des Mittel-C
+        assert_eq!(grand.ledger_steps(false, -1), Some((0, 0)));
+        assert_eq!(grand.ledger_steps(true, 1), Some((0, 0)));
+        // Nur Violinschlüssel: D4 hängt unter dem System, C4 bekommt die Linie
+        assert_eq!(StaffMode::Treble.ledger_steps(false, 1), None);
+        assert_eq!(StaffMode::Treble.ledger_steps(false, 0), Some((0, 0)));
+    }
+
+    #[test]
+    fn declick_fades_only_the_edges() {
+        // 5 ms bei 8000 Hz sind 40 Samples Blende an jedem Ende
+        let mut buf = vec![0.5f32; 400];
+        declick(&mut buf, 8000);
+        assert_eq!(buf[0], 0.0);
+        assert_eq!(buf[399], 0.0);
+        assert!(buf[1] > 0.0 && buf[1] < buf[20] && buf[20] < 0.5);
+        assert!(buf[40..360].iter().all(|&s| s == 0.5));
+    }
+
+    #[test]
+    fn resampling_keeps_length_and_pitch() {
+        // Eine Sekunde 440 Hz von 22050 Hz (Timidity am Minimum) auf 44100
+        // und 8000 Hz; gezählt werden die Nulldurchgänge nach oben
+        let sine = |rate: i32| -> Vec<f32> {
+            (0..rate).map(|i| (2.0 * PI * 440.0 * i as f64 / rate as f64).sin() as f32).collect()
+        };
+        let rising = |v: &[f32]| v.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        let src = sine(22050);
+        for kind in [Resample::Linear, Resample::Cubic] {
+            for dst_rate in [44100, 8000] {
+                let out = resample(&src, 22050, dst_rate, kind);
+                assert_eq!(out.len(), dst_rate as usize);
+                assert!(rising(&out).abs_diff(440) <= 1, "{}", rising(&out));
+                // Am Ende fehlen die Stützstellen, dort wird nur gehalten
+                let ideal = sine(dst_rate);
+                let error = out.iter().zip(&ideal).take(out.len() - 2)
+                    .map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+                assert!(error < 0.01, "{:?} {} Hz: {}", kind, dst_rate, error);
+            }
+        }
+        assert_eq!(resample(&src, 22050, 22050, Resample::Cubic), src);
+    }
+
+    #[test]
+    fn png_chunks_have_valid_crc() {
+        // Bekannte Prüfsumme des leeren IEND-Chunks
+        assert_eq!(png::crc32(b"IEND"), 0xAE42_6082);
+
+        let mut out = Vec::new();
+        png::write_png(&mut out, 2, 1, &[255, 0, 0, 255, 0, 0]).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&out[16..24], &[0, 0, 0, 2, 0, 0, 0, 1]); // Breite, Höhe
+        assert_eq!(&out[out.len() - 12..], b"\0\0\0\0IEND\xAE\x42\x60\x82");
+    }
+
+    #[test]
+    fn end_of_track_skips_trailing_junk() {
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,       // C4 an
+            0x60, 0x80, 60, 0,         // C4 aus
+            0x00, 0xFF, 0x2F, 0x00,    // End of Track
+            0x00, 0x90, 0xFF, 0x12,    // Datenmüll innerhalb der Spurlänge
+        ];
+        let mut file = midi_file(1, 96,
+            &[track, &[0x00, 0x91, 64, 90, 0x60, 0x81, 64, 0, 0x00, 0xFF, 0x2F, 0x00]]);
+
+        let (events, _) = parse_midi_from(&mut std::io::Cursor::new(file.clone())).unwrap();
+        let keys: Vec<(u8, u8)> = events.iter().map(|e| (e.channel, e.note)).collect();
+        assert_eq!(keys, vec![(0, 60), (1, 64), (0, 60), (1, 64)]);
+
+        // Abgeschnittene Datei: die zweite Spur passt nicht mehr hinein
+        file.truncate(file.len() - 2);
+        let err = parse_midi_from(&mut std::io::Cursor::new(file)).unwrap_err();
+        assert!(err.to_string().contains("12 Bytes angegeben, 10 übrig"), "{}", err);
+    }
+
+    #[test]
+    fn extended_header_is_skipped() {
+        // Header mit zwei zusätzlichen Bytes, die übersprungen werden müssen
+        let track: &[u8] = &[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        let mut file = midi_file(0, 96, &[track]);
+        file[7] = 8; // Länge des Headers
+        file.splice(14..14, [0xAB, 0xCD]);
+
+        let (events, division) = parse_midi_from(&mut std::io::Cursor::new(file)).unwrap();
+        assert_eq!(division, 96);
+        let keys: Vec<(EventType, u8)> = events.iter().map(|e| (e.event_type, e.note)).collect();
+        assert_eq!(keys, vec![(EventType::NoteOn, 60), (EventType::NoteOff, 60)]);
+    }
+
+    #[test]
+    fn notes_remember_their_track() {
+        // Zwei Spuren, beide auf Kanal 0, die erste nur mit dem Tempo
+        let file = midi_file(1, 96, &[
+            &[0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x00, 0xFF, 0x2F, 0x00],
+            &[0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00],
+            &[0x30, 0x90, 64, 100, 0x60, 0x90, 64, 0, 0x00, 0xFF, 0x2F, 0x00],
+        ]);
+
+        let (events, division) = parse_midi_from(&mut std::io::Cursor::new(file)).unwrap();
+        assert_eq!(events[0].track, 0);
+        let (notes, _) = convert_to_notes(&events, division, None, 0);
+        let tracks: Vec<(i32, i32, u16)> =
+            notes.iter().map(|n| (n.midi_key, n.channel, n.track)).collect();
+        assert_eq!(tracks, vec![(60, 0, 1), (64, 0, 2)]);
+        assert_ne!(get_track_color(1), get_track_color(2));
+        assert_ne!(get_track_color(9), default_channel_color(9));
+    }
+
+    #[test]
+    fn oversized_meta_event_is_rejected() {
+        // Text-Event mit 100 Bytes Länge in einer Spur mit 4 Bytes Rest,
+        // gefolgt von einer zweiten Spur, in die es hineinreichen würde
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,
+            0x00, 0xFF, 0x01, 0x64, b'a', b'b', b'c', b'd',
+        ];
+        let file = midi_file(1, 96,
+            &[track, &[0x00, 0x90, 64, 100, 0x60, 0x80, 64, 0, 0x00, 0xFF, 0x2F, 0x00]]);
+        let err = parse_midi_from(&mut std::io::Cursor::new(file)).unwrap_err();
+        assert!(err.to_string().contains("100 Bytes angegeben, 4 übrig"), "{}", err);
+    }
+
+    #[test]
+    fn zero_division_is_rejected() {
+        let end: &[u8] = &[0x00, 0xFF, 0x2F, 0x00];
+        // SMPTE mit oberem Byte 0x80 (-128 Bilder pro Sekunde)
+        for division in [0, 0x8028] {
+            let file = midi_file(0, division, &[end]);
+            let err = parse_midi_from(&mut std::io::Cursor::new(file)).unwrap_err();
+            assert!(err.to_string().contains("Zeitbasis"), "{}", err);
+        }
+        assert_eq!(smpte_seconds_per_tick(0x8028), None);
+        assert_eq!(smpte_seconds_per_tick(0xE728), Some(1.0 / (25.0 * 40.0)));
+    }
+
+    #[test]
+    fn tempo_change_during_note_splits_its_duration() {
+        // Division 96: ein Schlag bei 120 BPM (0,5 s), dann Wechsel auf
+        // 60 BPM mitten in der gehaltenen Note, ein Schlag mehr (1 s)
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,                      // C4 an
+            0x60, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // 60 BPM
+            0x60, 0x80, 60, 0,                        // C4 aus
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let file = midi_file(0, 96, &[track]);
+
+        let (events, division) = parse_midi_from(&mut std::io::Cursor::new(file)).unwrap();
+        let (notes, _) = convert_to_notes(&events, division, None, 0);
+        assert_eq!(notes.len(), 1);
+        assert!((notes[0].duration - 1.5).abs() < 1e-9, "{}", notes[0].duration);
+
+        // Bei doppeltem Tempo halbiert sich alles
+        let (notes, _) = convert_to_notes(&events, division, Some(2.0), 0);
+        assert!((notes[0].duration - 0.75).abs() < 1e-9, "{}", notes[0].duration);
+        let changes = build_tempo_changes(&events, division, Some(2.0));
+        assert_eq!(changes, vec![(0.0, 120.0), (0.25, 60.0)]);
+    }
+
+    #[test]
+    fn note_names_follow_the_key() {
+        assert_eq!(note_name(60, 0), "C4");
+        assert_eq!(note_name(78, KeyInfo::from_name("G").0), "F#5");
+        assert_eq!(note_name(70, KeyInfo::from_name("F").0), "Bb4");
+        assert_eq!(note_name(0, 0), "C-1");
+        // Alle Zeichen gibt es in der Pixelschrift
+        assert!(note_name(70, 5).chars().chain("F#-1".chars()).all(|c| pixel_glyph(c) != [0; 5]));
+    }
+
+    #[test]
+    fn key_signature_matches_key_names() {
+        let names = ["Ges", "Des", "As", "Es", "Bes", "F", "C", "G", "D", "A", "E", "H", "Ges"];
+        for (sf, name) in (-6..=6).zip(names) {
+            assert_eq!(KeyInfo::from_midi(sf).0, KeyInfo::from_name(name).0, "sf = {}", sf);
+        }
+        assert_eq!(KeyInfo::from_midi(-3), KeyInfo(3, 3));
+        assert_eq!(KeyInfo::from_midi(4), KeyInfo(4, 4));
     }
-    Ok(())
 }
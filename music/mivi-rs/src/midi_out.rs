@@ -0,0 +1,155 @@
+// =====================================================================
+// MIDI-AUSGABE (für --midi-out)
+// =====================================================================
+
+// Schickt die Noten synchron zur Wiedergabe als Note On/Off an einen
+// MIDI-Ausgang, etwa einen Hardware- oder Software-Synthesizer. Der
+// Zeitplan hängt nur an der Zeit, die die Hauptschleife ohnehin in jedem
+// Bild berechnet: alles bis zur aktuellen Zeit wird gesendet. Beim
+// Spulen, in der Pause und beim Beenden verstummen die klingenden Noten.
+// Die Verbindung selbst braucht das Feature "midi-out" (Crate midir).
+
+use crate::Note;
+
+// Ein Vorwärtssprung um mehr als das gilt als Spulen, nicht als langsames Bild
+#[cfg_attr(not(feature = "midi-out"), allow(dead_code))]
+const MIDI_JUMP_SECONDS: f64 = 0.5;
+
+// Zeitplan der Nachrichten; ohne das Feature braucht ihn nur der Test
+#[cfg_attr(not(feature = "midi-out"), allow(dead_code))]
+pub struct MidiSchedule {
+    // (Zeit, Nachricht), bei gleicher Zeit Note Off vor Note On
+    events: Vec<(f64, [u8; 3])>,
+    next: usize,
+    last_time: f64,
+    // Offene Note Ons pro Kanal und Taste
+    sounding: Vec<u16>
+}
+
+#[cfg_attr(not(feature = "midi-out"), allow(dead_code))]
+impl MidiSchedule {
+    pub fn new(notes: &[Note]) -> Self {
+        let mut events = Vec::with_capacity(notes.len() * 2);
+        for n in notes {
+            let (ch, key) = ((n.channel & 15) as u8, n.midi_key.clamp(0, 127) as u8);
+            let vel = n.velocity.clamp(1, 127) as u8;
+            events.push((n.start_time, [0x90 | ch, key, vel]));
+            events.push((n.start_time + n.duration, [0x80 | ch, key, 0]));
+        }
+        events.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1[0].cmp(&b.1[0])));
+        MidiSchedule {events, next: 0, last_time: f64::NEG_INFINITY, sounding: vec![0; 16 * 128]}
+    }
+
+    // Hängt alle Nachrichten bis `current_time` an `out` an. Nach einem
+    // Sprung geht es ab der neuen Zeit weiter; dort schon klingende
+    // Noten setzen erst mit ihrem nächsten Anschlag ein.
+    pub fn advance(&mut self, current_time: f64, out: &mut Vec<[u8; 3]>) {
+        if current_time < self.last_time || current_time - self.last_time > MIDI_JUMP_SECONDS {
+            self.silence(out);
+            self.next = self.events.partition_point(|&(t, _)| t < current_time);
+        }
+        while let Some(&(t, msg)) = self.events.get(self.next) && t <= current_time {
+            self.next += 1;
+            let count = &mut self.sounding[((msg[0] & 15) as usize) * 128 + msg[1] as usize];
+            if msg[0] & 0xF0 == 0x90 {
+                *count += 1;
+            } else if *count > 0 {
+                *count -= 1;
+            } else {
+                continue; // Anschlag lag vor dem Sprung
+            }
+            out.push(msg);
+        }
+        self.last_time = current_time;
+    }
+
+    // Note Off für alle klingenden Noten
+    pub fn silence(&mut self, out: &mut Vec<[u8; 3]>) {
+        for (i, count) in self.sounding.iter_mut().enumerate() {
+            for _ in 0..*count {
+                out.push([0x80 | (i / 128) as u8, (i % 128) as u8, 0]);
+            }
+            *count = 0;
+        }
+    }
+}
+
+#[cfg(feature = "midi-out")]
+pub struct MidiOut {
+    conn: midir::MidiOutputConnection,
+    schedule: MidiSchedule,
+    buf: Vec<[u8; 3]>
+}
+
+#[cfg(feature = "midi-out")]
+impl MidiOut {
+    // Verbindet mit dem ersten Ausgang, dessen Name `port` enthält (ohne
+    // Beachtung der Groß-/Kleinschreibung), bei leerem `port` mit dem ersten
+    pub fn connect(port: &str, notes: &[Note]) -> Result<MidiOut, Box<dyn std::error::Error>> {
+        let output = midir::MidiOutput::new("mivi")?;
+        let ports = output.ports();
+        let names: Vec<String> = ports.iter()
+            .map(|p| output.port_name(p).unwrap_or_default())
+            .collect();
+        let wanted = port.to_lowercase();
+        let Some(index) = names.iter().position(|name| name.to_lowercase().contains(&wanted)) else {
+            return Err(format!("Kein MIDI-Ausgang \"{}\" gefunden, vorhanden: {}", port,
+                if names.is_empty() { "keiner".to_string() } else { names.join(", ") }).into());
+        };
+        let conn = output.connect(&ports[index], "mivi")
+            .map_err(|e| format!("MIDI-Ausgang {} nicht verfügbar: {}", names[index], e))?;
+        println!("MIDI-Ausgang: {}", names[index]);
+        Ok(MidiOut {conn, schedule: MidiSchedule::new(notes), buf: Vec::new()})
+    }
+
+    // Neue Noten nach einem Tempowechsel, weiter ab der nächsten Zeit
+    pub fn set_notes(&mut self, notes: &[Note]) {
+        self.schedule.silence(&mut self.buf);
+        self.schedule = MidiSchedule::new(notes);
+        self.flush();
+    }
+
+    // Einmal pro Bild mit der aktuellen Wiedergabezeit aufzurufen
+    pub fn update(&mut self, current_time: f64, paused: bool) {
+        if paused {
+            self.schedule.silence(&mut self.buf);
+        } else {
+            self.schedule.advance(current_time, &mut self.buf);
+        }
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        for msg in self.buf.drain(..) {
+            // Ein abgezogenes Gerät soll die Wiedergabe nicht abbrechen
+            let _ = self.conn.send(&msg);
+        }
+    }
+}
+
+#[cfg(feature = "midi-out")]
+impl Drop for MidiOut {
+    fn drop(&mut self) {
+        self.schedule.silence(&mut self.buf);
+        self.flush();
+    }
+}
+
+// Ohne das Feature gibt es keinen Ausgang; `connect` meldet das
+#[cfg(not(feature = "midi-out"))]
+pub enum MidiOut {}
+
+#[cfg(not(feature = "midi-out"))]
+impl MidiOut {
+    pub fn connect(_port: &str, _notes: &[Note]) -> Result<MidiOut, Box<dyn std::error::Error>> {
+        Err("--midi-out erfordert den Build mit --features midi-out".into())
+    }
+
+    pub fn set_notes(&mut self, _notes: &[Note]) {
+        match *self {}
+    }
+
+    pub fn update(&mut self, _current_time: f64, _paused: bool) {
+        match *self {}
+    }
+}
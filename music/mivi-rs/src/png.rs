@@ -0,0 +1,145 @@
+// =====================================================================
+// PNG-EXPORT (für --render-frames)
+// =====================================================================
+
+// Minimaler PNG-Encoder ohne Abhängigkeiten für RGB-Bilder mit 8 Bit
+// pro Kanal. Jede Zeile wird mit dem Sub-Filter vorverarbeitet, danach
+// komprimiert ein einzelner Deflate-Block mit festen Huffman-Codes
+// gleiche Bytes in Folge (Distanz 1). Das genügt für die großen ein-
+// farbigen Flächen der Visualisierung.
+
+use std::io::{self, Write};
+
+// Basislänge und Anzahl der Zusatzbits der Deflate-Längencodes 257-285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &x in chunk {
+            a += x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+// Schreibt Bits beginnend beim niederwertigsten Bit, wie Deflate es will
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u32
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, count: u32) {
+        self.acc |= value << self.bits;
+        self.bits += count;
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    // Huffman-Codes stehen mit dem höchstwertigen Bit zuerst im Strom
+    fn put_code(&mut self, code: u32, count: u32) {
+        self.put(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn put_symbol(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol as u32, 8),
+            144..=255 => self.put_code(0x190 + (symbol - 144) as u32, 9),
+            256..=279 => self.put_code((symbol - 256) as u32, 7),
+            _ => self.put_code(0xC0 + (symbol - 280) as u32, 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+        self.out
+    }
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {out: Vec::new(), acc: 0, bits: 0};
+    w.put(1, 1); // letzter Block
+    w.put(1, 2); // feste Huffman-Codes
+
+    let mut i = 0;
+    while i < data.len() {
+        let run = if i > 0 {
+            data[i..].iter().take(258).take_while(|&&b| b == data[i - 1]).count()
+        } else {
+            0
+        };
+        if run >= 3 {
+            let index = LENGTH_BASE.iter().rposition(|&base| base as usize <= run).unwrap();
+            w.put_symbol(257 + index as u16);
+            w.put((run - LENGTH_BASE[index] as usize) as u32, LENGTH_EXTRA[index] as u32);
+            w.put_code(0, 5); // Distanzcode 0: Distanz 1
+            i += run;
+        } else {
+            w.put_symbol(data[i] as u16);
+            i += 1;
+        }
+    }
+    w.put_symbol(256); // Blockende
+    w.finish()
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    out.write_all(&body)?;
+    out.write_all(&crc32(&body).to_be_bytes())
+}
+
+// `rgb` enthält die Zeilen ohne Zwischenraum, 3 Bytes pro Pixel
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let stride = width as usize * 3;
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in rgb.chunks(stride).take(height as usize) {
+        raw.push(1); // Filter "Sub": Differenz zum Pixel links daneben
+        raw.extend(row.iter().enumerate()
+            .map(|(x, &b)| if x < 3 { b } else { b.wrapping_sub(row[x - 3]) }));
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(deflate(&raw));
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 Bit, RGB, keine Verschachtelung
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    write_chunk(out, b"IHDR", &header)?;
+    write_chunk(out, b"IDAT", &zlib)?;
+    write_chunk(out, b"IEND", &[])
+}
@@ -0,0 +1,68 @@
+// =====================================================================
+// SITZUNG MERKEN (für --resume)
+// =====================================================================
+
+// Stand der Wiedergabe, der neben der MIDI-Datei als "<Datei>.mivi"
+// abgelegt wird: eine Zeile "schlüssel=wert" pro Eintrag. Zeiten sind
+// Sekunden beim Originaltempo, damit sie zu jedem Tempo passen.
+// Unbekannte oder ungültige Zeilen werden beim Lesen übergangen.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub position: f64,
+    pub tempo: f64,
+    pub view_mode: u8,
+    pub pixels_per_second: f64,
+    pub muted: [bool; 16],
+    pub loop_a: Option<f64>,
+    pub loop_b: Option<f64>
+}
+
+pub fn session_path(midifile: &str) -> String {
+    format!("{}.mivi", midifile)
+}
+
+impl Session {
+    // Übernimmt aus `text` alle gültigen Einträge, der Rest bleibt wie in `self`
+    pub fn parse(mut self, text: &str) -> Session {
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else { continue; };
+            let value = value.trim();
+            let number = value.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0);
+            match key.trim() {
+                "position" => if let Some(v) = number { self.position = v; },
+                "tempo" => if let Some(v) = number.filter(|&v| v > 0.0) { self.tempo = v; },
+                "view" => if let Ok(v) = value.parse::<u8>() && v < 3 { self.view_mode = v; },
+                "zoom" => if let Some(v) = number.filter(|&v| v > 0.0) { self.pixels_per_second = v; },
+                "muted" => {
+                    self.muted = [false; 16];
+                    for ch in value.split(',').filter_map(|c| c.trim().parse::<usize>().ok()) {
+                        if (1..=16).contains(&ch) { self.muted[ch - 1] = true; }
+                    }
+                },
+                "loop_a" => self.loop_a = number,
+                "loop_b" => self.loop_b = number,
+                _ => {}
+            }
+        }
+        self
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "position={:.3}", self.position)?;
+        writeln!(f, "tempo={}", self.tempo)?;
+        writeln!(f, "view={}", self.view_mode)?;
+        writeln!(f, "zoom={}", self.pixels_per_second)?;
+        let muted: Vec<String> = (0..16).filter(|&c| self.muted[c])
+            .map(|c| (c + 1).to_string()).collect();
+        writeln!(f, "muted={}", muted.join(","))?;
+        // Leer, wenn der Schleifenpunkt nicht gesetzt ist
+        let point = |p: Option<f64>| p.map_or(String::new(), |p| format!("{:.3}", p));
+        writeln!(f, "loop_a={}", point(self.loop_a))?;
+        writeln!(f, "loop_b={}", point(self.loop_b))
+    }
+}
@@ -5,10 +5,12 @@
 use sdl2::pixels::Color;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
-use sdl2::rect::Rect;
-use crate::{Env, RenderView, Note};
-use crate::PIXELS_PER_SECOND;
+use sdl2::rect::{Point, Rect};
+use crate::{Env, RenderView, Note, display_key, get_note_base_color};
+use crate::theory::{determine_accidental, get_staff_step, is_flat_root};
 
+#[cfg(feature = "image")]
+use crate::theory::Accidental;
 #[cfg(feature = "image")]
 use sdl2::{
     image::{LoadTexture, InitFlag},
@@ -27,10 +29,17 @@ const PLAYHEAD_X: i32 = 200;               // X-Position der "Jetzt"-Linie
 const PLAYHEAD_WIDTH: u32 = 3;             // Dicke der "Jetzt"-Linie
 const PLAYHEAD_COLOR: Color = Color::RGB(160, 160, 160);
 
+const BAR_LINE_WIDTH: u32 = 2;             // Taktstrich (auf der Eins)
+const BEAT_LINE_ALPHA: u8 = 50;            // Transparenz der übrigen Schläge (0-255)
+
 const NOTE_HEAD_WIDTH: i32 = 18;           // Breite des Notenkopfs
 const NOTE_HEAD_HEIGHT: i32 = 14;          // Höhe des Notenkopfs (meist == Spacing)
 const NOTE_TRAIL_ALPHA: u8 = 100;          // Transparenz der Schweif-Spur (0-255)
 
+const STEM_LENGTH: i32 = STAFF_LINE_SPACING * 7 / 2; // 3,5 Linienabstände
+const STEM_WIDTH: u32 = 2;
+const FLAG_SPACING: i32 = 7;               // Abstand zweier Fähnchen (Sechzehntel)
+
 // Konfiguration für Liniensystem und Hilfslinien
 const LEDGER_LINE_WIDTH: u32 = 26;   // Etwas breiter als der Notenkopf (18)
 
@@ -114,51 +123,74 @@ impl Textures {
     }
 }
 
+// Welche Notensysteme gezeigt werden
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-enum Accidental {
-    None,    // Kein Vorzeichen an der Note nötig
-    Natural, // ♮ Auflösungszeichen
-    Sharp,   // # Kreuz
-    Flat,    // b Be
-}
-
-fn is_flat_root(root: i32) -> bool {
-    // F(5), Bes(10), Es(3), As(8), Des(1), Ges(6)
-    matches!(root, 5 | 10 | 3 | 8 | 1 | 6)
+pub enum StaffMode {
+    Grand,  // Violin- und Bassschlüssel (Klavier-Akkolade)
+    Treble, // nur Violinschlüssel, bspw. für Flöte
+    Bass,   // nur Bassschlüssel, bspw. für Bassgitarre
 }
 
-fn determine_accidental(midi_key: i32, root: i32) -> Accidental {
-    let n = midi_key.rem_euclid(12);
-    let major_intervals = [0, 2, 4, 5, 7, 9, 11];
-
-    for &interval in &major_intervals {
-        if (root + interval) % 12 == n {
-            return Accidental::None;
+impl StaffMode {
+    fn has_treble(self) -> bool { self != StaffMode::Bass }
+    fn has_bass(self) -> bool { self != StaffMode::Treble }
+
+    // Oberste und unterste Notenlinie in Steps relativ zu C4
+    fn top_step(self) -> i32 { if self.has_treble() {10} else {-2} }
+    fn bottom_step(self) -> i32 { if self.has_bass() {-10} else {2} }
+
+    // Ob eine Taste zum Bass-System gehört. In der Akkolade sind das die
+    // Noten unterhalb des Trennpunkts (--split).
+    pub fn in_bass(self, midi_key: i32, split_key: i32) -> bool {
+        match self {
+            StaffMode::Grand => midi_key < split_key,
+            StaffMode::Treble => false,
+            StaffMode::Bass => true,
         }
     }
 
-    // Ist der Ton physikalisch eine weiße Taste (C, D, E, F, G, A, B)?
-    // Wenn ja, aber nicht in der Skala (siehe oben), muss er aufgelöst werden.
-    let is_white_key = matches!(n, 0 | 2 | 4 | 5 | 7 | 9 | 11);
-
-    if is_white_key {
-        return Accidental::Natural;
-    }
-
-    // Chromatische Anpassung (# oder b)
-    // Der Ton ist eine schwarze Taste und nicht in der Skala.
-    // Entscheidung anhand des Quintenzirkels (Flat Roots vs Sharp Roots).
-    if is_flat_root(root) {
-        Accidental::Flat
-    } else {
-        Accidental::Sharp
+    // Hilfslinien einer Note auf `rel_step` (relativ zu C4) als Bereich
+    // von Steps, bezogen auf das System, zu dem die Note gehört. Nur jede
+    // gerade Zahl darin ist eine Linie.
+    pub fn ledger_steps(self, in_bass: bool, rel_step: i32) -> Option<(i32, i32)> {
+        let (top_step, bottom_step) = if in_bass {(-2, -10)} else {(10, 2)};
+        if self == StaffMode::Grand && rel_step > -2 && rel_step < 2 {
+            // "Niemandsland" zwischen Bass (-2) und Treble (2): Steps -1, 0, 1
+            // Mittel-C ist Step 0. Die Notenköpfe von H3 und D4 berühren
+            // dessen Hilfslinie und hängen ohne sie zwischen den Systemen,
+            // daher bekommen alle drei die Linie auf der 0.
+            Some((0, 0))
+        } else if rel_step > top_step + 1 {
+            // FALL 1: Note ab der ersten Hilfslinie über der obersten Linie
+            // (F5 bzw. A3 im Bass): Leiter von dieser Hilfslinie bis zur Note
+            Some((top_step + 2, rel_step))
+        } else if rel_step < bottom_step - 1 {
+            // FALL 2: dito unter der untersten Linie (G2 bzw. E4 im Violinschlüssel)
+            Some((rel_step, bottom_step - 2))
+        } else {
+            None
+        }
     }
 }
 
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct KeyInfo(pub i32, pub u8);
 
 impl KeyInfo {
+    // Aus dem Key-Signature-Meta-Event: `sf` Anzahl der Kreuze (positiv)
+    // bzw. Bes (negativ). Moll braucht keine Sonderbehandlung, da die
+    // Vorzeichen denen der parallelen Dur-Tonart entsprechen.
+    pub fn from_midi(sf: i8) -> KeyInfo {
+        match sf {
+            // Cis-Dur und Ces-Dur enharmonisch als Des-Dur bzw. H-Dur
+            7.. => KeyInfo(1, 5),
+            ..=-7 => KeyInfo(11, 5),
+            1..=6 => KeyInfo((7 * sf as i32) % 12, sf as u8),
+            _ => KeyInfo((5 * -sf as i32) % 12, (-sf) as u8)
+        }
+    }
+
     pub fn from_name(key: &str) -> KeyInfo {
         match key {
             "C" | "a" | "Am"  => KeyInfo(0, 0),
@@ -178,41 +210,6 @@ impl KeyInfo {
     }
 }
 
-// Berechnet den vertikalen "Step" im Notensystem relativ zu C4 (Midi 60)
-// C4 = 0, D4 = 1, E4 = 2 ...
-fn get_staff_step(midi: i32, flat: bool) -> i32 {
-    let octave = (midi / 12) - 1; // MIDI Oktave (-1 für interne Berechnung)
-    let note_in_octave = midi % 12;
-
-    // Mapping: Semitone Index -> Staff Step Index (C=0, D=1, E=2, F=3, G=4, A=5, B=6)
-    // Schwarze Tasten (Sharps) landen auf der gleichen Höhe wie die Note darunter
-    let step_in_octave = if flat {
-        match note_in_octave {
-            0 => 0,      // C
-            1 | 2 => 1,  // Db, D
-            3 | 4 => 2,  // Eb, E
-            5 => 3,      // F
-            6 | 7 => 4,  // Gb, G
-            8 | 9 => 5,  // Ab, A
-            10 | 11 => 6,// Bb, B
-            _ => 0
-        }
-    } else {
-        match note_in_octave {
-            0 | 1 => 0, // C, C#
-            2 | 3 => 1, // D, D#
-            4 => 2,     // E
-            5 | 6 => 3, // F, F#
-            7 | 8 => 4, // G, G#
-            9 | 10 => 5,// A, A#
-            11 => 6,    // B
-            _ => 0
-        }
-    };
-
-    (octave * 7) + step_in_octave
-}
-
 #[cfg(feature = "image")]
 fn render_accidentals(env: &mut Env, textures: &mut Textures, x: i32, y: i32, flat: bool) {
     const X_SCALE: i32 = 100;
@@ -287,23 +284,25 @@ fn render_keys(env: &mut Env, textures: &mut Textures, center_y: i32, flat: bool
     // Wir berechnen die Y-Position der G-Linie:
     let g4_y = center_y - (4 * STAFF_LINE_SPACING / 2);
 
-    // Wir zeichnen das Bild zentriert um diese Linie und addieren den Offset
-    let rect_treble = Rect::new(
-        20,
-        g4_y - (treble_h / 2) + treble_offset_y,
-        treble_w,
-        treble_h as u32
-    );
+    const X_ACCI: i32 = 68;
+    if env.staff_mode.has_treble() {
+        // Wir zeichnen das Bild zentriert um diese Linie und addieren den Offset
+        let rect_treble = Rect::new(
+            20,
+            g4_y - (treble_h / 2) + treble_offset_y,
+            treble_w,
+            treble_h as u32
+        );
 
-    // Textur kopieren (das 'None' bedeutet: ganzes Quellbild nutzen)
-    env.canvas.copy(&textures.treble_key, None, rect_treble).unwrap();
+        // Textur kopieren (das 'None' bedeutet: ganzes Quellbild nutzen)
+        env.canvas.copy(&textures.treble_key, None, rect_treble).unwrap();
 
-    const X_ACCI: i32 = 68;
-    if env.root_key.0 != 0 {
-        render_accidentals(env, textures, X_ACCI, g4_y - 60, flat);
+        if env.root_key.0 != 0 {
+            render_accidentals(env, textures, X_ACCI, g4_y - 60, flat);
+        }
     }
 
-    if env.show_bass_staff {
+    if env.staff_mode.has_bass() {
         // Bass Reference ist F3 (Step -4)
         let f3_y = center_y - (-4 * STAFF_LINE_SPACING / 2);
 
@@ -329,7 +328,9 @@ fn render_keys(_env: &mut Env, _textures: &Textures, _center_y: i32, _flat: bool
 #[allow(dead_code)]
 pub struct BufferedHead {
     x: i32, y: i32, midi_key: i32,
-    color: Color
+    color: Color,
+    stem_up: bool,
+    flags: u8 // Fähnchen am Hals: 1 für Achtel, 2 für Sechzehntel
 }
 
 // Ein generischer Ringpuffer fester Größe auf dem Stack.
@@ -341,6 +342,8 @@ pub struct StackRingBuffer<T, const N: usize> {
     len: usize
 }
 
+// Nicht alle Methoden braucht der Renderer selbst
+#[allow(dead_code)]
 impl<T, const N: usize> StackRingBuffer<T, N> {
     pub fn new() -> Self {
         const {assert!(N != 0);}
@@ -378,6 +381,30 @@ impl<T, const N: usize> StackRingBuffer<T, N> {
         self.len -= 1;
         item
     }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Alle Elemente vom ältesten zum neuesten, ohne sie zu entfernen
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).filter_map(move |i| self.buffer[(self.head + i) % N].as_ref())
+    }
+
+    /// Entfernt alle Elemente und liefert sie vom ältesten zum neuesten
+    pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.pop())
+    }
+}
+
+impl<T, const N: usize> Default for StackRingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 fn render_note(env: &mut Env, head: &BufferedHead,
@@ -420,11 +447,52 @@ fn render_note(env: &mut Env, head: &BufferedHead,
             crate::CORNER_ALL
         ).unwrap_or(());
     }
+    render_stem(env, head);
+}
+
+// Notenhals: nach oben rechts am Kopf, nach unten links am Kopf.
+// Fähnchen zeigen vom Halsende schräg nach rechts.
+fn render_stem(env: &mut Env, head: &BufferedHead) {
+    let mid_y = head.y + NOTE_HEAD_HEIGHT / 2;
+    let (stem_x, stem_y, end_y, dir) = if head.stem_up {
+        (head.x + NOTE_HEAD_WIDTH - STEM_WIDTH as i32, mid_y - STEM_LENGTH, mid_y - STEM_LENGTH, 1)
+    } else {
+        (head.x, mid_y, mid_y + STEM_LENGTH, -1)
+    };
+    env.canvas.set_draw_color(head.color);
+    env.canvas.fill_rect(Rect::new(stem_x, stem_y, STEM_WIDTH, STEM_LENGTH as u32)).unwrap_or(());
+
+    for i in 0..head.flags as i32 {
+        let y = end_y + dir * i * FLAG_SPACING;
+        for dx in 0..STEM_WIDTH as i32 {
+            env.canvas.draw_line(
+                Point::new(stem_x + dx, y),
+                Point::new(stem_x + dx + 9, y + dir * 12)
+            ).unwrap_or(());
+        }
+    }
+}
+
+// Anzahl der Fähnchen aus der Notenlänge relativ zum Schlag an ihrem
+// Beginn (grob, da MIDI-Noten meist etwas kürzer als notiert sind)
+fn flag_count(beat_grid: &[(f64, bool)], start: f64, duration: f64) -> u8 {
+    let idx = beat_grid.partition_point(|&(t, _)| t <= start);
+    if idx == 0 || idx >= beat_grid.len() {
+        return 0;
+    }
+    let beat = beat_grid[idx].0 - beat_grid[idx - 1].0;
+    if duration <= beat * 0.3 {
+        2
+    } else if duration <= beat * 0.6 {
+        1
+    } else {
+        0
+    }
 }
 
 pub fn render_staff(env: &mut Env, view: &RenderView,
     notes: &Vec<Note>, current_time: f64, textures: &mut Textures,
-    vis_offset: i32
+    vis_offset: i32, beat_grid: &[(f64, bool)]
 ) {
     // Hintergrund
     view.begin(&mut env.canvas, Color::RGB(255, 255, 255));
@@ -437,8 +505,13 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
 
     let flat = is_flat_root(env.root_key.0);
 
-    // Referenzpunkt: Mittleres C (C4, Midi 60) liegt vertikal in der Mitte des Fensters
-    let center_shift = if env.show_bass_staff {0} else {40};
+    // Referenzpunkt: Mittleres C (C4, Midi 60) liegt vertikal in der Mitte des Fensters,
+    // bei nur einem System wird dieses in die Mitte geschoben
+    let center_shift = match env.staff_mode {
+        StaffMode::Grand => 0,
+        StaffMode::Treble => 40,
+        StaffMode::Bass => -40,
+    };
     let center_y = h / 2 + center_shift;
 
     // Berechnung des "Steps" für C4
@@ -455,6 +528,18 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
         h as u32
     )).unwrap_or(());
 
+    // Visuelles Metronom: Playhead blinkt auf der Eins
+    if env.flash_intensity > 0.0 {
+        let Color {r, g, b, ..} = env.beat_color;
+        env.canvas.set_draw_color(Color::RGBA(r, g, b, (env.flash_intensity * 255.0) as u8));
+        env.canvas.fill_rect(Rect::new(
+            PLAYHEAD_X - 2,
+            0,
+            PLAYHEAD_WIDTH + 4,
+            h as u32
+        )).unwrap_or(());
+    }
+
     // -----------------------------------------------------------------
     // Notenlinien (Staff) zeichnen
     // -----------------------------------------------------------------
@@ -478,28 +563,54 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
 
     // Violinschlüssel (Treble): E4, G4, B4, D5, F5
     // Steps relativ zu C4 (0): E4=+2, G4=+4, B4=+6, D5=+8, F5=+10
-    let treble_steps = [2, 4, 6, 8, 10];
-    for s in treble_steps.iter() { draw_staff_line(&mut env.canvas, *s).unwrap_or(()); }
+    if env.staff_mode.has_treble() {
+        let treble_steps = [2, 4, 6, 8, 10];
+        for s in treble_steps.iter() { draw_staff_line(&mut env.canvas, *s).unwrap_or(()); }
+    }
 
     // Bassschlüssel: G2, B2, D3, F3, A3
     // C4 ist Step 0. C3 ist Step -7.
     // G2 = -17 + 4 = -13 ? Nein:
     // C3 = -7. B2 = -8, A2 = -9, G2 = -10.
     // Bass Steps relativ zu C4: A3=-2, F3=-4, D3=-6, B2=-8, G2=-10
-    if env.show_bass_staff {
+    if env.staff_mode.has_bass() {
         let bass_steps = [-2, -4, -6, -8, -10];
         for s in bass_steps.iter() { draw_staff_line(&mut env.canvas, *s).unwrap_or(()); }
     }
 
-    // -----------------------------------------------------------------
-    // Noten zeichnen (Horizontal Scrolling)
-    // -----------------------------------------------------------------
     // Visible Time Range berechnen wir neu für Horizontal
     // Pixel pro Sekunde horizontal
-    let visible_duration_seconds = (w as f64 - PLAYHEAD_X as f64) / PIXELS_PER_SECOND;
+    let visible_duration_seconds = (w as f64 - PLAYHEAD_X as f64) / env.pixels_per_second;
 
     // Wir schauen etwas in die Vergangenheit (links vom Playhead) und in die Zukunft (rechts)
-    let past_time_limit = PLAYHEAD_X as f64 / PIXELS_PER_SECOND;
+    let past_time_limit = PLAYHEAD_X as f64 / env.pixels_per_second;
+
+    // -----------------------------------------------------------------
+    // Taktstriche und Schläge (scrollen mit den Noten)
+    // -----------------------------------------------------------------
+    // Von der obersten bis zur untersten Notenlinie
+    let top_y = center_y - (env.staff_mode.top_step() * STAFF_LINE_SPACING / 2);
+    let bottom_y = center_y - (env.staff_mode.bottom_step() * STAFF_LINE_SPACING / 2)
+        + STAFF_LINE_THICKNESS as i32;
+    let line_h = (bottom_y - top_y) as u32;
+
+    let first = beat_grid.partition_point(|&(t, _)| t < current_time - past_time_limit);
+    for &(t, downbeat) in &beat_grid[first..] {
+        if t > current_time + visible_duration_seconds { break; }
+        let x = PLAYHEAD_X + ((t - current_time) * env.pixels_per_second) as i32;
+        if downbeat {
+            env.canvas.set_draw_color(STAFF_COLOR);
+            env.canvas.fill_rect(Rect::new(x, top_y, BAR_LINE_WIDTH, line_h)).unwrap_or(());
+        } else {
+            let Color {r, g, b, ..} = STAFF_COLOR;
+            env.canvas.set_draw_color(Color::RGBA(r, g, b, BEAT_LINE_ALPHA));
+            env.canvas.fill_rect(Rect::new(x, top_y, 1, line_h)).unwrap_or(());
+        }
+    }
+
+    // -----------------------------------------------------------------
+    // Noten zeichnen (Horizontal Scrolling)
+    // -----------------------------------------------------------------
 
     for n in notes {
         // Optimierung: Nur Noten zeichnen, die im Fenster sichtbar sind
@@ -507,13 +618,14 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
         // Start der Note muss < (current_time + future) sein
         if n.start_time > current_time + visible_duration_seconds + 2.0 { break; } // +2.0 Puffer
         if n.start_time + n.duration < current_time - past_time_limit - 1.0 { continue; }
+        if env.muted[n.channel as usize] { continue; }
 
         // X-Position berechnen
         // x = PLAYHEAD + (start - now) * speed
-        let x_start = PLAYHEAD_X as f64 + (n.start_time - current_time) * PIXELS_PER_SECOND;
-        let note_width_px = n.duration * PIXELS_PER_SECOND;
+        let x_start = PLAYHEAD_X as f64 + (n.start_time - current_time) * env.pixels_per_second;
+        let note_width_px = n.duration * env.pixels_per_second;
 
-        let display_key = n.midi_key + vis_offset;
+        let display_key = display_key(n, vis_offset);
 
         // Y-Position berechnen (Staff Mapping)
         let step = get_staff_step(display_key, flat);
@@ -524,7 +636,7 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
         let mut color = if env.black_notes {
             Color {r: 0, g: 0, b: 0, a: 0}
         } else {
-            n.color
+            get_note_base_color(env, n)
         };
 
         // Wenn Note gerade aktiv ist (unter dem Playhead), leicht aufhellen
@@ -536,16 +648,16 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
             color.b = color.b.saturating_add(color_shift);
         }
 
-        // A) Die Spur (Trail) - Länge der Note
-        let trail_rect = Rect::new(
-            x_start as i32 + 3,
-            y_pos - (NOTE_HEAD_HEIGHT / 4), // Spur ist etwas dünner als der Kopf
-            (note_width_px as u32).saturating_sub(3),
-            (NOTE_HEAD_HEIGHT / 2) as u32
-        );
-
+        // A) Die Spur (Trail) - Länge der Note, mit runden Enden wie
+        // die Notenköpfe
+        let trail_h = NOTE_HEAD_HEIGHT / 2; // Spur ist etwas dünner als der Kopf
         env.canvas.set_draw_color(Color::RGBA(color.r, color.g, color.b, NOTE_TRAIL_ALPHA));
-        env.canvas.fill_rect(trail_rect).unwrap_or(());
+        crate::render_fill_rounded_rect(
+            &mut env.canvas,
+            x_start as i32 + 3, y_pos - trail_h / 2,
+            (note_width_px as i32 - 3).max(0), trail_h,
+            trail_h / 2, crate::CORNER_ALL
+        ).unwrap_or(());
 
         // B) Der Notenkopf (Am Anfang der Note, rechtsbündig zur Spur sozusagen,
         // da die Musik nach links fließt, ist der Anfang der Note links)
@@ -570,47 +682,11 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
         //    println!("Note: {}, Abs: {}, Rel: {}", display_key, abs_step, rel_step);
         // }
 
-        let mut ledger_start = 0;
-        let mut ledger_end = 0;
-        let mut draw_ledgers = false;
+        // In der Akkolade bestimmt der Trennpunkt (--split) das System
+        let in_bass = env.staff_mode.in_bass(display_key, env.split_key);
 
         // Wichtig: Wir vergleichen rel_step (z.B. 0) statt abs_step (z.B. 28)
-        if rel_step > 10 {
-            // FALL 1: Note über dem Violinschlüssel (oberhalb F5 / Step 10)
-            ledger_start = 12;
-            ledger_end = rel_step;
-            draw_ledgers = true;
-        } else if rel_step < 2 {
-            // FALL 2: Note unter der untersten Linie des Violinschlüssels (E4 / Step 2)
-
-            if !env.show_bass_staff {
-                // Wenn Bass deaktiviert ist: Leiter hoch bis zum Violinschlüssel (Step 0)
-                // Wir zeichnen von der Note hoch bis zur 0 (Mittel-C Linie)
-                ledger_start = rel_step;
-                ledger_end = 0;
-                draw_ledgers = true;
-            } else {
-                // Wenn Bass-System aktiv ist:
-                // Bass liegt zwischen -10 und -2.
-
-                if rel_step > -2 {
-                    // "Niemandsland" zwischen Bass (-2) und Treble (2): Steps -1, 0, 1
-                    // Mittel-C ist Step 0. Wir zeichnen nur die Linie auf der 0.
-                    if rel_step == 0 {
-                        ledger_start = 0;
-                        ledger_end = 0;
-                        draw_ledgers = true;
-                    }
-                } else if rel_step < -10 {
-                    // Note unter dem Bass-Schlüssel (unter G2 / Step -10)
-                    ledger_start = rel_step;
-                    ledger_end = -12;
-                    draw_ledgers = true;
-                }
-            }
-        }
-
-        if draw_ledgers {
+        if let Some((ledger_start, ledger_end)) = env.staff_mode.ledger_steps(in_bass, rel_step) {
             env.canvas.set_draw_color(STAFF_COLOR);
             // Iteriere durch den Bereich.
             for s in ledger_start..=ledger_end {
@@ -631,18 +707,25 @@ pub fn render_staff(env: &mut Env, view: &RenderView,
             }
         }
 
+        // Hals nach oben unterhalb der Mittellinie des jeweiligen Systems
+        // (H4 bzw. D3 im Bass), sonst nach unten
+        let middle_step = if in_bass {-6} else {6};
         // Note zeichnen ein wenig verzögern, damit sie nicht
         // von den Hilfslinien der nächsten Noten überdeckt wird
         let new_head = BufferedHead {
             x: head_x, y: head_y, midi_key: display_key,
             color: Color::RGBA(color.r, color.g, color.b, 255),
+            stem_up: rel_step < middle_step,
+            flags: flag_count(beat_grid, n.start_time, n.duration),
         };
         if let Some(old_head) = env.ring_buffer.push_overflow(new_head) {
             render_note(env, &old_head, textures);
         }
     }
 
-    while let Some(head) = env.ring_buffer.pop() {
+    // Puffer herausnehmen, da render_note selbst `env` braucht
+    let mut ring_buffer = std::mem::take(&mut env.ring_buffer);
+    for head in ring_buffer.drain() {
         render_note(env, &head, textures);
     }
 
@@ -0,0 +1,102 @@
+// =====================================================================
+// MUSIKTHEORIE (Schreibweise der Noten)
+// =====================================================================
+
+// Regeln, nach denen die Noten im Notensystem und in den Beschriftungen
+// geschrieben werden: Vorzeichen je nach Tonart, Notenname und Höhe im
+// Liniensystem. Unabhängig vom Zeichnen, damit andere Auswertungen der
+// Noten dieselbe Schreibweise verwenden wie die Visualisierung. Die
+// Tonart wird jeweils als Grundton (Halbtöne über C, siehe `KeyInfo`)
+// übergeben.
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Accidental {
+    None,    // Kein Vorzeichen an der Note nötig
+    Natural, // ♮ Auflösungszeichen
+    Sharp,   // # Kreuz
+    Flat,    // b Be
+}
+
+pub fn is_flat_root(root: i32) -> bool {
+    // F(5), Bes(10), Es(3), As(8), Des(1), Ges(6)
+    matches!(root, 5 | 10 | 3 | 8 | 1 | 6)
+}
+
+pub fn determine_accidental(midi_key: i32, root: i32) -> Accidental {
+    let n = midi_key.rem_euclid(12);
+    let major_intervals = [0, 2, 4, 5, 7, 9, 11];
+
+    for &interval in &major_intervals {
+        if (root + interval) % 12 == n {
+            return Accidental::None;
+        }
+    }
+
+    // Ist der Ton physikalisch eine weiße Taste (C, D, E, F, G, A, B)?
+    // Wenn ja, aber nicht in der Skala (siehe oben), muss er aufgelöst werden.
+    let is_white_key = matches!(n, 0 | 2 | 4 | 5 | 7 | 9 | 11);
+
+    if is_white_key {
+        return Accidental::Natural;
+    }
+
+    // Chromatische Anpassung (# oder b)
+    // Der Ton ist eine schwarze Taste und nicht in der Skala.
+    // Entscheidung anhand des Quintenzirkels (Flat Roots vs Sharp Roots).
+    if is_flat_root(root) {
+        Accidental::Flat
+    } else {
+        Accidental::Sharp
+    }
+}
+
+// Notenname mit Oktave in englischer Schreibweise (C4 = mittleres C),
+// schwarze Tasten wie bei `determine_accidental` als Kreuz oder Be je
+// nach Tonart, bspw. "F#5" oder "Bb3".
+pub fn note_name(midi_key: i32, root: i32) -> String {
+    const LETTERS: [&str; 12] = ["C", "C", "D", "D", "E", "F", "F", "G", "G", "A", "A", "B"];
+    let n = midi_key.rem_euclid(12);
+    let octave = midi_key.div_euclid(12) - 1;
+    if !matches!(n, 1 | 3 | 6 | 8 | 10) {
+        format!("{}{}", LETTERS[n as usize], octave)
+    } else if is_flat_root(root) {
+        format!("{}b{}", LETTERS[n as usize + 1], octave)
+    } else {
+        format!("{}#{}", LETTERS[n as usize], octave)
+    }
+}
+
+// Berechnet den vertikalen "Step" im Notensystem relativ zu C4 (Midi 60)
+// C4 = 0, D4 = 1, E4 = 2 ...
+pub fn get_staff_step(midi: i32, flat: bool) -> i32 {
+    let octave = (midi / 12) - 1; // MIDI Oktave (-1 für interne Berechnung)
+    let note_in_octave = midi % 12;
+
+    // Mapping: Semitone Index -> Staff Step Index (C=0, D=1, E=2, F=3, G=4, A=5, B=6)
+    // Schwarze Tasten (Sharps) landen auf der gleichen Höhe wie die Note darunter
+    let step_in_octave = if flat {
+        match note_in_octave {
+            0 => 0,      // C
+            1 | 2 => 1,  // Db, D
+            3 | 4 => 2,  // Eb, E
+            5 => 3,      // F
+            6 | 7 => 4,  // Gb, G
+            8 | 9 => 5,  // Ab, A
+            10 | 11 => 6,// Bb, B
+            _ => 0
+        }
+    } else {
+        match note_in_octave {
+            0 | 1 => 0, // C, C#
+            2 | 3 => 1, // D, D#
+            4 => 2,     // E
+            5 | 6 => 3, // F, F#
+            7 | 8 => 4, // G, G#
+            9 | 10 => 5,// A, A#
+            11 => 6,    // B
+            _ => 0
+        }
+    };
+
+    (octave * 7) + step_in_octave
+}